@@ -1,39 +1,55 @@
-use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::clock::MessageClock;
+use crate::dedup::DedupCache;
+use crate::ids::IdGenerator;
+use crate::membership::Membership;
+use crate::msg::{error_code, Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::MessageSink;
+use crate::snapshot::Snapshotter;
 use anyhow::{bail, Context};
 use log::{debug, error};
 use rand::seq::SliceRandom;
-
 use serde::{Deserialize, Serialize};
+
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::{StdoutLock, Write};
-use std::thread;
-use std::time::Duration;
-use uuid::Uuid;
 
-pub struct CountNode<'a> {
+// What survives a restart when FLY_SNAPSHOT_DIR is set
+#[derive(Serialize, Deserialize, Default)]
+struct CountSnapshot {
+    operations: HashSet<(String, usize, usize)>,
+}
+
+pub struct CountNode {
     node_id: Option<String>,
     node_msg_id: usize,
-    output: StdoutLock<'a>,
+    output: Box<dyn MessageSink>,
+    dedup: DedupCache,
+    clock: MessageClock,
+    // Set when FLY_SWIM is, to skip peers it declares dead
+    membership: Option<Membership>,
+    // Peers the failure detector suspects, skipped like dead ones
+    suspected: HashSet<String>,
+    id_gen: IdGenerator,
+    snapshotter: Option<Snapshotter>,
     operations: HashSet<(String, usize, usize)>,
     // Other nodes from topology message and the
     // broadcast index we've sent them
     other_nodes_seen: HashMap<String, HashSet<(String, usize, usize)>>,
 }
 
-impl<'a> CountNode<'a> {
+impl CountNode {
     pub fn new(
         init_msg: Event<Message, Injected>,
-        mut output: StdoutLock<'a>,
-        tx: std::sync::mpsc::Sender<Event<Message, Injected>>,
-    ) -> Self {
+        mut output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
         debug!("in CountNode::new");
         match init_msg {
             Event::EOF => {
-                panic!("expected init")
+                bail!("expected init, got EOF")
             }
             Event::Injected(..) => {
-                panic!("expected init")
+                bail!("expected init, got injected event")
             }
             Event::Message(init_msg) => {
                 if let Payload::Init {
@@ -43,22 +59,18 @@ impl<'a> CountNode<'a> {
                 {
                     debug!("inside CountNode::new");
                     debug!("init_msg: {:?}", init_msg.clone());
+                    let id_gen = IdGenerator::from_env(node_id, node_ids)?;
                     let reply = Message {
                         src: init_msg.dest,
                         dest: init_msg.src,
                         body: Body {
                             msg_id: Some(0),
                             in_reply_to: init_msg.body.msg_id,
+                            clock: None,
                             payload: Payload::InitOk,
                         },
                     };
-                    serde_json::to_writer(&mut output, &reply)
-                        .context("serialize response to init")
-                        .unwrap();
-                    output
-                        .write_all(b"\n")
-                        .context("write trailing newline")
-                        .unwrap();
+                    output.send(&reply).context("send init_ok")?;
                     let mut other_nodes_seen: HashMap<String, HashSet<(String, usize, usize)>> =
                         HashMap::new();
                     for n in node_ids {
@@ -67,31 +79,43 @@ impl<'a> CountNode<'a> {
                         }
                         other_nodes_seen.insert(n.to_string(), HashSet::new());
                     }
-                    thread::spawn(move || loop {
-                        std::thread::sleep(Duration::from_millis(30));
-                        debug!("Sending GossipNow");
-                        tx.send(Event::Injected(Injected::GossipNow)).unwrap();
-                    });
-
                     /*
                     for k in node_ids.iter() {
                         other_nodes_seen.insert(k.to_string(), HashSet::new());
                     }
                     */
-                    CountNode {
+                    let mut node = CountNode {
                         node_id: Some(node_id.clone()),
                         node_msg_id: 1,
-                        output: output,
+                        output,
+                        dedup: DedupCache::default(),
+                        clock: MessageClock::from_env(node_id, node_ids)?,
+                        membership: Membership::from_env(node_id, node_ids)?,
+                        suspected: HashSet::new(),
+                        id_gen,
+                        snapshotter: None,
                         operations: HashSet::new(),
-                        other_nodes_seen: other_nodes_seen,
+                        other_nodes_seen,
+                    };
+                    if let Some(snapshotter) = Snapshotter::from_env(node_id)? {
+                        node.start_snapshots(snapshotter)?;
                     }
+                    Ok(node)
                 } else {
                     error!("Expected Init message as first message");
-                    panic!("")
+                    bail!("expected init, got {:?}", init_msg)
                 }
             }
         }
     }
+    // Restore what snapshotter last saved, then keep saving to it
+    pub fn start_snapshots(&mut self, snapshotter: Snapshotter) -> anyhow::Result<()> {
+        if let Some(snapshot) = snapshotter.load::<CountSnapshot>()? {
+            self.operations = snapshot.operations;
+        }
+        self.snapshotter = Some(snapshotter);
+        Ok(())
+    }
     pub fn create_message(
         &mut self,
         src: String,
@@ -99,21 +123,20 @@ impl<'a> CountNode<'a> {
         in_reply_to: Option<usize>,
         payload: Payload,
     ) -> Message {
-        let msg_id = Some(self.node_msg_id.clone());
+        let msg_id = Some(self.node_msg_id);
         let body = Body {
             msg_id,
             in_reply_to,
+            clock: None,
             payload,
         };
         self.node_msg_id += 1;
         Message { src, dest, body }
     }
-    pub fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut self.output, &msg).context("serialize response to Generate")?;
-        self.output
-            .write_all(b"\n")
-            .context("write trailing newline")?;
-        Ok(())
+    pub fn send(&mut self, mut msg: Message) -> anyhow::Result<()> {
+        self.clock.stamp(&mut msg);
+        self.dedup.record(&msg);
+        self.output.send(&msg)
     }
     pub fn write_message(
         &mut self,
@@ -129,6 +152,10 @@ impl<'a> CountNode<'a> {
         match input {
             Event::EOF => {}
             Event::Message(input) => match input.body.payload {
+                _ if self
+                    .membership
+                    .as_mut()
+                    .is_some_and(|m| m.handle(&input.src, &input.body.payload)) => {}
                 Payload::Init { .. } => {
                     bail!("Should've already processed init message");
                 }
@@ -140,9 +167,9 @@ impl<'a> CountNode<'a> {
                         Payload::EchoOk { echo },
                     )?;
                 }
-                Payload::Generate { .. } => {
-                    let id = Uuid::new_v4();
-                    let payload = Payload::GenerateOk { id: id.to_string() };
+                Payload::Generate => {
+                    let id = self.id_gen.next_id()?;
+                    let payload = Payload::GenerateOk { id };
                     self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
                 Payload::Add { delta } => {
@@ -155,14 +182,16 @@ impl<'a> CountNode<'a> {
                         delta,
                     ));
                     if !inserted {
-                        panic!("Expected value to be inserted: {:?}", input);
+                        // A client retrying an add we've already applied
+                        debug!("duplicate add: {:?}", input);
                     }
                     self.write_message(input.dest, input.src, input.body.msg_id, Payload::AddOk)?;
                 }
-                Payload::Broadcast { message } => bail!("didn't expect Broadcast for CountNode"),
+                Payload::Broadcast { .. } => bail!("didn't expect Broadcast for CountNode"),
                 Payload::Read { .. } => {
-                    let payload = Payload::ReadOkCount {
-                        value: self.operations.clone().into_iter().map(|(_, _, x)| x).sum(),
+                    let payload = Payload::ReadOk {
+                        messages: None,
+                        value: Some(self.operations.clone().into_iter().map(|(_, _, x)| x).sum()),
                     };
                     self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
@@ -200,45 +229,91 @@ impl<'a> CountNode<'a> {
                     )?;
                 }
                 Payload::EchoOk { .. } => {}
-                Payload::InitOk { .. } => bail!("received InitOk message"),
+                Payload::InitOk => bail!("received InitOk message"),
                 Payload::GenerateOk { .. } => bail!("received GenerateOk message"),
-                Payload::BroadcastOk { .. } => {}
+                Payload::BroadcastOk => {}
                 Payload::GossipEcho { .. } => bail!("CountNode received GossipEcho message"),
-                Payload::GossipCount { adds } => {
+                Payload::GossipCount { adds, updates } => {
                     debug!("received gossip: {:?}, ids: {:?}", &input.src, adds.clone());
+                    if let Some(membership) = self.membership.as_mut() {
+                        membership.absorb(&updates);
+                    }
                     for item in adds {
                         let _ = self.operations.insert(item.clone());
                         let _ = self
                             .other_nodes_seen
-                            .get_mut(&input.src)
-                            .unwrap()
+                            .entry(input.src.clone())
+                            .or_default()
                             .insert(item);
                     }
 
                     debug!("other_nodes_seen: {:?}", self.other_nodes_seen);
                 }
-                Payload::ReadOkEcho { .. } => bail!("received ReadOk message"),
-                Payload::ReadOkCount { .. } => bail!("received ReadOk message"),
-                Payload::AddOk { .. } => bail!("received AddOk message"),
-                Payload::TopologyOk { .. } => bail!("received TopologyOk message"),
+                Payload::ReadOk { .. } => bail!("received ReadOk message"),
+                Payload::AddOk => bail!("received AddOk message"),
+                Payload::TopologyOk => bail!("received TopologyOk message"),
                 _ => {
-                    bail!("Received unexpected msg for KafkaNode: {:?}", input)
+                    if input.body.msg_id.is_none() {
+                        bail!("Received unexpected msg for CountNode: {:?}", input)
+                    }
+                    let text = format!("CountNode doesn't support {:?}", input.body.payload);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::Error {
+                            code: error_code::NOT_SUPPORTED,
+                            text,
+                        },
+                    )?;
                 }
             },
-            Event::Injected(_input) => {
+            Event::Injected(Injected::PeerSuspected(peer)) => {
+                self.suspected.insert(peer);
+            }
+            Event::Injected(Injected::PeerRecovered(peer)) => {
+                self.suspected.remove(&peer);
+            }
+            Event::Injected(Injected::GossipNow) => {
+                if let Some(membership) = self.membership.as_mut() {
+                    membership.tick();
+                }
                 let _ = self.gossip();
+                if self.snapshotter.as_ref().is_some_and(|s| s.is_due()) {
+                    self.save_snapshot().context("save snapshot")?;
+                }
             }
         }
 
+        self.flush_membership()
+    }
+
+    // Send the membership protocol's messages
+    fn flush_membership(&mut self) -> anyhow::Result<()> {
+        let Some(membership) = self.membership.as_mut() else {
+            return Ok(());
+        };
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in membership.take_messages() {
+            self.write_message(node_id.clone(), dest, None, payload)?;
+        }
         Ok(())
     }
 
+    fn save_snapshot(&mut self) -> anyhow::Result<()> {
+        let Some(snapshotter) = self.snapshotter.as_mut() else {
+            return Ok(());
+        };
+        snapshotter.save(&CountSnapshot {
+            operations: self.operations.clone(),
+        })
+    }
+
     fn gossip(&mut self) -> anyhow::Result<()> {
         debug!("in gossip");
         for key in self
             .other_nodes_seen
             .keys()
-            .into_iter()
             .cloned()
             .collect::<Vec<_>>()
             .iter()
@@ -247,6 +322,12 @@ impl<'a> CountNode<'a> {
             if key == self.node_id.as_ref().unwrap() {
                 continue;
             }
+            // or to ones declared dead or suspected
+            if self.suspected.contains(key)
+                || self.membership.as_ref().is_some_and(|m| !m.is_live(key))
+            {
+                continue;
+            }
             debug!("working on: {:?}", key);
             // let mut ids = self.broadcast_ids.iter().cloned().collect::<Vec<_>>();
             let ids = self.operations.clone();
@@ -268,19 +349,81 @@ impl<'a> CountNode<'a> {
                 .cloned()
                 .collect();
             debug!("extra: {:?}", extra);
-            let mut ids_to_send = ids.iter().cloned().collect::<Vec<_>>();
+            let mut ids_to_send = ids.to_vec();
             ids_to_send.extend(extra.iter().cloned());
             ids_to_send.sort();
             ids_to_send.dedup();
             debug!("ids_to_send: {:?}", ids_to_send);
+            let updates = self
+                .membership
+                .as_mut()
+                .map(|m| m.piggyback(key))
+                .unwrap_or_default();
             let msg = self.create_message(
                 self.node_id.clone().unwrap(),
                 key.clone(),
                 None,
-                Payload::GossipCount { adds: ids_to_send },
+                Payload::GossipCount {
+                    adds: ids_to_send,
+                    updates,
+                },
             );
             self.send(msg)?;
         }
         Ok(())
     }
 }
+
+impl Node for CountNode {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        CountNode::new(init_msg, output)
+    }
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        CountNode::step(self, input)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        CountNode::send(self, msg)
+    }
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        Some(&mut self.dedup)
+    }
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        Some(&mut self.clock)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush()
+    }
+    fn on_shutdown(&mut self) -> anyhow::Result<()> {
+        self.save_snapshot().context("save snapshot on shutdown")
+    }
+}
+
+#[test]
+fn a_restarted_node_restores_its_snapshot() -> anyhow::Result<()> {
+    use crate::harness::Harness;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("fly-count-{}", uuid::Uuid::new_v4()));
+    let snapshotter = || Snapshotter::new(&dir, "n0", Duration::from_secs(60));
+    let read = |h: &mut Harness<CountNode>| match h.call(Payload::Read { key: None })? {
+        Payload::ReadOk { value, .. } => Ok(value),
+        other => bail!("expected read_ok, got {:?}", other),
+    };
+
+    let mut node = Harness::<CountNode>::new("n0", &["n0"])?;
+    node.node().start_snapshots(snapshotter()?)?;
+    node.call(Payload::Add { delta: 3 })?;
+    node.call(Payload::Add { delta: 4 })?;
+    node.node().on_shutdown()?;
+
+    // A new node snapshotting to the same dir starts where the old one stopped
+    let mut node = Harness::<CountNode>::new("n0", &["n0"])?;
+    assert_eq!(read(&mut node)?, Some(0));
+    node.node().start_snapshots(snapshotter()?)?;
+    assert_eq!(read(&mut node)?, Some(7));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -1,89 +1,154 @@
-use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::broadcast::{BroadcastOrder, CausalBroadcast, TotalOrder};
+use crate::clock::MessageClock;
+use crate::consensus::Backend;
+use crate::dedup::DedupCache;
+use crate::ids::IdGenerator;
+use crate::membership::Membership;
+use crate::msg::{error_code, Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::MessageSink;
+use crate::snapshot::Snapshotter;
 use anyhow::{bail, Context};
-use log::{debug, error};
+use log::{debug, error, info};
 use rand::seq::SliceRandom;
-
 use serde::{Deserialize, Serialize};
+
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::io::{StdoutLock, Write};
-use std::thread;
-use std::time::Duration;
-use uuid::Uuid;
+use std::time::{Duration, Instant};
 
-pub struct EchoNode<'a> {
+// How long a broadcast forwarded to the leader in total order mode waits
+// for its reply before the client is told to try again
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What survives a restart when FLY_SNAPSHOT_DIR is set
+#[derive(Serialize, Deserialize, Default)]
+struct EchoSnapshot {
+    broadcast_ids: HashSet<usize>,
+    #[serde(default)]
+    causal: Option<CausalBroadcast>,
+}
+
+pub struct EchoNode {
     node_id: Option<String>,
     node_msg_id: usize,
-    output: StdoutLock<'a>,
+    node_ids: Vec<String>,
+    output: Box<dyn MessageSink>,
+    dedup: DedupCache,
+    clock: MessageClock,
+    // Set when FLY_SWIM is, to skip peers it declares dead
+    membership: Option<Membership>,
+    // Peers the failure detector suspects, skipped like dead ones
+    suspected: HashSet<String>,
+    id_gen: IdGenerator,
+    snapshotter: Option<Snapshotter>,
     broadcast_ids: HashSet<usize>,
+    // Set in causal mode, where broadcast_ids only has delivered messages
+    causal: Option<CausalBroadcast>,
+    // Origin of the messages this run broadcasts in causal mode: the node
+    // id and a fresh incarnation, so a node restarted from an older
+    // snapshot, or none, never reuses an (origin, seq) peers have delivered
+    origin: String,
+    // Set in total order mode, where reads return its delivery order
+    total: Option<TotalOrder>,
+    // Broadcasts forwarded to the leader in total order mode, by the
+    // msg_id we sent them with, mapped to the client and msg_id to relay
+    // the reply to and when they were sent
+    forwarded: HashMap<usize, (String, Option<usize>, Instant)>,
     // Other nodes from topology message and the
     // broadcast index we've sent them
     other_nodes_seen: HashMap<String, HashSet<usize>>,
 }
 
-impl<'a> EchoNode<'a> {
+impl EchoNode {
     pub fn new(
         init_msg: Event<Message, Injected>,
-        mut output: StdoutLock<'a>,
-        tx: std::sync::mpsc::Sender<Event<Message, Injected>>,
-    ) -> Self {
+        mut output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
         debug!("in EchoNode::new");
         match init_msg {
             Event::EOF => {
-                panic!("expected init")
+                bail!("expected init, got EOF")
             }
             Event::Injected(..) => {
-                panic!("expected init")
+                bail!("expected init, got injected event")
             }
             Event::Message(init_msg) => {
                 if let Payload::Init {
                     ref node_id,
-                    node_ids: _,
+                    ref node_ids,
                 } = init_msg.body.payload
                 {
                     debug!("inside EchoNode::new");
                     debug!("init_msg: {:?}", init_msg.clone());
+                    let id_gen = IdGenerator::from_env(node_id, node_ids)?;
+                    let snapshotter = Snapshotter::from_env(node_id)?;
+                    let snapshot: EchoSnapshot = match snapshotter {
+                        Some(ref s) => s.load()?.unwrap_or_default(),
+                        None => EchoSnapshot::default(),
+                    };
+                    let order = BroadcastOrder::from_env()?;
+                    let causal = match order {
+                        BroadcastOrder::Causal => Some(snapshot.causal.unwrap_or_default()),
+                        _ => None,
+                    };
+                    let total = match order {
+                        BroadcastOrder::Total => {
+                            Some(TotalOrder::new(Backend::from_env()?, node_id, node_ids))
+                        }
+                        _ => None,
+                    };
                     let reply = Message {
                         src: init_msg.dest,
                         dest: init_msg.src,
                         body: Body {
                             msg_id: Some(0),
                             in_reply_to: init_msg.body.msg_id,
+                            clock: None,
                             payload: Payload::InitOk,
                         },
                     };
-                    serde_json::to_writer(&mut output, &reply)
-                        .context("serialize response to init")
-                        .unwrap();
-                    output
-                        .write_all(b"\n")
-                        .context("write trailing newline")
-                        .unwrap();
+                    output.send(&reply).context("send init_ok")?;
                     let other_nodes_seen: HashMap<String, HashSet<usize>> = HashMap::new();
-                    thread::spawn(move || loop {
-                        std::thread::sleep(Duration::from_millis(30));
-                        tx.send(Event::Injected(Injected::GossipNow)).unwrap();
-                    });
-
                     /*
                     for k in node_ids.iter() {
                         other_nodes_seen.insert(k.to_string(), HashSet::new());
                     }
                     */
-                    EchoNode {
+                    Ok(EchoNode {
                         node_id: Some(node_id.clone()),
                         node_msg_id: 1,
-                        output: output,
-                        broadcast_ids: HashSet::new(),
-                        other_nodes_seen: other_nodes_seen,
-                    }
+                        node_ids: node_ids.clone(),
+                        output,
+                        dedup: DedupCache::default(),
+                        clock: MessageClock::from_env(node_id, node_ids)?,
+                        membership: Membership::from_env(node_id, node_ids)?,
+                        suspected: HashSet::new(),
+                        id_gen,
+                        snapshotter,
+                        broadcast_ids: snapshot.broadcast_ids,
+                        causal,
+                        origin: format!("{}.{}", node_id, rand::random::<u32>()),
+                        total,
+                        forwarded: HashMap::new(),
+                        other_nodes_seen,
+                    })
                 } else {
                     error!("Expected Init message as first message");
-                    panic!("")
+                    bail!("expected init, got {:?}", init_msg)
                 }
             }
         }
     }
+    // Deliver broadcasts causally, as FLY_BROADCAST_ORDER=causal does
+    pub fn start_causal(&mut self) {
+        self.causal = Some(CausalBroadcast::default());
+    }
+    // Order broadcasts through backend, as FLY_BROADCAST_ORDER=total does
+    pub fn start_total_order(&mut self, backend: Backend) {
+        let node_id = self.node_id.clone().unwrap();
+        self.total = Some(TotalOrder::new(backend, &node_id, &self.node_ids));
+    }
     pub fn create_message(
         &mut self,
         src: String,
@@ -91,21 +156,20 @@ impl<'a> EchoNode<'a> {
         in_reply_to: Option<usize>,
         payload: Payload,
     ) -> Message {
-        let msg_id = Some(self.node_msg_id.clone());
+        let msg_id = Some(self.node_msg_id);
         let body = Body {
             msg_id,
             in_reply_to,
+            clock: None,
             payload,
         };
         self.node_msg_id += 1;
         Message { src, dest, body }
     }
-    pub fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut self.output, &msg).context("serialize response to Generate")?;
-        self.output
-            .write_all(b"\n")
-            .context("write trailing newline")?;
-        Ok(())
+    pub fn send(&mut self, mut msg: Message) -> anyhow::Result<()> {
+        self.clock.stamp(&mut msg);
+        self.dedup.record(&msg);
+        self.output.send(&msg)
     }
     pub fn write_message(
         &mut self,
@@ -118,9 +182,27 @@ impl<'a> EchoNode<'a> {
         self.send(msg)
     }
     pub fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        self.step_at(input, Instant::now())
+    }
+    pub fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
         match input {
             Event::EOF => {}
             Event::Message(input) => match input.body.payload {
+                _ if self
+                    .membership
+                    .as_mut()
+                    .is_some_and(|m| m.handle(&input.src, &input.body.payload)) => {}
+                _ if input
+                    .body
+                    .in_reply_to
+                    .is_some_and(|id| self.forwarded.contains_key(&id)) =>
+                {
+                    self.relay(input)?;
+                }
+                _ if self
+                    .total
+                    .as_mut()
+                    .is_some_and(|t| t.handle(&input.src, &input.body.payload, now)) => {}
                 Payload::Init { .. } => {
                     bail!("Should've already processed init message");
                 }
@@ -132,14 +214,20 @@ impl<'a> EchoNode<'a> {
                         Payload::EchoOk { echo },
                     )?;
                 }
-                Payload::Generate { .. } => {
-                    let id = Uuid::new_v4();
-                    let payload = Payload::GenerateOk { id: id.to_string() };
+                Payload::Generate => {
+                    let id = self.id_gen.next_id()?;
+                    let payload = Payload::GenerateOk { id };
                     self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
+                Payload::Broadcast { message } if self.total.is_some() => {
+                    self.total_broadcast(input, message, now)?;
+                }
                 Payload::Broadcast { message } => {
                     if !self.broadcast_ids.contains(&message) {
                         // debug!("Need to push to broadcast_ids: {:?}", message);
+                        if let Some(causal) = self.causal.as_mut() {
+                            causal.broadcast(&self.origin, message);
+                        }
                         self.broadcast_ids.insert(message);
                         debug!("Current broadcast_ids: {:?}", &self.broadcast_ids);
                     }
@@ -151,8 +239,13 @@ impl<'a> EchoNode<'a> {
                     )?;
                 }
                 Payload::Read { .. } => {
-                    let payload = Payload::ReadOkEcho {
-                        messages: self.broadcast_ids.clone().into_iter().collect(),
+                    let messages = match self.total {
+                        Some(ref total) => total.delivered().to_vec(),
+                        None => self.broadcast_ids.clone().into_iter().collect(),
+                    };
+                    let payload = Payload::ReadOk {
+                        messages: Some(messages),
+                        value: None,
                     };
                     self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
@@ -189,41 +282,196 @@ impl<'a> EchoNode<'a> {
                     )?;
                 }
                 Payload::EchoOk { .. } => {}
-                Payload::InitOk { .. } => bail!("received InitOk message"),
+                Payload::InitOk => bail!("received InitOk message"),
                 Payload::GenerateOk { .. } => bail!("received GenerateOk message"),
-                Payload::BroadcastOk { .. } => {}
+                Payload::BroadcastOk => {}
                 Payload::GossipCount { .. } => bail!("EchoNode received GossipCount message"),
-                Payload::GossipEcho { ids } => {
+                Payload::GossipEcho {
+                    ids,
+                    causal,
+                    updates,
+                } => {
                     debug!("received gossip: {:?}, ids: {:?}", &input.src, ids.clone());
-                    self.broadcast_ids.extend(ids.clone());
+                    if let Some(membership) = self.membership.as_mut() {
+                        membership.absorb(&updates);
+                    }
+                    match self.causal.as_mut() {
+                        Some(state) => {
+                            for msg in causal {
+                                self.broadcast_ids.extend(state.receive(msg));
+                            }
+                            debug!("holdback queue length: {}", state.holdback_len());
+                        }
+                        None => self.broadcast_ids.extend(ids.clone()),
+                    }
+                    // Gossip from a peer we haven't been told about yet
+                    // adds it to the set we gossip with
                     self.other_nodes_seen
-                        .get_mut(&input.src)
-                        .unwrap()
+                        .entry(input.src)
+                        .or_default()
                         .extend(ids);
                     debug!("other_nodes_seen: {:?}", self.other_nodes_seen);
                 }
-                Payload::ReadOkEcho { .. } => bail!("received ReadOk message"),
-                Payload::ReadOkCount { .. } => bail!("received ReadOk message"),
+                Payload::ReadOk { .. } => bail!("received ReadOk message"),
                 Payload::Add { .. } => bail!("received Add message for EchoNode"),
-                Payload::AddOk { .. } => bail!("received AddOk message"),
-                Payload::TopologyOk { .. } => bail!("received TopologyOk message"),
+                Payload::AddOk => bail!("received AddOk message"),
+                Payload::TopologyOk => bail!("received TopologyOk message"),
                 _ => {
-                    bail!("Received unexpected msg for KafkaNode: {:?}", input)
+                    if input.body.msg_id.is_none() {
+                        bail!("Received unexpected msg for EchoNode: {:?}", input)
+                    }
+                    let text = format!("EchoNode doesn't support {:?}", input.body.payload);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::Error {
+                            code: error_code::NOT_SUPPORTED,
+                            text,
+                        },
+                    )?;
                 }
             },
-            Event::Injected(_input) => {
-                let _ = self.propagate_broadcast_messages();
+            Event::Injected(Injected::PeerSuspected(peer)) => {
+                self.suspected.insert(peer);
+            }
+            Event::Injected(Injected::PeerRecovered(peer)) => {
+                self.suspected.remove(&peer);
+            }
+            Event::Injected(Injected::GossipNow) => {
+                if let Some(membership) = self.membership.as_mut() {
+                    membership.tick();
+                }
+                match self.total.as_mut() {
+                    Some(total) => total.tick(now),
+                    None => {
+                        let _ = self.propagate_broadcast_messages();
+                    }
+                }
+                self.expire_forwarded(now)?;
+                if self.snapshotter.as_ref().is_some_and(|s| s.is_due()) {
+                    self.save_snapshot().context("save snapshot")?;
+                }
             }
         }
 
+        self.flush_membership()?;
+        self.flush_total()
+    }
+
+    // Propose a client's broadcast if we lead, otherwise hand it to the
+    // leader. Broadcasts another node forwarded aren't forwarded again.
+    fn total_broadcast(
+        &mut self,
+        input: Message,
+        message: usize,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let total = self.total.as_mut().expect("total order mode");
+        let error = match total.propose(message, input.src.clone(), input.body.msg_id) {
+            Ok(()) => return Ok(()),
+            Err(Some(leader)) if !self.node_ids.contains(&input.src) => {
+                let msg = self.create_message(input.dest, leader, None, input.body.payload);
+                if let Some(msg_id) = msg.body.msg_id {
+                    self.forwarded
+                        .insert(msg_id, (input.src, input.body.msg_id, now));
+                }
+                return self.send(msg);
+            }
+            Err(_) => "no leader to order the message",
+        };
+        self.write_message(
+            input.dest,
+            input.src,
+            input.body.msg_id,
+            Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: error.to_string(),
+            },
+        )
+    }
+
+    // Pass the leader's reply back to the client that asked us
+    fn relay(&mut self, input: Message) -> anyhow::Result<()> {
+        let Some((client, client_msg_id, _)) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.forwarded.remove(&id))
+        else {
+            bail!("no forwarded request for {:?}", input);
+        };
+        self.write_message(input.dest, client, client_msg_id, input.body.payload)
+    }
+
+    // Give up on forwarded broadcasts the leader hasn't answered, e.g.
+    // because it died or lost the lead, so the client can try again
+    fn expire_forwarded(&mut self, now: Instant) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .forwarded
+            .iter()
+            .filter(|(_, (_, _, sent))| now.saturating_duration_since(*sent) >= FORWARD_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        let node_id = self.node_id.clone().unwrap();
+        for id in expired {
+            let (client, client_msg_id, _) = self.forwarded.remove(&id).expect("expired broadcast");
+            self.write_message(
+                node_id.clone(),
+                client,
+                client_msg_id,
+                Payload::Error {
+                    code: error_code::TEMPORARILY_UNAVAILABLE,
+                    text: "no reply from the leader".to_string(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // Send the consensus messages and reply to clients whose broadcasts
+    // have been delivered
+    fn flush_total(&mut self) -> anyhow::Result<()> {
+        let Some(total) = self.total.as_mut() else {
+            return Ok(());
+        };
+        let messages = total.take_messages();
+        let replies = total.deliver()?;
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in messages {
+            self.write_message(node_id.clone(), dest, None, payload)?;
+        }
+        for (client, client_msg_id, payload) in replies {
+            self.write_message(node_id.clone(), client, client_msg_id, payload)?;
+        }
+        Ok(())
+    }
+
+    // Send the membership protocol's messages
+    fn flush_membership(&mut self) -> anyhow::Result<()> {
+        let Some(membership) = self.membership.as_mut() else {
+            return Ok(());
+        };
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in membership.take_messages() {
+            self.write_message(node_id.clone(), dest, None, payload)?;
+        }
         Ok(())
     }
 
+    fn save_snapshot(&mut self) -> anyhow::Result<()> {
+        let Some(snapshotter) = self.snapshotter.as_mut() else {
+            return Ok(());
+        };
+        snapshotter.save(&EchoSnapshot {
+            broadcast_ids: self.broadcast_ids.clone(),
+            causal: self.causal.clone(),
+        })
+    }
+
     fn propagate_broadcast_messages(&mut self) -> anyhow::Result<()> {
         for key in self
             .other_nodes_seen
             .keys()
-            .into_iter()
             .cloned()
             .collect::<Vec<_>>()
             .iter()
@@ -232,6 +480,12 @@ impl<'a> EchoNode<'a> {
             if key == self.node_id.as_ref().unwrap() {
                 continue;
             }
+            // or to ones declared dead or suspected
+            if self.suspected.contains(key)
+                || self.membership.as_ref().is_some_and(|m| !m.is_live(key))
+            {
+                continue;
+            }
             debug!("working on: {:?}", key);
             // let mut ids = self.broadcast_ids.iter().cloned().collect::<Vec<_>>();
             let ids = self.broadcast_ids.clone();
@@ -253,19 +507,234 @@ impl<'a> EchoNode<'a> {
                 .cloned()
                 .collect();
             debug!("extra: {:?}", extra);
-            let mut ids_to_send = ids.iter().cloned().collect::<Vec<_>>();
+            let mut ids_to_send = ids.to_vec();
             ids_to_send.extend(extra.iter());
             ids_to_send.sort();
             ids_to_send.dedup();
             debug!("ids_to_send: {:?}", ids_to_send);
+            let causal = match self.causal {
+                Some(ref state) => ids_to_send
+                    .iter()
+                    .filter_map(|id| state.message(*id).cloned())
+                    .collect(),
+                None => Vec::new(),
+            };
+            let updates = self
+                .membership
+                .as_mut()
+                .map(|m| m.piggyback(key))
+                .unwrap_or_default();
             let msg = self.create_message(
                 self.node_id.clone().unwrap(),
                 key.clone(),
                 None,
-                Payload::GossipEcho { ids: ids_to_send },
+                Payload::GossipEcho {
+                    ids: ids_to_send,
+                    causal,
+                    updates,
+                },
             );
             self.send(msg)?;
         }
         Ok(())
     }
 }
+
+impl Node for EchoNode {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        EchoNode::new(init_msg, output)
+    }
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        EchoNode::step(self, input)
+    }
+    fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        EchoNode::step_at(self, input, now)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        EchoNode::send(self, msg)
+    }
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        Some(&mut self.dedup)
+    }
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        Some(&mut self.clock)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush()
+    }
+    fn on_shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(causal) = self.causal.as_ref() {
+            info!("causal broadcast metrics: {:?}", causal.metrics());
+        }
+        self.save_snapshot().context("save snapshot on shutdown")
+    }
+}
+
+#[test]
+fn total_order_delivers_the_same_order_everywhere() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let nodes = ["n0", "n1", "n2"];
+    let mut net = HarnessNet::<EchoNode>::new(&nodes)?;
+    for id in nodes {
+        net.node(id).node().start_total_order(Backend::Raft);
+    }
+    net.run(2000)?;
+    let leaders = |net: &mut HarnessNet<EchoNode>| {
+        nodes
+            .into_iter()
+            .filter(|n| {
+                let total = net.node(n).node().total.as_ref().expect("total order");
+                total.is_leader()
+            })
+            .collect::<Vec<_>>()
+    };
+    let [old_leader] = leaders(&mut net)[..] else {
+        bail!("expected one leader");
+    };
+
+    // Followers hand broadcasts to the leader and relay its reply
+    for (i, dest) in nodes.iter().enumerate() {
+        let msg_id = net.request(dest, Payload::Broadcast { message: i });
+        let reply = net.wait(msg_id, 1000)?;
+        assert_eq!(reply.src, *dest);
+        assert!(matches!(reply.body.payload, Payload::BroadcastOk));
+    }
+
+    // Cut off, the leader still takes a broadcast, but the majority elects
+    // another and orders a different one in its place
+    net.partition(&[old_leader]);
+    let lost = net.request(old_leader, Payload::Broadcast { message: 10 });
+    net.run(2000)?;
+    let new_leader = leaders(&mut net)
+        .into_iter()
+        .find(|n| *n != old_leader)
+        .context("a leader in the majority")?;
+    let msg_id = net.request(new_leader, Payload::Broadcast { message: 11 });
+    assert!(matches!(
+        net.wait(msg_id, 1000)?.body.payload,
+        Payload::BroadcastOk
+    ));
+    net.heal();
+    assert!(matches!(
+        net.wait(lost, 2000)?.body.payload,
+        Payload::Error {
+            code: error_code::TEMPORARILY_UNAVAILABLE,
+            ..
+        }
+    ));
+
+    net.run(500)?;
+    let mut orders = Vec::new();
+    for id in nodes {
+        match net.call(id, Payload::Read { key: None })? {
+            Payload::ReadOk {
+                messages: Some(messages),
+                ..
+            } => orders.push(messages),
+            other => bail!("expected read_ok, got {:?}", other),
+        }
+    }
+    assert_eq!(orders[0][..3], [0, 1, 2]);
+    assert_eq!(orders[0][3..], [11]);
+    assert!(orders.iter().all(|order| *order == orders[0]));
+    Ok(())
+}
+
+#[test]
+fn a_broadcast_forwarded_to_a_lost_leader_times_out() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let nodes = ["n0", "n1", "n2"];
+    let mut net = HarnessNet::<EchoNode>::new(&nodes)?;
+    for id in nodes {
+        net.node(id).node().start_total_order(Backend::Raft);
+    }
+    net.run(2000)?;
+    let (leader, followers): (Vec<&str>, Vec<&str>) = nodes.into_iter().partition(|n| {
+        let total = net.node(n).node().total.as_ref().expect("total order");
+        total.is_leader()
+    });
+    assert_eq!(leader.len(), 1);
+
+    // The follower still takes it for the leader, so forwards the
+    // broadcast into the partition
+    net.partition(&[leader[0]]);
+    let msg_id = net.request(followers[0], Payload::Broadcast { message: 1 });
+    net.deliver()?;
+    assert!(!net.node(followers[0]).node().forwarded.is_empty());
+    assert!(matches!(
+        net.wait(msg_id, 2000)?.body.payload,
+        Payload::Error {
+            code: error_code::TEMPORARILY_UNAVAILABLE,
+            ..
+        }
+    ));
+    assert!(net.node(followers[0]).node().forwarded.is_empty());
+    Ok(())
+}
+
+#[test]
+fn causal_mode_holds_back_gossip_that_arrives_early() -> anyhow::Result<()> {
+    use crate::harness::{Harness, HarnessNet};
+
+    let nodes = ["n0", "n1", "n2"];
+    let mut net = HarnessNet::<EchoNode>::new(&nodes)?;
+    for id in nodes {
+        net.node(id).node().start_causal();
+    }
+    let read = |net: &mut HarnessNet<EchoNode>, id: &str| match net
+        .call(id, Payload::Read { key: None })?
+    {
+        Payload::ReadOk {
+            messages: Some(mut messages),
+            ..
+        } => {
+            messages.sort();
+            Ok(messages)
+        }
+        other => bail!("expected read_ok, got {:?}", other),
+    };
+    // n0 gossips with everyone once it's given the topology
+    let topology = nodes.iter().map(|n| (n.to_string(), Vec::new())).collect();
+    net.call("n0", Payload::Topology { topology })?;
+
+    // n1 sees 1 and broadcasts 2 after it, but n0's gossip of 1 to n2 is
+    // slow, so n2 hears of 2 first
+    net.call("n0", Payload::Broadcast { message: 1 })?;
+    let (to_n2, to_n1): (Vec<Message>, Vec<Message>) = net
+        .node("n0")
+        .inject(Injected::GossipNow)?
+        .into_iter()
+        .partition(|m| m.dest == "n2");
+    to_n1.into_iter().for_each(|m| net.send(m));
+    net.deliver()?;
+    net.call("n1", Payload::Broadcast { message: 2 })?;
+    for mut msg in net.node("n1").inject(Injected::GossipNow)? {
+        msg.dest = "n2".to_string();
+        net.send(msg);
+    }
+    net.deliver()?;
+    assert_eq!(read(&mut net, "n2")?, Vec::<usize>::new());
+    to_n2.into_iter().for_each(|m| net.send(m));
+    net.deliver()?;
+    assert_eq!(read(&mut net, "n2")?, [1, 2]);
+
+    // Restarted without its state, n0 starts counting again, and n2 still
+    // takes what it broadcasts for new
+    let mut restarted = Harness::<EchoNode>::new("n0", &nodes)?;
+    restarted.node().start_causal();
+    restarted.call(Payload::Topology {
+        topology: HashMap::from([("n2".to_string(), Vec::new())]),
+    })?;
+    restarted.call(Payload::Broadcast { message: 3 })?;
+    for msg in restarted.inject(Injected::GossipNow)? {
+        net.send(msg);
+    }
+    net.deliver()?;
+    assert_eq!(read(&mut net, "n2")?, [1, 2, 3]);
+    Ok(())
+}
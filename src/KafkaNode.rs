@@ -1,40 +1,83 @@
-use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::clock::MessageClock;
+use crate::cluster::{Cluster, ClusterConfig};
+use crate::dedup::DedupCache;
+use crate::groups::{coordinator_for, GroupCoordinator};
+use crate::ids::IdGenerator;
+use crate::kafka_log::{MemoryLog, MessageLog, RetentionPolicy, DEFAULT_GROUP};
+use crate::membership::Membership;
+use crate::msg::{error_code, Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::MessageSink;
+use crate::snapshot::Snapshotter;
+use crate::wal::{SegmentLog, WalConfig};
 use anyhow::{bail, Context};
 use log::{debug, error};
-use rand::seq::SliceRandom;
 
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::io::{StdoutLock, Write};
-use std::thread;
-use std::time::Duration;
-use uuid::Uuid;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
-pub struct KafkaNode<'a> {
+// How often the retention policy is applied, checked on each gossip tick
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+// How long a split request waits on the owners of its keys before the
+// client is told it timed out
+const GATHER_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A client request split between the nodes owning its keys, with the reply
+// built up as their answers come in
+struct Gather {
+    client: String,
+    client_msg_id: Option<usize>,
+    parts: usize,
+    reply: Payload,
+    started: Instant,
+}
+
+pub struct KafkaNode {
     node_id: Option<String>,
     node_msg_id: usize,
-    output: StdoutLock<'a>,
-    logs: HashMap<String, Vec<(usize, usize)>>,
-    committed: HashMap<String, usize>,
+    node_ids: Vec<String>,
+    output: Box<dyn MessageSink>,
+    dedup: DedupCache,
+    clock: MessageClock,
+    // Set when FLY_SWIM is, to skip peers it declares dead
+    membership: Option<Membership>,
+    id_gen: IdGenerator,
+    // In memory unless FLY_KAFKA_WAL_DIR points at an on-disk log
+    log: Box<dyn MessageLog>,
+    retention: RetentionPolicy,
+    // Only used for the in-memory log, the on-disk one is durable already
+    snapshotter: Option<Snapshotter>,
+    last_retention: Instant,
+    // Set when FLY_CLUSTER is, to spread keys over the nodes that join
+    cluster: Option<Cluster>,
+    // Clients waiting on a JoinCluster or LeaveCluster, answered once done
+    cluster_waiters: Vec<(String, Option<usize>)>,
+    // Requests split by key owner, by the msg_id of the first part sent
+    gathers: HashMap<usize, Gather>,
+    // msg_id of each part sent -> msg_id of the first
+    parts: HashMap<usize, usize>,
+    // Consumer groups this node is the coordinator for
+    groups: GroupCoordinator,
+    // Group requests forwarded to their coordinator, by the msg_id we sent
+    // them with, mapped to the client and msg_id to relay the reply to
+    forwarded: HashMap<usize, (String, Option<usize>)>,
     // Other nodes from topology message and the
     // broadcast index we've sent them
     other_nodes_seen: HashMap<String, HashSet<(String, usize, usize)>>,
 }
 
-impl<'a> KafkaNode<'a> {
+impl KafkaNode {
     pub fn new(
         init_msg: Event<Message, Injected>,
-        mut output: StdoutLock<'a>,
-        tx: std::sync::mpsc::Sender<Event<Message, Injected>>,
-    ) -> Self {
+        mut output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
         debug!("in KafkaNode::new");
         match init_msg {
             Event::EOF => {
-                panic!("expected init")
+                bail!("expected init, got EOF")
             }
             Event::Injected(..) => {
-                panic!("expected init")
+                bail!("expected init, got injected event")
             }
             Event::Message(init_msg) => {
                 if let Payload::Init {
@@ -44,22 +87,32 @@ impl<'a> KafkaNode<'a> {
                 {
                     debug!("inside KafkaNode::new");
                     debug!("init_msg: {:?}", init_msg.clone());
+                    let id_gen = IdGenerator::from_env(node_id, node_ids)?;
+                    // Recover before acknowledging init so nothing is served
+                    // from a half-loaded log
+                    let (log, snapshotter): (Box<dyn MessageLog>, _) = match WalConfig::from_env()?
+                    {
+                        Some(config) => (Box::new(SegmentLog::open(config)?), None),
+                        None => {
+                            let snapshotter = Snapshotter::from_env(node_id)?;
+                            let log: MemoryLog = match snapshotter {
+                                Some(ref s) => s.load()?.unwrap_or_default(),
+                                None => MemoryLog::default(),
+                            };
+                            (Box::new(log), snapshotter)
+                        }
+                    };
                     let reply = Message {
                         src: init_msg.dest,
                         dest: init_msg.src,
                         body: Body {
                             msg_id: Some(0),
                             in_reply_to: init_msg.body.msg_id,
+                            clock: None,
                             payload: Payload::InitOk,
                         },
                     };
-                    serde_json::to_writer(&mut output, &reply)
-                        .context("serialize response to init")
-                        .unwrap();
-                    output
-                        .write_all(b"\n")
-                        .context("write trailing newline")
-                        .unwrap();
+                    output.send(&reply).context("send init_ok")?;
                     let mut other_nodes_seen: HashMap<String, HashSet<(String, usize, usize)>> =
                         HashMap::new();
                     for n in node_ids {
@@ -68,32 +121,46 @@ impl<'a> KafkaNode<'a> {
                         }
                         other_nodes_seen.insert(n.to_string(), HashSet::new());
                     }
-                    thread::spawn(move || loop {
-                        std::thread::sleep(Duration::from_millis(30));
-                        debug!("Sending GossipNow");
-                        tx.send(Event::Injected(Injected::GossipNow)).unwrap();
-                    });
-
                     /*
                     for k in node_ids.iter() {
                         other_nodes_seen.insert(k.to_string(), HashSet::new());
                     }
                     */
-                    KafkaNode {
+                    Ok(KafkaNode {
                         node_id: Some(node_id.clone()),
                         node_msg_id: 1,
-                        output: output,
-                        logs: HashMap::new(),
-                        committed: HashMap::new(),
-                        other_nodes_seen: other_nodes_seen,
-                    }
+                        node_ids: node_ids.clone(),
+                        output,
+                        dedup: DedupCache::default(),
+                        clock: MessageClock::from_env(node_id, node_ids)?,
+                        membership: Membership::from_env(node_id, node_ids)?,
+                        id_gen,
+                        log,
+                        retention: RetentionPolicy::from_env()?,
+                        snapshotter,
+                        last_retention: Instant::now(),
+                        cluster: ClusterConfig::from_env()?
+                            .map(|config| Cluster::from_config(node_id, node_ids, config)),
+                        cluster_waiters: Vec::new(),
+                        gathers: HashMap::new(),
+                        parts: HashMap::new(),
+                        groups: GroupCoordinator::default(),
+                        forwarded: HashMap::new(),
+                        other_nodes_seen,
+                    })
                 } else {
                     error!("Expected Init message as first message");
-                    panic!("")
+                    bail!("expected init, got {:?}", init_msg)
                 }
             }
         }
     }
+    // Spread keys over the nodes with dynamic membership, as FLY_CLUSTER
+    // does
+    pub fn start_cluster(&mut self, config: ClusterConfig) {
+        let node_id = self.node_id.clone().unwrap();
+        self.cluster = Some(Cluster::from_config(&node_id, &self.node_ids, config));
+    }
     pub fn create_message(
         &mut self,
         src: String,
@@ -101,21 +168,20 @@ impl<'a> KafkaNode<'a> {
         in_reply_to: Option<usize>,
         payload: Payload,
     ) -> Message {
-        let msg_id = Some(self.node_msg_id.clone());
+        let msg_id = Some(self.node_msg_id);
         let body = Body {
             msg_id,
             in_reply_to,
+            clock: None,
             payload,
         };
         self.node_msg_id += 1;
         Message { src, dest, body }
     }
-    pub fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        serde_json::to_writer(&mut self.output, &msg).context("serialize response to Generate")?;
-        self.output
-            .write_all(b"\n")
-            .context("write trailing newline")?;
-        Ok(())
+    pub fn send(&mut self, mut msg: Message) -> anyhow::Result<()> {
+        self.clock.stamp(&mut msg);
+        self.dedup.record(&msg);
+        self.output.send(&msg)
     }
     pub fn write_message(
         &mut self,
@@ -128,33 +194,118 @@ impl<'a> KafkaNode<'a> {
         self.send(msg)
     }
     pub fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        self.step_at(input, Instant::now())
+    }
+    pub fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
         match input {
             Event::EOF => {}
             Event::Message(input) => match input.body.payload {
-                Payload::Send { key, msg } => {
-                    let entry = self.logs.entry(key).or_insert(Vec::new());
-                    let offset = entry[..].last().unwrap_or(&(0, 0)).0 + 1 as usize;
-                    entry.push((offset, msg));
+                _ if self
+                    .membership
+                    .as_mut()
+                    .is_some_and(|m| m.handle(&input.src, &input.body.payload)) => {}
+                _ if self.handle_cluster(&input)? => {}
+                _ if self.group_coordinator(&input.body.payload).is_some() => {
+                    let coordinator = self
+                        .group_coordinator(&input.body.payload)
+                        .context("coordinator for forwarded request")?;
+                    self.forward(coordinator, input)?;
+                }
+                _ if input
+                    .body
+                    .in_reply_to
+                    .is_some_and(|id| self.forwarded.contains_key(&id)) =>
+                {
+                    self.relay(input)?;
+                }
+                _ if input
+                    .body
+                    .in_reply_to
+                    .is_some_and(|id| self.parts.contains_key(&id)) =>
+                {
+                    self.gather(input)?;
+                }
+                _ if self.is_routed(&input.body.payload) => {
+                    self.route(input, now)?;
+                }
+                Payload::Send { key, msg, sub_key } => {
+                    let offset = self.log.append(&key, msg, sub_key.as_deref())?;
                     self.write_message(
                         input.dest,
                         input.src,
                         input.body.msg_id,
-                        Payload::SendOk{offset},
+                        Payload::SendOk { offset },
                     )?;
                 }
-                Payload::SendOk { offset } => {bail!("didn't expect SendOk")}
-                Payload::Poll { offsets } => {
-
-
+                Payload::SendOk { .. } => bail!("didn't expect SendOk"),
+                Payload::SendBatch { msgs } => {
+                    let payload = match self.log.append_batch(&msgs) {
+                        Ok(offsets) => Payload::SendBatchOk { offsets },
+                        Err(e) => {
+                            error!("batch of {} messages failed: {:#}", msgs.len(), e);
+                            Payload::Error {
+                                code: error_code::ABORT,
+                                text: format!("batch not appended: {:#}", e),
+                            }
+                        }
+                    };
+                    self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
-                Payload::PollOk { msgs } => {}
-                Payload::CommitOffsets { offsets } => {}
-                Payload::CommitOffsetsOk { .. } => {}
-                Payload::ListCommittedOffsets { keys } => {}
-                Payload::ListCommittedOffsetsOk { offsets } => {}
-                Payload::Generate { .. } => {
-                    let id = Uuid::new_v4();
-                    let payload = Payload::GenerateOk { id: id.to_string() };
+                Payload::SendBatchOk { .. } => bail!("didn't expect SendBatchOk"),
+                payload @ (Payload::Poll { .. }
+                | Payload::CommitOffsets { .. }
+                | Payload::ListCommittedOffsets { .. }) => {
+                    let reply = self.serve(payload)?;
+                    self.write_message(input.dest, input.src, input.body.msg_id, reply)?;
+                }
+                Payload::PollOk { .. } => {}
+                Payload::CommitOffsetsOk => {}
+                Payload::ListCommittedOffsetsOk { .. } => {}
+                Payload::JoinCluster | Payload::LeaveCluster if self.cluster.is_some() => {
+                    let cluster = self.cluster.as_mut().expect("cluster");
+                    match input.body.payload {
+                        Payload::JoinCluster => cluster.join(),
+                        _ => cluster.leave(),
+                    }
+                    self.cluster_waiters.push((input.src, input.body.msg_id));
+                }
+                Payload::JoinGroup {
+                    group,
+                    member,
+                    keys,
+                    strategy,
+                } => {
+                    let (generation, keys) = self.groups.join(&group, &member, keys, strategy);
+                    debug!("{} joined {} in generation {}", member, group, generation);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::JoinGroupOk { generation, keys },
+                    )?;
+                }
+                Payload::LeaveGroup { group, member } => {
+                    let generation = self.groups.leave(&group, &member);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::LeaveGroupOk { generation },
+                    )?;
+                }
+                Payload::SyncGroup { group, member } => {
+                    let payload = match self.groups.assignment(&group, &member) {
+                        Some((generation, keys)) => Payload::SyncGroupOk { generation, keys },
+                        None => Payload::Error {
+                            code: error_code::PRECONDITION_FAILED,
+                            text: format!("{} isn't a member of {}", member, group),
+                        },
+                    };
+                    self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
+                }
+                Payload::Generate => {
+                    let id = self.id_gen.next_id()?;
+                    let payload = Payload::GenerateOk { id };
                     self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
                 }
                 Payload::Topology { ref topology } => {
@@ -191,24 +342,369 @@ impl<'a> KafkaNode<'a> {
                     )?;
                 }
                 _ => {
-                    bail!("Received unexpected msg for KafkaNode: {:?}", input)
+                    if input.body.msg_id.is_none() {
+                        bail!("Received unexpected msg for KafkaNode: {:?}", input)
+                    }
+                    let text = format!("KafkaNode doesn't support {:?}", input.body.payload);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::Error {
+                            code: error_code::NOT_SUPPORTED,
+                            text,
+                        },
+                    )?;
                 }
             },
-            Event::Injected(_input) => {
+            Event::Injected(Injected::GossipNow) => {
+                if let Some(membership) = self.membership.as_mut() {
+                    membership.tick();
+                }
+                if let Some(cluster) = self.cluster.as_mut() {
+                    cluster
+                        .tick(crate::ids::now_ms(), self.log.as_mut())
+                        .context("tick cluster")?;
+                }
                 let _ = self.gossip();
+                self.expire_gathers(now)?;
+                if self.retention.is_enabled()
+                    && now.saturating_duration_since(self.last_retention) >= RETENTION_INTERVAL
+                {
+                    self.last_retention = now;
+                    let removed = self
+                        .log
+                        .retain(&self.retention, crate::ids::now_ms())
+                        .context("apply retention")?;
+                    debug!("retention removed {} messages", removed);
+                }
+                if self.snapshotter.as_ref().is_some_and(|s| s.is_due()) {
+                    self.save_snapshot().context("save snapshot")?;
+                }
+            }
+            // Peer events are only for nodes that skip suspected peers
+            Event::Injected(_) => {}
+        }
+
+        self.flush_membership()?;
+        self.flush_cluster()
+    }
+
+    // Send the membership protocol's messages
+    fn flush_membership(&mut self) -> anyhow::Result<()> {
+        let Some(membership) = self.membership.as_mut() else {
+            return Ok(());
+        };
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in membership.take_messages() {
+            self.write_message(node_id.clone(), dest, None, payload)?;
+        }
+        Ok(())
+    }
+
+    // Send the cluster protocol's messages, and answer the clients waiting
+    // on this node to join or leave once it has
+    fn flush_cluster(&mut self) -> anyhow::Result<()> {
+        let Some(cluster) = self.cluster.as_mut() else {
+            return Ok(());
+        };
+        let messages = cluster.take_messages();
+        let done = !cluster.is_changing();
+        let epoch = cluster.view().epoch;
+        let payload = match cluster.is_member() {
+            true => Payload::JoinClusterOk { epoch },
+            false => Payload::LeaveClusterOk { epoch },
+        };
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in messages {
+            self.write_message(node_id.clone(), dest, None, payload)?;
+        }
+        if done {
+            for (client, msg_id) in std::mem::take(&mut self.cluster_waiters) {
+                self.write_message(node_id.clone(), client, msg_id, payload.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_cluster(&mut self, input: &Message) -> anyhow::Result<bool> {
+        let Some(cluster) = self.cluster.as_mut() else {
+            return Ok(false);
+        };
+        cluster.handle(&input.src, &input.body.payload, self.log.as_mut())
+    }
+
+    // Whether payload should go to the owners of its keys rather than be
+    // served here, or be refused while this node's keys are moving
+    fn is_routed(&self, payload: &Payload) -> bool {
+        let (Some(cluster), Some(keys)) = (self.cluster.as_ref(), request_keys(payload)) else {
+            return false;
+        };
+        !cluster.is_member()
+            || !cluster.is_settled()
+            || keys
+                .iter()
+                .any(|k| cluster.owner(k) != self.node_id.as_ref())
+    }
+
+    // Send a request on to the nodes that own its keys, splitting it if
+    // there's more than one
+    fn route(&mut self, input: Message, now: Instant) -> anyhow::Result<()> {
+        let cluster = self.cluster.as_ref().context("routing without a cluster")?;
+        let keys = request_keys(&input.body.payload).context("routing unkeyed request")?;
+        let node_id = self.node_id.clone().unwrap();
+        // owner -> keys it owns
+        let mut owners: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in keys {
+            let Some(owner) = cluster.owner(key) else {
+                return self.refuse(input, error_code::TEMPORARILY_UNAVAILABLE, "no members");
+            };
+            owners
+                .entry(owner.clone())
+                .or_default()
+                .push(key.to_string());
+        }
+        if owners.contains_key(&node_id) && !cluster.is_settled() {
+            return self.refuse(
+                input,
+                error_code::TEMPORARILY_UNAVAILABLE,
+                "keys are moving between nodes",
+            );
+        }
+        // Requests another node sent aren't forwarded again, so nodes with
+        // different views can't pass one back and forth
+        if self.node_ids.contains(&input.src) || cluster.view().members.contains(&input.src) {
+            return self.refuse(
+                input,
+                error_code::TEMPORARILY_UNAVAILABLE,
+                "not the owner of these keys",
+            );
+        }
+        if owners.len() == 1 {
+            let owner = owners.into_keys().next().expect("one owner");
+            return self.forward(owner, input);
+        }
+        if let Payload::SendBatch { .. } = input.body.payload {
+            return self.refuse(
+                input,
+                error_code::ABORT,
+                "batch spans keys on several nodes",
+            );
+        }
+
+        let mut gather = Gather {
+            client: input.src,
+            client_msg_id: input.body.msg_id,
+            parts: 0,
+            reply: match input.body.payload {
+                Payload::Poll { .. } => Payload::PollOk {
+                    msgs: HashMap::new(),
+                },
+                Payload::CommitOffsets { .. } => Payload::CommitOffsetsOk,
+                _ => Payload::ListCommittedOffsetsOk {
+                    offsets: HashMap::new(),
+                },
+            },
+            started: now,
+        };
+        let mut first = None;
+        for (owner, keys) in owners {
+            let part = split(&input.body.payload, &keys);
+            if owner == node_id {
+                merge(&mut gather.reply, self.serve(part)?);
+                continue;
             }
+            let msg = self.create_message(node_id.clone(), owner, None, part);
+            let msg_id = msg.body.msg_id.context("part without msg_id")?;
+            let first = *first.get_or_insert(msg_id);
+            self.parts.insert(msg_id, first);
+            gather.parts += 1;
+            self.send(msg)?;
+        }
+        let first = first.context("split request with no remote parts")?;
+        self.gathers.insert(first, gather);
+        Ok(())
+    }
+
+    // Fold an owner's answer into the reply, sending it once all are in.
+    // An error goes straight back to the client instead.
+    fn gather(&mut self, input: Message) -> anyhow::Result<()> {
+        let Some(first) = input.body.in_reply_to.and_then(|id| self.parts.remove(&id)) else {
+            bail!("no split request for {:?}", input);
+        };
+        let Some(gather) = self.gathers.get_mut(&first) else {
+            debug!("dropping {:?} for a request already answered", input);
+            return Ok(());
+        };
+        if let Payload::Error { .. } = input.body.payload {
+            let gather = self.gathers.remove(&first).expect("gather");
+            let node_id = self.node_id.clone().unwrap();
+            return self.write_message(
+                node_id,
+                gather.client,
+                gather.client_msg_id,
+                input.body.payload,
+            );
+        }
+        merge(&mut gather.reply, input.body.payload);
+        gather.parts -= 1;
+        if gather.parts > 0 {
+            return Ok(());
         }
+        let gather = self.gathers.remove(&first).expect("gather");
+        self.write_message(
+            input.dest,
+            gather.client,
+            gather.client_msg_id,
+            gather.reply,
+        )
+    }
 
+    // Answer split requests some owner never replied to with a timeout, and
+    // forget their parts so late replies are dropped
+    fn expire_gathers(&mut self, now: Instant) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .gathers
+            .iter()
+            .filter(|(_, g)| now.saturating_duration_since(g.started) >= GATHER_TIMEOUT)
+            .map(|(first, _)| *first)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        self.parts.retain(|_, first| !expired.contains(first));
+        let node_id = self.node_id.clone().unwrap();
+        for first in expired {
+            let gather = self.gathers.remove(&first).expect("expired gather");
+            self.write_message(
+                node_id.clone(),
+                gather.client,
+                gather.client_msg_id,
+                Payload::Error {
+                    code: error_code::TIMEOUT,
+                    text: format!("{} of the key owners didn't reply", gather.parts),
+                },
+            )?;
+        }
         Ok(())
     }
 
+    fn refuse(&mut self, input: Message, code: usize, text: &str) -> anyhow::Result<()> {
+        let payload = Payload::Error {
+            code,
+            text: text.to_string(),
+        };
+        self.write_message(input.dest, input.src, input.body.msg_id, payload)
+    }
+
+    // Answer a poll or an offsets request from the local log
+    fn serve(&mut self, payload: Payload) -> anyhow::Result<Payload> {
+        match payload {
+            Payload::Poll { offsets } => {
+                let mut msgs = HashMap::new();
+                for (key, offset) in offsets {
+                    let from = self.log.read(&key, offset)?;
+                    if !from.is_empty() {
+                        msgs.insert(key, from);
+                    }
+                }
+                Ok(Payload::PollOk { msgs })
+            }
+            Payload::CommitOffsets { offsets, group } => {
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                for (key, offset) in offsets {
+                    self.log.commit(group, &key, offset)?;
+                }
+                Ok(Payload::CommitOffsetsOk)
+            }
+            Payload::ListCommittedOffsets { keys, group } => {
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                let offsets = keys
+                    .into_iter()
+                    .filter_map(|k| self.log.committed(group, &k).map(|o| (k, o)))
+                    .collect();
+                Ok(Payload::ListCommittedOffsetsOk { offsets })
+            }
+            _ => bail!("can't serve {:?} from the log", payload),
+        }
+    }
+
+    fn save_snapshot(&mut self) -> anyhow::Result<()> {
+        if let (Some(snapshotter), Some(log)) = (self.snapshotter.as_mut(), self.log.snapshot()) {
+            snapshotter.save(log)?;
+        }
+        Ok(())
+    }
+
+    // The coordinator to forward payload to, if it's a request for a
+    // consumer group that another node coordinates. In a cluster committed
+    // offsets live with their key instead.
+    fn group_coordinator(&self, payload: &Payload) -> Option<String> {
+        let group = match payload {
+            Payload::CommitOffsets {
+                group: Some(group), ..
+            }
+            | Payload::ListCommittedOffsets {
+                group: Some(group), ..
+            } if self.cluster.is_none() => {
+                // Committed offsets stay with the coordinator from init even
+                // while it's suspected, so they're never split between nodes
+                let owner = coordinator_for(group, &self.node_ids)?;
+                return (Some(owner) != self.node_id.as_ref()).then(|| owner.clone());
+            }
+            Payload::JoinGroup { group, .. }
+            | Payload::LeaveGroup { group, .. }
+            | Payload::SyncGroup { group, .. } => group,
+            _ => return None,
+        };
+        let nodes: Vec<String> = match self.cluster {
+            Some(ref c) => c.view().members.iter().cloned().collect(),
+            None => self.node_ids.clone(),
+        };
+        // Groups move off nodes declared dead
+        let live: Vec<String> = match self.membership {
+            Some(ref m) => nodes.into_iter().filter(|n| m.is_live(n)).collect(),
+            None => nodes,
+        };
+        let coordinator = coordinator_for(group, &live)?;
+        (Some(coordinator) != self.node_id.as_ref()).then(|| coordinator.clone())
+    }
+
+    // Pass a client's request to the node that handles it, to relay the
+    // reply back
+    fn forward(&mut self, dest: String, input: Message) -> anyhow::Result<()> {
+        debug!("forwarding {:?} to {}", input, dest);
+        let msg = self.create_message(
+            self.node_id.clone().unwrap(),
+            dest,
+            None,
+            input.body.payload,
+        );
+        if let Some(msg_id) = msg.body.msg_id {
+            self.forwarded
+                .insert(msg_id, (input.src, input.body.msg_id));
+        }
+        self.send(msg)
+    }
+
+    // Pass a coordinator's reply back to the client that asked us
+    fn relay(&mut self, input: Message) -> anyhow::Result<()> {
+        let Some((client, client_msg_id)) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.forwarded.remove(&id))
+        else {
+            bail!("no forwarded request for {:?}", input);
+        };
+        self.write_message(input.dest, client, client_msg_id, input.body.payload)
+    }
+
     fn gossip(&mut self) -> anyhow::Result<()> {
         /*
         debug!("in gossip");
         for key in self
             .other_nodes_seen
             .keys()
-            .into_iter()
             .cloned()
             .collect::<Vec<_>>()
             .iter()
@@ -255,3 +751,185 @@ impl<'a> KafkaNode<'a> {
         Ok(())
     }
 }
+
+// The keys a request reads or writes, for requests that go to the keys'
+// owners in a cluster
+fn request_keys(payload: &Payload) -> Option<Vec<&str>> {
+    let keys = match payload {
+        Payload::Send { key, .. } => vec![key.as_str()],
+        Payload::SendBatch { msgs } => msgs.iter().map(|m| m.key.as_str()).collect(),
+        Payload::Poll { offsets } | Payload::CommitOffsets { offsets, .. } => {
+            offsets.keys().map(|k| k.as_str()).collect()
+        }
+        Payload::ListCommittedOffsets { keys, .. } => keys.iter().map(|k| k.as_str()).collect(),
+        _ => return None,
+    };
+    Some(keys)
+}
+
+// The part of a poll or an offsets request that's for keys
+fn split(payload: &Payload, keys: &[String]) -> Payload {
+    let pick = |offsets: &HashMap<String, usize>| {
+        keys.iter()
+            .filter_map(|k| offsets.get(k).map(|o| (k.clone(), *o)))
+            .collect()
+    };
+    match payload {
+        Payload::Poll { offsets } => Payload::Poll {
+            offsets: pick(offsets),
+        },
+        Payload::CommitOffsets { offsets, group } => Payload::CommitOffsets {
+            offsets: pick(offsets),
+            group: group.clone(),
+        },
+        Payload::ListCommittedOffsets { group, .. } => Payload::ListCommittedOffsets {
+            keys: keys.to_vec(),
+            group: group.clone(),
+        },
+        _ => payload.clone(),
+    }
+}
+
+// Add one owner's answer to a split request's reply
+fn merge(reply: &mut Payload, part: Payload) {
+    match (reply, part) {
+        (Payload::PollOk { msgs }, Payload::PollOk { msgs: more }) => msgs.extend(more),
+        (
+            Payload::ListCommittedOffsetsOk { offsets },
+            Payload::ListCommittedOffsetsOk { offsets: more },
+        ) => offsets.extend(more),
+        _ => {}
+    }
+}
+
+impl Node for KafkaNode {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        KafkaNode::new(init_msg, output)
+    }
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        KafkaNode::step(self, input)
+    }
+    fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        KafkaNode::step_at(self, input, now)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        KafkaNode::send(self, msg)
+    }
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        Some(&mut self.dedup)
+    }
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        Some(&mut self.clock)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush()
+    }
+    fn on_shutdown(&mut self) -> anyhow::Result<()> {
+        self.log.sync().context("sync log on shutdown")?;
+        self.save_snapshot().context("save snapshot on shutdown")
+    }
+}
+
+#[test]
+fn a_node_outside_the_initial_members_joins_and_takes_its_keys() -> anyhow::Result<()> {
+    use crate::cluster::ClusterView;
+    use crate::harness::HarnessNet;
+
+    let mut net = HarnessNet::<KafkaNode>::new(&["n0", "n1", "n2"])?;
+    for id in ["n0", "n1", "n2"] {
+        net.node(id).node().start_cluster(ClusterConfig {
+            retry_ms: 200,
+            members: Some(vec!["n0".to_string(), "n1".to_string()]),
+        });
+    }
+    let keys: Vec<String> = (0..30).map(|i| format!("k{}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+        let send = Payload::Send {
+            key: key.clone(),
+            msg: i,
+            sub_key: None,
+        };
+        assert!(matches!(net.call("n0", send)?, Payload::SendOk { .. }));
+    }
+
+    let msg_id = net.request("n2", Payload::JoinCluster);
+    net.deliver()?;
+    // The join goes to the coordinator on the next tick
+    assert!(net.reply(msg_id).is_none());
+    net.tick()?;
+    assert!(matches!(
+        net.reply(msg_id),
+        Some(Payload::JoinClusterOk { epoch: 1 })
+    ));
+
+    let view = ClusterView {
+        epoch: 1,
+        members: ["n0", "n1", "n2"].iter().map(|n| n.to_string()).collect(),
+    };
+    let taken: Vec<&String> = keys
+        .iter()
+        .filter(|k| view.owner(k).unwrap() == "n2")
+        .collect();
+    assert!(!taken.is_empty());
+    // Handed over to n2, and served from there
+    let held = net.node("n2").node().log.keys();
+    assert!(taken.iter().all(|k| held.contains(k)));
+    for key in keys.iter() {
+        let poll = Payload::Poll {
+            offsets: HashMap::from([(key.clone(), 0)]),
+        };
+        match net.call("n2", poll)? {
+            Payload::PollOk { msgs } => assert_eq!(msgs[key].len(), 1, "{}", key),
+            other => bail!("expected poll_ok for {}, got {:?}", key, other),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn a_split_poll_times_out_when_an_owner_never_replies() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let mut net = HarnessNet::<KafkaNode>::new(&["n0", "n1"])?;
+    for id in ["n0", "n1"] {
+        net.node(id).node().start_cluster(ClusterConfig::default());
+    }
+    // A key each node owns
+    let view = net
+        .node("n0")
+        .node()
+        .cluster
+        .as_ref()
+        .expect("cluster")
+        .view()
+        .clone();
+    let key_on = |owner: &str| {
+        (0..)
+            .map(|i| format!("k{}", i))
+            .find(|k| view.owner(k).is_some_and(|o| o == owner))
+            .expect("key")
+    };
+    let offsets = HashMap::from([(key_on("n0"), 0), (key_on("n1"), 0)]);
+
+    net.partition(&["n1"]);
+    let msg_id = net.request("n0", Payload::Poll { offsets });
+    net.deliver()?;
+    net.tick()?;
+    assert!(net.reply(msg_id).is_none());
+
+    net.advance(GATHER_TIMEOUT);
+    net.tick()?;
+    assert!(matches!(
+        net.reply(msg_id),
+        Some(Payload::Error {
+            code: error_code::TIMEOUT,
+            ..
+        })
+    ));
+    let n0 = net.node("n0").node();
+    assert!(n0.gathers.is_empty() && n0.parts.is_empty());
+    Ok(())
+}
@@ -0,0 +1,459 @@
+use crate::clock::MessageClock;
+use crate::consensus::{Backend, Consensus};
+use crate::dedup::DedupCache;
+use crate::kv::{KvCommand, KvStore};
+use crate::msg::{error_code, Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::MessageSink;
+use anyhow::{bail, Context};
+use log::{debug, error, warn};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How long a request forwarded to the leader waits for its reply before the
+// client is told it timed out
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A lin-kv node: reads, writes and compare-and-sets go through a replicated
+// log, Raft or Multi-Paxos as FLY_CONSENSUS picks, and are applied in log
+// order, so every operation is linearizable
+pub struct LinKvNode {
+    node_id: Option<String>,
+    node_msg_id: usize,
+    node_ids: Vec<String>,
+    output: Box<dyn MessageSink>,
+    dedup: DedupCache,
+    clock: MessageClock,
+    consensus: Box<dyn Consensus>,
+    kv: KvStore,
+    // The consensus clock starts at zero when the node does
+    start: Instant,
+    // Log position -> (tag it was proposed with, client, client msg_id)
+    // for commands proposed here, to reply once they're applied
+    pending: HashMap<u64, (u64, String, Option<usize>)>,
+    // Requests forwarded to the leader, by the msg_id we sent them with,
+    // mapped to the client and msg_id to relay the reply to and when they
+    // were sent
+    forwarded: HashMap<usize, (String, Option<usize>, Instant)>,
+}
+
+impl LinKvNode {
+    pub fn new(
+        init_msg: Event<Message, Injected>,
+        mut output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        debug!("in LinKvNode::new");
+        match init_msg {
+            Event::EOF => {
+                bail!("expected init, got EOF")
+            }
+            Event::Injected(..) => {
+                bail!("expected init, got injected event")
+            }
+            Event::Message(init_msg) => {
+                if let Payload::Init {
+                    ref node_id,
+                    ref node_ids,
+                } = init_msg.body.payload
+                {
+                    debug!("init_msg: {:?}", init_msg.clone());
+                    let backend = Backend::from_env()?;
+                    let reply = Message {
+                        src: init_msg.dest,
+                        dest: init_msg.src,
+                        body: Body {
+                            msg_id: Some(0),
+                            in_reply_to: init_msg.body.msg_id,
+                            clock: None,
+                            payload: Payload::InitOk,
+                        },
+                    };
+                    output.send(&reply).context("send init_ok")?;
+                    Ok(LinKvNode {
+                        node_id: Some(node_id.clone()),
+                        node_msg_id: 1,
+                        node_ids: node_ids.clone(),
+                        output,
+                        dedup: DedupCache::default(),
+                        clock: MessageClock::from_env(node_id, node_ids)?,
+                        consensus: backend.start(node_id, node_ids, rand::random(), 0),
+                        kv: KvStore::default(),
+                        start: Instant::now(),
+                        pending: HashMap::new(),
+                        forwarded: HashMap::new(),
+                    })
+                } else {
+                    error!("Expected Init message as first message");
+                    bail!("expected init, got {:?}", init_msg)
+                }
+            }
+        }
+    }
+    pub fn create_message(
+        &mut self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+    ) -> Message {
+        let msg_id = Some(self.node_msg_id);
+        let body = Body {
+            msg_id,
+            in_reply_to,
+            clock: None,
+            payload,
+        };
+        self.node_msg_id += 1;
+        Message { src, dest, body }
+    }
+    pub fn send(&mut self, mut msg: Message) -> anyhow::Result<()> {
+        self.clock.stamp(&mut msg);
+        self.dedup.record(&msg);
+        self.output.send(&msg)
+    }
+    pub fn write_message(
+        &mut self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+    ) -> anyhow::Result<()> {
+        let msg = self.create_message(src, dest, in_reply_to, payload);
+        self.send(msg)
+    }
+    pub fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        self.step_at(input, Instant::now())
+    }
+    pub fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        let now_ms = now.saturating_duration_since(self.start).as_millis() as u64;
+        match input {
+            Event::EOF => {}
+            Event::Message(input) => match input.body.payload {
+                _ if input
+                    .body
+                    .in_reply_to
+                    .is_some_and(|id| self.forwarded.contains_key(&id)) =>
+                {
+                    self.relay(input)?;
+                }
+                _ if self
+                    .consensus
+                    .handle(&input.src, &input.body.payload, now_ms) => {}
+                Payload::Read { key: Some(_) } | Payload::Write { .. } | Payload::Cas { .. } => {
+                    self.client_request(input, now)?;
+                }
+                _ => {
+                    if input.body.msg_id.is_none() {
+                        bail!("Received unexpected msg for LinKvNode: {:?}", input)
+                    }
+                    let text = format!("LinKvNode doesn't support {:?}", input.body.payload);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::Error {
+                            code: error_code::NOT_SUPPORTED,
+                            text,
+                        },
+                    )?;
+                }
+            },
+            Event::Injected(Injected::GossipNow) => {
+                self.consensus.tick(now_ms);
+                self.expire_forwarded(now)?;
+            }
+            // Peer events are only for nodes that skip suspected peers
+            Event::Injected(_) => {}
+        }
+        self.flush_consensus()
+    }
+
+    // Propose a client's operation if we lead, otherwise hand it to the
+    // leader. Requests another node forwarded aren't forwarded again, so
+    // nodes with stale ideas of the leader can't bounce one between them.
+    fn client_request(&mut self, input: Message, now: Instant) -> anyhow::Result<()> {
+        let command = KvCommand::from_payload(&input.body.payload).context("lin-kv request")?;
+        let error = match self.consensus.propose(serde_json::to_value(&command)?) {
+            Ok((index, tag)) => {
+                self.pending
+                    .insert(index, (tag, input.src, input.body.msg_id));
+                return Ok(());
+            }
+            Err(Some(leader)) if !self.node_ids.contains(&input.src) => {
+                let msg = self.create_message(input.dest, leader, None, input.body.payload);
+                if let Some(msg_id) = msg.body.msg_id {
+                    self.forwarded
+                        .insert(msg_id, (input.src, input.body.msg_id, now));
+                }
+                return self.send(msg);
+            }
+            Err(_) => "no leader to handle the request",
+        };
+        self.write_message(
+            input.dest,
+            input.src,
+            input.body.msg_id,
+            Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: error.to_string(),
+            },
+        )
+    }
+
+    // Pass the leader's reply back to the client that asked us
+    fn relay(&mut self, input: Message) -> anyhow::Result<()> {
+        let Some((client, client_msg_id, _)) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.forwarded.remove(&id))
+        else {
+            bail!("no forwarded request for {:?}", input);
+        };
+        self.write_message(input.dest, client, client_msg_id, input.body.payload)
+    }
+
+    // Give up on forwarded requests the leader hasn't answered, e.g. because
+    // it has gone away. It may still have applied them, so the client gets
+    // an indefinite error.
+    fn expire_forwarded(&mut self, now: Instant) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .forwarded
+            .iter()
+            .filter(|(_, (_, _, sent))| now.saturating_duration_since(*sent) >= FORWARD_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        let node_id = self.node_id.clone().unwrap();
+        for id in expired {
+            let (client, client_msg_id, _) = self.forwarded.remove(&id).expect("expired request");
+            self.write_message(
+                node_id.clone(),
+                client,
+                client_msg_id,
+                Payload::Error {
+                    code: error_code::TIMEOUT,
+                    text: "no reply from the leader".to_string(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // Send the consensus messages and apply whatever has committed, replying
+    // to clients whose commands were proposed here
+    fn flush_consensus(&mut self) -> anyhow::Result<()> {
+        let node_id = self.node_id.clone().unwrap();
+        for (dest, payload) in self.consensus.take_messages() {
+            let msg = self.create_message(node_id.clone(), dest, None, payload);
+            self.send(msg)?;
+        }
+        for (index, tag, command) in self.consensus.take_committed() {
+            let command: KvCommand =
+                serde_json::from_value(command).context("deserialize committed command")?;
+            let reply = self.kv.apply(&command);
+            let Some((proposed_tag, client, client_msg_id)) = self.pending.remove(&index) else {
+                continue;
+            };
+            // A different entry committed at this index, so ours never will
+            let reply = if proposed_tag == tag {
+                reply
+            } else {
+                warn!(
+                    "entry {} proposed with {} was replaced",
+                    index, proposed_tag
+                );
+                Payload::Error {
+                    code: error_code::TEMPORARILY_UNAVAILABLE,
+                    text: "leadership changed before the request committed".to_string(),
+                }
+            };
+            self.write_message(node_id.clone(), client, client_msg_id, reply)?;
+        }
+        // Positions committed as a new leader's no-ops don't come back from
+        // take_committed, but ours were replaced there all the same
+        let commit_index = self.consensus.commit_index();
+        let lost: Vec<u64> = self
+            .pending
+            .keys()
+            .filter(|index| **index <= commit_index)
+            .cloned()
+            .collect();
+        for index in lost {
+            let (_, client, client_msg_id) = self.pending.remove(&index).expect("lost entry");
+            warn!("entry {} was replaced by a no-op", index);
+            self.write_message(
+                node_id.clone(),
+                client,
+                client_msg_id,
+                Payload::Error {
+                    code: error_code::TEMPORARILY_UNAVAILABLE,
+                    text: "leadership changed before the request committed".to_string(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Node for LinKvNode {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        LinKvNode::new(init_msg, output)
+    }
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        LinKvNode::step(self, input)
+    }
+    fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        LinKvNode::step_at(self, input, now)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        LinKvNode::send(self, msg)
+    }
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        Some(&mut self.dedup)
+    }
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        Some(&mut self.clock)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[test]
+fn followers_relay_requests_and_a_minority_refuses_them() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let mut net = HarnessNet::<LinKvNode>::new(&["n0", "n1", "n2"])?;
+    net.run(2000)?;
+    let (leader, followers): (Vec<&str>, Vec<&str>) = ["n0", "n1", "n2"]
+        .into_iter()
+        .partition(|n| net.node(n).node().consensus.is_leader());
+    assert_eq!(leader.len(), 1);
+    let call = |net: &mut HarnessNet<LinKvNode>, dest: &str, payload: Payload| {
+        let msg_id = net.request(dest, payload);
+        let reply = net.wait(msg_id, 1000)?;
+        // Answered by the node the client asked, whoever did the work
+        assert_eq!(reply.src, dest);
+        anyhow::Ok(reply.body.payload)
+    };
+
+    let follower = followers[0];
+    let write = Payload::Write { key: 1, value: 1 };
+    assert!(matches!(call(&mut net, follower, write)?, Payload::WriteOk));
+    let cas = Payload::Cas {
+        key: 1,
+        from: 1,
+        to: 2,
+    };
+    assert!(matches!(call(&mut net, follower, cas)?, Payload::CasOk));
+    let stale = Payload::Cas {
+        key: 1,
+        from: 1,
+        to: 3,
+    };
+    assert!(matches!(
+        call(&mut net, follower, stale)?,
+        Payload::Error {
+            code: error_code::PRECONDITION_FAILED,
+            ..
+        }
+    ));
+    assert!(matches!(
+        call(&mut net, follower, Payload::Read { key: Some(1) })?,
+        Payload::ReadOk { value: Some(2), .. }
+    ));
+
+    // Cut off on its own, a follower soon loses track of the leader and
+    // turns clients away, while the majority carries on without it
+    let cut_off = followers[1];
+    net.partition(&[cut_off]);
+    net.run(2000)?;
+    for payload in [
+        Payload::Write { key: 1, value: 5 },
+        Payload::Read { key: Some(1) },
+    ] {
+        assert!(matches!(
+            call(&mut net, cut_off, payload)?,
+            Payload::Error {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                ..
+            }
+        ));
+    }
+    let write = Payload::Write { key: 1, value: 7 };
+    assert!(matches!(call(&mut net, follower, write)?, Payload::WriteOk));
+
+    // Once healed it catches up, and relays again
+    net.heal();
+    net.run(2000)?;
+    assert!(matches!(
+        call(&mut net, cut_off, Payload::Read { key: Some(1) })?,
+        Payload::ReadOk { value: Some(7), .. }
+    ));
+    Ok(())
+}
+
+#[test]
+fn a_request_forwarded_to_a_lost_leader_times_out() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let mut net = HarnessNet::<LinKvNode>::new(&["n0", "n1", "n2"])?;
+    net.run(2000)?;
+    let (leader, followers): (Vec<&str>, Vec<&str>) = ["n0", "n1", "n2"]
+        .into_iter()
+        .partition(|n| net.node(n).node().consensus.is_leader());
+    assert_eq!(leader.len(), 1);
+
+    // The follower still takes it for the leader, so forwards the request
+    // into the partition
+    net.partition(&[leader[0]]);
+    let msg_id = net.request(followers[0], Payload::Write { key: 1, value: 1 });
+    net.deliver()?;
+    assert!(!net.node(followers[0]).node().forwarded.is_empty());
+    assert!(matches!(
+        net.wait(msg_id, 2000)?.body.payload,
+        Payload::Error {
+            code: error_code::TIMEOUT,
+            ..
+        }
+    ));
+    assert!(net.node(followers[0]).node().forwarded.is_empty());
+    Ok(())
+}
+
+#[test]
+fn a_request_the_new_leader_replaced_fails() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+
+    let nodes = ["n0", "n1", "n2"];
+    let mut net = HarnessNet::<LinKvNode>::new(&nodes)?;
+    net.run(2000)?;
+    let (leader, followers): (Vec<&str>, Vec<&str>) = nodes
+        .into_iter()
+        .partition(|n| net.node(n).node().consensus.is_leader());
+    assert_eq!(leader.len(), 1);
+
+    // Cut off, the leader still takes the write, but the majority elects
+    // another whose no-op takes its place in the log
+    net.partition(&[leader[0]]);
+    let lost = net.request(leader[0], Payload::Write { key: 1, value: 1 });
+    net.run(2000)?;
+    let msg_id = net.request(followers[0], Payload::Write { key: 1, value: 2 });
+    assert!(matches!(
+        net.wait(msg_id, 1000)?.body.payload,
+        Payload::WriteOk
+    ));
+    net.heal();
+    assert!(matches!(
+        net.wait(lost, 2000)?.body.payload,
+        Payload::Error {
+            code: error_code::TEMPORARILY_UNAVAILABLE,
+            ..
+        }
+    ));
+    assert!(net.node(leader[0]).node().pending.is_empty());
+    Ok(())
+}
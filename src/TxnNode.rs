@@ -0,0 +1,701 @@
+use crate::clock::{HybridClock, MessageClock};
+use crate::dedup::DedupCache;
+use crate::msg::{error_code, Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::MessageSink;
+use crate::txn::{Consistency, MicroOp, MvccStore, ReplicatedWrites, Store, Version};
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Most units of writes sent to a peer in one replicate_writes message
+const REPLICATION_BATCH: usize = 256;
+// How long a transaction waits for the certifier before the client is told
+// its outcome is unknown
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+// How often old versions are garbage collected, checked on each tick
+const GC_INTERVAL: Duration = Duration::from_secs(1);
+
+// A snapshot isolation transaction waiting on the certifier
+struct PendingTxn {
+    client: String,
+    client_msg_id: Option<usize>,
+    // The ops with reads filled in, to reply with once it commits
+    txn: Vec<MicroOp>,
+    // Its writes, to apply here once it commits
+    writes: Vec<(usize, usize)>,
+    start_ts: u64,
+    sent: Instant,
+}
+
+pub struct TxnNode {
+    node_id: Option<String>,
+    node_msg_id: usize,
+    output: Box<dyn MessageSink>,
+    dedup: DedupCache,
+    clock: MessageClock,
+    consistency: Consistency,
+    store: Store,
+    // Snapshot isolation state. The node with the lowest id certifies every
+    // commit, so it's the one place that sees them all and can tell which
+    // committer was first, then streams them to the rest in commit order.
+    mvcc: MvccStore,
+    hlc: HybridClock,
+    certifier: String,
+    // Commit timestamp of the newest commit applied here. Everything before
+    // it has been applied too, so snapshots up to it are complete.
+    watermark: u64,
+    // Outgoing commit_txn msg_id -> the transaction it's for
+    pending: HashMap<usize, PendingTxn>,
+    last_gc: Instant,
+    // Writes made here that some peer hasn't acknowledged yet, oldest first
+    unreplicated: VecDeque<ReplicatedWrites>,
+    next_seq: usize,
+    // Peer -> highest seq it has acknowledged
+    acked: HashMap<String, usize>,
+    // Peer -> highest seq of its writes applied here
+    applied: HashMap<String, usize>,
+}
+
+impl TxnNode {
+    pub fn new(
+        init_msg: Event<Message, Injected>,
+        mut output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        debug!("in TxnNode::new");
+        match init_msg {
+            Event::EOF => {
+                bail!("expected init, got EOF")
+            }
+            Event::Injected(..) => {
+                bail!("expected init, got injected event")
+            }
+            Event::Message(init_msg) => {
+                if let Payload::Init {
+                    ref node_id,
+                    ref node_ids,
+                } = init_msg.body.payload
+                {
+                    debug!("init_msg: {:?}", init_msg.clone());
+                    let consistency = Consistency::from_env()?;
+                    info!("running transactions at {:?}", consistency);
+                    let reply = Message {
+                        src: init_msg.dest,
+                        dest: init_msg.src,
+                        body: Body {
+                            msg_id: Some(0),
+                            in_reply_to: init_msg.body.msg_id,
+                            clock: None,
+                            payload: Payload::InitOk,
+                        },
+                    };
+                    output.send(&reply).context("send init_ok")?;
+                    let acked: HashMap<String, usize> = node_ids
+                        .iter()
+                        .filter(|n| *n != node_id)
+                        .map(|n| (n.clone(), 0))
+                        .collect();
+                    let certifier = node_ids.iter().min().unwrap_or(node_id).clone();
+                    Ok(TxnNode {
+                        node_id: Some(node_id.clone()),
+                        node_msg_id: 1,
+                        output,
+                        dedup: DedupCache::default(),
+                        clock: MessageClock::from_env(node_id, node_ids)?,
+                        consistency,
+                        store: Store::default(),
+                        mvcc: MvccStore::default(),
+                        hlc: HybridClock::default(),
+                        certifier,
+                        watermark: 0,
+                        pending: HashMap::new(),
+                        last_gc: Instant::now(),
+                        unreplicated: VecDeque::new(),
+                        next_seq: 1,
+                        applied: acked.keys().map(|n| (n.clone(), 0)).collect(),
+                        acked,
+                    })
+                } else {
+                    error!("Expected Init message as first message");
+                    bail!("expected init, got {:?}", init_msg)
+                }
+            }
+        }
+    }
+    // Run transactions at consistency instead of FLY_TXN_CONSISTENCY's level
+    pub fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+    pub fn create_message(
+        &mut self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+    ) -> Message {
+        let msg_id = Some(self.node_msg_id);
+        let body = Body {
+            msg_id,
+            in_reply_to,
+            clock: None,
+            payload,
+        };
+        self.node_msg_id += 1;
+        Message { src, dest, body }
+    }
+    pub fn send(&mut self, mut msg: Message) -> anyhow::Result<()> {
+        self.clock.stamp(&mut msg);
+        self.dedup.record(&msg);
+        self.output.send(&msg)
+    }
+    pub fn write_message(
+        &mut self,
+        src: String,
+        dest: String,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+    ) -> anyhow::Result<()> {
+        let msg = self.create_message(src, dest, in_reply_to, payload);
+        self.send(msg)
+    }
+    pub fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        self.step_at(input, Instant::now())
+    }
+    pub fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        match input {
+            Event::EOF => {}
+            Event::Message(input) => match input.body.payload {
+                _ if input
+                    .body
+                    .in_reply_to
+                    .is_some_and(|id| self.pending.contains_key(&id)) =>
+                {
+                    self.finish_txn(input)?;
+                }
+                Payload::Txn { txn } if self.consistency == Consistency::SnapshotIsolation => {
+                    self.snapshot_txn(input.src, input.body.msg_id, txn, now)?;
+                }
+                Payload::Txn { txn } => {
+                    let node_id = self.node_id.clone().unwrap();
+                    let (txn, units) = self.store.execute(txn, &node_id, self.consistency);
+                    for (version, writes) in units {
+                        self.unreplicated.push_back(ReplicatedWrites {
+                            seq: self.next_seq,
+                            version,
+                            writes,
+                        });
+                        self.next_seq += 1;
+                    }
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::TxnOk { txn },
+                    )?;
+                }
+                Payload::TxnOk { .. } => bail!("didn't expect TxnOk"),
+                Payload::CommitTxn { start_ts, writes } => {
+                    if self.node_id.as_ref() != Some(&self.certifier) {
+                        bail!(
+                            "only {} certifies commits, got one from {}",
+                            self.certifier,
+                            input.src
+                        );
+                    }
+                    let payload = match self.certify(start_ts, writes) {
+                        Ok((commit_ts, seq)) => Payload::CommitTxnOk { commit_ts, seq },
+                        Err(key) => conflict(key),
+                    };
+                    self.write_message(input.dest, input.src, input.body.msg_id, payload)?;
+                }
+                Payload::CommitTxnOk { .. } => debug!("late commit_txn_ok: {:?}", input),
+                Payload::ReplicateWrites { writes } => {
+                    // Units arrive as a run from the last one we acknowledged,
+                    // possibly more than once, so only apply the next ones
+                    let applied = self.applied.get(&input.src).copied().unwrap_or_default();
+                    let mut next = applied;
+                    for unit in writes.into_iter().filter(|w| w.seq > applied) {
+                        if unit.seq != next + 1 {
+                            break;
+                        }
+                        next = unit.seq;
+                        self.apply_unit(&unit);
+                    }
+                    self.applied.insert(input.src.clone(), next);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::ReplicateWritesOk { seq: next },
+                    )?;
+                }
+                Payload::ReplicateWritesOk { seq } => {
+                    let acked = self.acked.entry(input.src).or_default();
+                    *acked = (*acked).max(seq);
+                    // Everyone has the oldest writes now
+                    let everyone = self.acked.values().min().cloned().unwrap_or(usize::MAX);
+                    while self.unreplicated.front().is_some_and(|w| w.seq <= everyone) {
+                        self.unreplicated.pop_front();
+                    }
+                }
+                _ => {
+                    if input.body.msg_id.is_none() {
+                        bail!("Received unexpected msg for TxnNode: {:?}", input)
+                    }
+                    let text = format!("TxnNode doesn't support {:?}", input.body.payload);
+                    self.write_message(
+                        input.dest,
+                        input.src,
+                        input.body.msg_id,
+                        Payload::Error {
+                            code: error_code::NOT_SUPPORTED,
+                            text,
+                        },
+                    )?;
+                }
+            },
+            Event::Injected(Injected::GossipNow) => {
+                self.replicate()?;
+                if self.consistency == Consistency::SnapshotIsolation {
+                    self.expire_pending(now)?;
+                    if now.saturating_duration_since(self.last_gc) >= GC_INTERVAL {
+                        self.last_gc = now;
+                        // No snapshot older than this can be taken any more
+                        let oldest = self
+                            .pending
+                            .values()
+                            .map(|p| p.start_ts)
+                            .min()
+                            .unwrap_or(self.watermark)
+                            .min(self.watermark);
+                        let removed = self.mvcc.gc(oldest);
+                        debug!("gc removed {} versions below {}", removed, oldest);
+                    }
+                }
+            }
+            Event::Injected(_) => {}
+        }
+
+        Ok(())
+    }
+
+    // Start timestamp for a new snapshot. The certifier has applied every
+    // commit so it can read as of now; anywhere else commits after the
+    // watermark may still be on their way, so the snapshot stops there.
+    fn snapshot_ts(&mut self) -> u64 {
+        if self.node_id.as_ref() == Some(&self.certifier) {
+            self.hlc.now()
+        } else {
+            self.watermark
+        }
+    }
+
+    fn snapshot_txn(
+        &mut self,
+        client: String,
+        client_msg_id: Option<usize>,
+        ops: Vec<MicroOp>,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let node_id = self.node_id.clone().unwrap();
+        let start_ts = self.snapshot_ts();
+        let (txn, writes) = self.mvcc.execute(ops, start_ts);
+        // Read-only transactions can't conflict with anything
+        if writes.is_empty() {
+            return self.write_message(node_id, client, client_msg_id, Payload::TxnOk { txn });
+        }
+        if node_id == self.certifier {
+            let payload = match self.certify(start_ts, writes) {
+                Ok(_) => Payload::TxnOk { txn },
+                Err(key) => conflict(key),
+            };
+            return self.write_message(node_id, client, client_msg_id, payload);
+        }
+        let msg = self.create_message(
+            node_id,
+            self.certifier.clone(),
+            None,
+            Payload::CommitTxn {
+                start_ts,
+                writes: writes.clone(),
+            },
+        );
+        if let Some(msg_id) = msg.body.msg_id {
+            self.pending.insert(
+                msg_id,
+                PendingTxn {
+                    client,
+                    client_msg_id,
+                    txn,
+                    writes,
+                    start_ts,
+                    sent: now,
+                },
+            );
+        }
+        self.send(msg)
+    }
+
+    // First committer wins: writes commit unless one of their keys has been
+    // committed since start_ts, in which case that key is the error. A
+    // commit gives its timestamp and its seq in the stream of writes.
+    fn certify(
+        &mut self,
+        start_ts: u64,
+        writes: Vec<(usize, usize)>,
+    ) -> Result<(u64, usize), usize> {
+        if let Some(key) = self.mvcc.conflict(&writes, start_ts) {
+            debug!("conflict on {} for snapshot at {}", key, start_ts);
+            return Err(key);
+        }
+        let commit_ts = self.hlc.update(start_ts);
+        for (key, value) in writes.iter() {
+            self.mvcc.apply(*key, *value, commit_ts);
+        }
+        self.watermark = commit_ts;
+        self.unreplicated.push_back(ReplicatedWrites {
+            seq: self.next_seq,
+            version: Version {
+                counter: commit_ts,
+                node: self.certifier.clone(),
+            },
+            writes,
+        });
+        self.next_seq += 1;
+        Ok((commit_ts, self.next_seq - 1))
+    }
+
+    // Apply a unit of writes made on another node
+    fn apply_unit(&mut self, unit: &ReplicatedWrites) {
+        for (key, value) in unit.writes.iter() {
+            match self.consistency {
+                Consistency::SnapshotIsolation => {
+                    self.mvcc.apply(*key, *value, unit.version.counter)
+                }
+                _ => self.store.apply(*key, *value, &unit.version),
+            }
+        }
+        if self.consistency == Consistency::SnapshotIsolation {
+            self.watermark = unit.version.counter;
+            self.hlc.update(unit.version.counter);
+        }
+    }
+
+    // Tell the client how the certifier decided its transaction
+    fn finish_txn(&mut self, input: Message) -> anyhow::Result<()> {
+        let Some(pending) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.pending.remove(&id))
+        else {
+            bail!("no pending transaction for {:?}", input);
+        };
+        let payload = match input.body.payload {
+            Payload::CommitTxnOk { commit_ts, seq } => {
+                // When it's the next of the certifier's writes it can be
+                // applied now, so the client's next transaction here reads
+                // it rather than conflicting with it. Otherwise it comes
+                // with the rest once they're replicated.
+                let certifier = self.certifier.clone();
+                if self.applied.get(&certifier).copied().unwrap_or_default() + 1 == seq {
+                    self.apply_unit(&ReplicatedWrites {
+                        seq,
+                        version: Version {
+                            counter: commit_ts,
+                            node: certifier.clone(),
+                        },
+                        writes: pending.writes,
+                    });
+                    self.applied.insert(certifier, seq);
+                }
+                Payload::TxnOk { txn: pending.txn }
+            }
+            error @ Payload::Error { .. } => error,
+            other => bail!("unexpected reply to commit_txn: {:?}", other),
+        };
+        self.write_message(input.dest, pending.client, pending.client_msg_id, payload)
+    }
+
+    // Give up on transactions the certifier hasn't answered. They may still
+    // have committed, so the client gets an indefinite error.
+    fn expire_pending(&mut self, now: Instant) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.sent) >= COMMIT_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let pending = self.pending.remove(&id).expect("expired transaction");
+            warn!("no commit decision from {} for {}", self.certifier, id);
+            self.write_message(
+                self.node_id.clone().unwrap(),
+                pending.client,
+                pending.client_msg_id,
+                Payload::Error {
+                    code: error_code::TIMEOUT,
+                    text: format!("no commit decision from {}", self.certifier),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // Send every peer the writes it hasn't acknowledged. Unacknowledged ones
+    // go again on the next tick, and applying them twice is harmless.
+    fn replicate(&mut self) -> anyhow::Result<()> {
+        let peers: Vec<(String, usize)> = self.acked.iter().map(|(p, s)| (p.clone(), *s)).collect();
+        for (peer, acked) in peers {
+            let writes: Vec<ReplicatedWrites> = self
+                .unreplicated
+                .iter()
+                .filter(|w| w.seq > acked)
+                .take(REPLICATION_BATCH)
+                .cloned()
+                .collect();
+            if writes.is_empty() {
+                continue;
+            }
+            debug!("replicating {} units of writes to {}", writes.len(), peer);
+            let msg = self.create_message(
+                self.node_id.clone().unwrap(),
+                peer,
+                None,
+                Payload::ReplicateWrites { writes },
+            );
+            self.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+fn conflict(key: usize) -> Payload {
+    Payload::Error {
+        code: error_code::TXN_CONFLICT,
+        text: format!("key {} was written after this transaction's snapshot", key),
+    }
+}
+
+impl Node for TxnNode {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self> {
+        TxnNode::new(init_msg, output)
+    }
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()> {
+        TxnNode::step(self, input)
+    }
+    fn step_at(&mut self, input: Event<Message, Injected>, now: Instant) -> anyhow::Result<()> {
+        TxnNode::step_at(self, input, now)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        TxnNode::send(self, msg)
+    }
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        Some(&mut self.dedup)
+    }
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        Some(&mut self.clock)
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[test]
+fn a_non_certifier_forwards_commits_and_relays_conflicts() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+    use crate::txn::OpKind;
+
+    let mut net = HarnessNet::<TxnNode>::new(&["n0", "n1"])?;
+    for id in ["n0", "n1"] {
+        net.node(id)
+            .node()
+            .set_consistency(Consistency::SnapshotIsolation);
+    }
+    let write = |value| Payload::Txn {
+        txn: vec![MicroOp(OpKind::Write, 1, Some(value))],
+    };
+    let read = || Payload::Txn {
+        txn: vec![MicroOp(OpKind::Read, 1, None)],
+    };
+    let reads = |value| vec![MicroOp(OpKind::Read, 1, Some(value))];
+    // n0 certifies, so n1 sends it the commits. Each is applied on n1 as
+    // soon as it's certified, so the second write doesn't conflict with the
+    // first, and n1 reads its own writes.
+    for value in [10, 20] {
+        assert!(matches!(
+            net.call("n1", write(value))?,
+            Payload::TxnOk { .. }
+        ));
+    }
+    assert!(matches!(net.call("n1", read())?, Payload::TxnOk { txn } if txn == reads(20)));
+    assert!(matches!(net.call("n0", read())?, Payload::TxnOk { txn } if txn == reads(20)));
+    // A write on n0 isn't replicated to n1 until the next tick, so this
+    // snapshot is from before it
+    assert!(matches!(net.call("n0", write(30))?, Payload::TxnOk { .. }));
+    assert!(matches!(
+        net.call("n1", write(40))?,
+        Payload::Error {
+            code: error_code::TXN_CONFLICT,
+            ..
+        }
+    ));
+    net.tick()?;
+    assert!(matches!(net.call("n1", read())?, Payload::TxnOk { txn } if txn == reads(30)));
+    Ok(())
+}
+
+#[test]
+fn a_commit_the_certifier_never_answers_times_out() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+    use crate::txn::OpKind;
+
+    let mut net = HarnessNet::<TxnNode>::new(&["n0", "n1"])?;
+    for id in ["n0", "n1"] {
+        net.node(id)
+            .node()
+            .set_consistency(Consistency::SnapshotIsolation);
+    }
+    net.partition(&["n0"]);
+    let write = Payload::Txn {
+        txn: vec![MicroOp(OpKind::Write, 1, Some(10))],
+    };
+    let msg_id = net.request("n1", write);
+    net.deliver()?;
+    net.tick()?;
+    assert!(net.reply(msg_id).is_none());
+
+    net.advance(COMMIT_TIMEOUT);
+    net.tick()?;
+    assert!(matches!(
+        net.reply(msg_id),
+        Some(Payload::Error {
+            code: error_code::TIMEOUT,
+            ..
+        })
+    ));
+    assert!(net.node("n1").node().pending.is_empty());
+    Ok(())
+}
+
+#[test]
+fn replicated_writes_apply_only_in_sequence() -> anyhow::Result<()> {
+    use crate::harness::Harness;
+    use crate::txn::OpKind;
+
+    let mut h = Harness::<TxnNode>::new("n1", &["n0", "n1"])?;
+    h.node().set_consistency(Consistency::SnapshotIsolation);
+    let unit = |seq, counter, value| ReplicatedWrites {
+        seq,
+        version: Version {
+            counter,
+            node: "n0".to_string(),
+        },
+        writes: vec![(1, value)],
+    };
+    let replicate = |h: &mut Harness<TxnNode>, writes| -> anyhow::Result<usize> {
+        let sent = h.message("n0", Payload::ReplicateWrites { writes })?;
+        match sent.as_slice() {
+            [Message {
+                body:
+                    Body {
+                        payload: Payload::ReplicateWritesOk { seq },
+                        ..
+                    },
+                ..
+            }] => Ok(*seq),
+            other => bail!("expected replicate_writes_ok, got {:?}", other),
+        }
+    };
+    let read = |h: &mut Harness<TxnNode>| -> anyhow::Result<Option<usize>> {
+        let read = Payload::Txn {
+            txn: vec![MicroOp(OpKind::Read, 1, None)],
+        };
+        match h.call(read)? {
+            Payload::TxnOk { txn } => Ok(txn[0].2),
+            other => bail!("expected txn_ok, got {:?}", other),
+        }
+    };
+
+    // Seq 2 before 1 waits for it
+    assert_eq!(replicate(&mut h, vec![unit(2, 6, 20)])?, 0);
+    assert_eq!(read(&mut h)?, None);
+    assert_eq!(replicate(&mut h, vec![unit(1, 5, 10), unit(2, 6, 20)])?, 2);
+    assert_eq!(read(&mut h)?, Some(20));
+    // Sent again, they're acknowledged without winding the snapshot back
+    assert_eq!(replicate(&mut h, vec![unit(1, 5, 10), unit(2, 6, 20)])?, 2);
+    assert_eq!(read(&mut h)?, Some(20));
+    Ok(())
+}
+
+#[test]
+fn read_committed_levels_replicate_writes_to_peers() -> anyhow::Result<()> {
+    use crate::harness::HarnessNet;
+    use crate::txn::OpKind;
+
+    // Read uncommitted sends every write on its own, read committed only
+    // the final value of each key, together
+    for (consistency, units) in [
+        (
+            Consistency::ReadUncommitted,
+            vec![vec![(1, 1)], vec![(1, 2)], vec![(2, 3)]],
+        ),
+        (Consistency::ReadCommitted, vec![vec![(1, 2), (2, 3)]]),
+    ] {
+        let mut net = HarnessNet::<TxnNode>::new(&["n0", "n1"])?;
+        for id in ["n0", "n1"] {
+            net.node(id).node().set_consistency(consistency);
+        }
+        let txn = Payload::Txn {
+            txn: vec![
+                MicroOp(OpKind::Write, 1, Some(1)),
+                MicroOp(OpKind::Write, 1, Some(2)),
+                MicroOp(OpKind::Write, 2, Some(3)),
+                MicroOp(OpKind::Read, 1, None),
+            ],
+        };
+        assert!(matches!(
+            net.call("n0", txn)?,
+            Payload::TxnOk { txn } if txn[3] == MicroOp(OpKind::Read, 1, Some(2))
+        ));
+
+        let sent = net.node("n0").inject(Injected::GossipNow)?;
+        let [Message {
+            body:
+                Body {
+                    payload: Payload::ReplicateWrites { writes },
+                    ..
+                },
+            ..
+        }] = sent.as_slice()
+        else {
+            bail!("expected replicate_writes, got {:?}", sent);
+        };
+        let sent_units: Vec<Vec<(usize, usize)>> =
+            writes.iter().map(|w| w.writes.clone()).collect();
+        assert_eq!(sent_units, units, "{:?}", consistency);
+
+        for msg in sent {
+            net.send(msg);
+        }
+        net.deliver()?;
+        let read = Payload::Txn {
+            txn: vec![
+                MicroOp(OpKind::Read, 1, None),
+                MicroOp(OpKind::Read, 2, None),
+            ],
+        };
+        assert!(matches!(
+            net.call("n1", read)?,
+            Payload::TxnOk { txn } if txn == [
+                MicroOp(OpKind::Read, 1, Some(2)),
+                MicroOp(OpKind::Read, 2, Some(3)),
+            ]
+        ));
+    }
+    Ok(())
+}
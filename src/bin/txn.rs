@@ -0,0 +1,7 @@
+use fly::runtime;
+use fly::TxnNode::TxnNode;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    runtime::run::<TxnNode>()
+}
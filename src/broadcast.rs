@@ -0,0 +1,295 @@
+use crate::consensus::{Backend, Consensus};
+use crate::msg::{error_code, Payload};
+use anyhow::{bail, Context};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+pub const BROADCAST_ORDER_ENV: &str = "FLY_BROADCAST_ORDER";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BroadcastOrder {
+    // Messages are visible as soon as they arrive, in no particular order
+    Unordered,
+    // A message is only visible once everything visible where it was
+    // broadcast is visible here too
+    Causal,
+    // Every node delivers every message in the same order, the order the
+    // consensus backend from FLY_CONSENSUS commits them in
+    Total,
+}
+
+impl std::str::FromStr for BroadcastOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "unordered" => Ok(BroadcastOrder::Unordered),
+            "causal" => Ok(BroadcastOrder::Causal),
+            "total" => Ok(BroadcastOrder::Total),
+            _ => bail!("unknown broadcast order {:?}", s),
+        }
+    }
+}
+
+impl BroadcastOrder {
+    // Order from FLY_BROADCAST_ORDER, defaulting to unordered
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(BROADCAST_ORDER_ENV) {
+            Ok(s) => s.parse().context("parse FLY_BROADCAST_ORDER"),
+            Err(_) => Ok(BroadcastOrder::Unordered),
+        }
+    }
+}
+
+// A broadcast message with what it causally depends on, gossiped with it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CausalMessage {
+    pub message: usize,
+    // The node the client broadcast it to, with the incarnation of it that
+    // did, and how many messages that incarnation had broadcast including
+    // this one
+    pub origin: String,
+    pub seq: u64,
+    // Messages delivered at the origin when it was broadcast, as a count
+    // per origin node
+    pub deps: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CausalMetrics {
+    pub delivered: usize,
+    // Messages that had to wait for one they depend on
+    pub held_back: usize,
+    pub max_holdback: usize,
+}
+
+// Causal delivery: received messages wait in a holdback queue until every
+// message they depend on has been delivered
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct CausalBroadcast {
+    // Messages delivered from each origin node. They're delivered in seq
+    // order per origin, so a count is enough.
+    delivered: BTreeMap<String, u64>,
+    // Delivered messages, to gossip them on
+    messages: HashMap<usize, CausalMessage>,
+    // (origin, seq) -> message waiting on its dependencies
+    holdback: BTreeMap<(String, u64), CausalMessage>,
+    #[serde(skip)]
+    metrics: CausalMetrics,
+}
+
+impl CausalBroadcast {
+    // Broadcast a new message from a client, delivering it here at once
+    pub fn broadcast(&mut self, origin: &str, message: usize) -> &CausalMessage {
+        let deps = self.delivered.clone();
+        let seq = deps.get(origin).unwrap_or(&0) + 1;
+        self.deliver(CausalMessage {
+            message,
+            origin: origin.to_string(),
+            seq,
+            deps,
+        });
+        &self.messages[&message]
+    }
+    // Take a message from gossip, returning the messages it let us deliver,
+    // in the order they were delivered
+    pub fn receive(&mut self, msg: CausalMessage) -> Vec<usize> {
+        if self.is_delivered(&msg) {
+            return Vec::new();
+        }
+        if !self.is_deliverable(&msg) {
+            if let Entry::Vacant(entry) = self.holdback.entry((msg.origin.clone(), msg.seq)) {
+                debug!("holding back {:?}", msg);
+                entry.insert(msg);
+                self.metrics.held_back += 1;
+                self.metrics.max_holdback = self.metrics.max_holdback.max(self.holdback.len());
+            }
+            return Vec::new();
+        }
+        let mut delivered = vec![msg.message];
+        self.deliver(msg);
+        // Each delivery can free up others, so go round until none do
+        loop {
+            let ready: Vec<(String, u64)> = self
+                .holdback
+                .iter()
+                .filter(|(_, m)| self.is_deliverable(m))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if ready.is_empty() {
+                break;
+            }
+            // Deliveries only ever add to what's delivered, so these all
+            // stay deliverable
+            for key in ready {
+                let msg = self.holdback.remove(&key).expect("held back message");
+                delivered.push(msg.message);
+                self.deliver(msg);
+            }
+        }
+        delivered
+    }
+    pub fn message(&self, message: usize) -> Option<&CausalMessage> {
+        self.messages.get(&message)
+    }
+    pub fn holdback_len(&self) -> usize {
+        self.holdback.len()
+    }
+    pub fn metrics(&self) -> &CausalMetrics {
+        &self.metrics
+    }
+
+    fn is_delivered(&self, msg: &CausalMessage) -> bool {
+        self.delivered.get(&msg.origin).unwrap_or(&0) >= &msg.seq
+    }
+    // The next message from its origin, with everything it depends on
+    // from other origins delivered
+    fn is_deliverable(&self, msg: &CausalMessage) -> bool {
+        self.delivered.get(&msg.origin).unwrap_or(&0) + 1 == msg.seq
+            && msg
+                .deps
+                .iter()
+                .filter(|(node, _)| **node != msg.origin)
+                .all(|(node, count)| self.delivered.get(node).unwrap_or(&0) >= count)
+    }
+    fn deliver(&mut self, msg: CausalMessage) {
+        self.delivered.insert(msg.origin.clone(), msg.seq);
+        self.messages.insert(msg.message, msg);
+        self.metrics.delivered += 1;
+    }
+}
+
+// Atomic broadcast on top of a replicated log: messages are proposed to
+// the leader and delivered in the order they commit
+pub struct TotalOrder {
+    consensus: Box<dyn Consensus>,
+    // The consensus clock starts at zero when the node does
+    start: Instant,
+    delivered: Vec<usize>,
+    seen: HashSet<usize>,
+    // Log position -> (tag it was proposed with, client, client msg_id)
+    pending: HashMap<u64, (u64, String, Option<usize>)>,
+}
+
+impl TotalOrder {
+    pub fn new(backend: Backend, node_id: &str, node_ids: &[String]) -> Self {
+        TotalOrder {
+            consensus: backend.start(node_id, node_ids, rand::random(), 0),
+            start: Instant::now(),
+            delivered: Vec::new(),
+            seen: HashSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+    pub fn is_leader(&self) -> bool {
+        self.consensus.is_leader()
+    }
+    // Every message delivered so far, in delivery order
+    pub fn delivered(&self) -> &[usize] {
+        &self.delivered
+    }
+    // Propose a client's message, to reply once it's delivered. Without
+    // the lead, returns the leader to forward it to, if there is one.
+    pub fn propose(
+        &mut self,
+        message: usize,
+        client: String,
+        client_msg_id: Option<usize>,
+    ) -> Result<(), Option<String>> {
+        let (index, tag) = self.consensus.propose(serde_json::json!(message))?;
+        self.pending.insert(index, (tag, client, client_msg_id));
+        Ok(())
+    }
+    pub fn handle(&mut self, from: &str, payload: &Payload, now: Instant) -> bool {
+        let now_ms = self.now_ms(now);
+        self.consensus.handle(from, payload, now_ms)
+    }
+    pub fn tick(&mut self, now: Instant) {
+        let now_ms = self.now_ms(now);
+        self.consensus.tick(now_ms)
+    }
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        self.consensus.take_messages()
+    }
+    // Deliver whatever has committed, returning the replies due to clients
+    // whose messages were proposed here
+    pub fn deliver(&mut self) -> anyhow::Result<Vec<(String, Option<usize>, Payload)>> {
+        let mut replies = Vec::new();
+        for (index, tag, command) in self.consensus.take_committed() {
+            let message: usize =
+                serde_json::from_value(command).context("deserialize committed message")?;
+            // A retried broadcast can commit twice
+            if self.seen.insert(message) {
+                self.delivered.push(message);
+            }
+            let Some((proposed_tag, client, client_msg_id)) = self.pending.remove(&index) else {
+                continue;
+            };
+            let reply = if proposed_tag == tag {
+                Payload::BroadcastOk
+            } else {
+                warn!(
+                    "entry {} proposed with {} was replaced",
+                    index, proposed_tag
+                );
+                replaced()
+            };
+            replies.push((client, client_msg_id, reply));
+        }
+        // Positions committed as a new leader's no-ops don't come back from
+        // take_committed, but ours were replaced there all the same
+        let commit_index = self.consensus.commit_index();
+        let lost: Vec<u64> = self
+            .pending
+            .keys()
+            .filter(|index| **index <= commit_index)
+            .cloned()
+            .collect();
+        for index in lost {
+            let (_, client, client_msg_id) = self.pending.remove(&index).expect("lost entry");
+            warn!("entry {} was replaced by a no-op", index);
+            replies.push((client, client_msg_id, replaced()));
+        }
+        Ok(replies)
+    }
+
+    fn now_ms(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_millis() as u64
+    }
+}
+
+fn replaced() -> Payload {
+    Payload::Error {
+        code: error_code::TEMPORARILY_UNAVAILABLE,
+        text: "leadership changed before the message was ordered".to_string(),
+    }
+}
+
+#[test]
+fn causal_broadcast_holds_back_until_dependencies_arrive() {
+    let mut n0 = CausalBroadcast::default();
+    let mut n1 = CausalBroadcast::default();
+    let mut n2 = CausalBroadcast::default();
+    let a = n0.broadcast("n0", 1).clone();
+    let b = n0.broadcast("n0", 2).clone();
+    // n1 sees a, then broadcasts c in reply to it
+    assert_eq!(n1.receive(a.clone()), vec![1]);
+    let c = n1.broadcast("n1", 3).clone();
+
+    // n2 gets them backwards, and can't show c or b before a
+    assert!(n2.receive(c.clone()).is_empty());
+    assert!(n2.receive(b.clone()).is_empty());
+    assert_eq!(n2.holdback_len(), 2);
+    let mut delivered = n2.receive(a.clone());
+    assert_eq!(delivered[0], 1);
+    delivered[1..].sort();
+    assert_eq!(delivered, vec![1, 2, 3]);
+    assert_eq!(n2.holdback_len(), 0);
+    assert!(n2.receive(c).is_empty());
+    assert_eq!(n2.metrics().held_back, 2);
+    assert_eq!(n2.metrics().max_holdback, 2);
+    assert_eq!(n2.metrics().delivered, 3);
+}
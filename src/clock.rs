@@ -0,0 +1,243 @@
+use crate::ids::now_ms;
+use crate::msg::Message;
+use anyhow::{bail, Context};
+use log::trace;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+pub const CLOCK_ENV: &str = "FLY_CLOCK";
+
+// Bits of a timestamp given to the logical counter, below the milliseconds
+const LOGICAL_BITS: u64 = 16;
+
+// Hybrid logical clock. Timestamps are milliseconds of wall clock time
+// shifted up by LOGICAL_BITS with a logical counter in the low bits, so
+// they compare as plain u64s, stay close to real time, and still increase
+// when the wall clock stalls or goes backwards.
+#[derive(Default)]
+pub struct HybridClock {
+    last: u64,
+}
+
+impl HybridClock {
+    // A timestamp later than any this clock has issued or seen
+    pub fn now(&mut self) -> u64 {
+        self.tick(now_ms())
+    }
+    // Move past a timestamp from another node, returning a local timestamp
+    // later than both
+    pub fn update(&mut self, remote: u64) -> u64 {
+        self.last = self.last.max(remote);
+        self.now()
+    }
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+    fn tick(&mut self, wall_ms: u64) -> u64 {
+        self.last = (wall_ms << LOGICAL_BITS).max(self.last + 1);
+        self.last
+    }
+}
+
+// The wall clock milliseconds part of a timestamp
+pub fn physical_ms(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
+
+// Clock metadata carried in a message body, e.g. {"lamport": 12}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockStamp {
+    Lamport(u64),
+    // Events seen from each node, by node id
+    Vector(BTreeMap<String, u64>),
+    Hybrid(u64),
+}
+
+// Lamport and hybrid stamps are totally ordered, though a smaller one
+// doesn't mean its event happened before. Vector stamps are only ordered
+// when one event did happen before the other, and are None when they're
+// concurrent. Stamps of different kinds don't compare.
+impl PartialOrd for ClockStamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (ClockStamp::Lamport(a), ClockStamp::Lamport(b)) => Some(a.cmp(b)),
+            (ClockStamp::Hybrid(a), ClockStamp::Hybrid(b)) => Some(a.cmp(b)),
+            (ClockStamp::Vector(a), ClockStamp::Vector(b)) => {
+                let mut order = Ordering::Equal;
+                for node in a.keys().chain(b.keys()) {
+                    let (x, y) = (a.get(node).unwrap_or(&0), b.get(node).unwrap_or(&0));
+                    match (order, x.cmp(y)) {
+                        (_, Ordering::Equal) => {}
+                        (Ordering::Equal, o) => order = o,
+                        (o, p) if o != p => return None,
+                        _ => {}
+                    }
+                }
+                Some(order)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockKind {
+    None,
+    Lamport,
+    Vector,
+    Hybrid,
+}
+
+impl std::str::FromStr for ClockKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(ClockKind::None),
+            "lamport" => Ok(ClockKind::Lamport),
+            "vector" => Ok(ClockKind::Vector),
+            "hlc" => Ok(ClockKind::Hybrid),
+            _ => bail!("unknown clock {:?}", s),
+        }
+    }
+}
+
+// The clock a node stamps the messages it sends other nodes with. Sending
+// and receiving are both events: the runtime calls receive for every
+// message before the node steps, and the node calls stamp as it sends.
+pub struct MessageClock {
+    kind: ClockKind,
+    node_id: String,
+    node_ids: Vec<String>,
+    lamport: u64,
+    vector: BTreeMap<String, u64>,
+    hybrid: HybridClock,
+}
+
+impl MessageClock {
+    pub fn new(kind: ClockKind, node_id: &str, node_ids: &[String]) -> Self {
+        MessageClock {
+            kind,
+            node_id: node_id.to_string(),
+            node_ids: node_ids.to_vec(),
+            lamport: 0,
+            vector: BTreeMap::new(),
+            hybrid: HybridClock::default(),
+        }
+    }
+    // Clock from FLY_CLOCK, defaulting to none
+    pub fn from_env(node_id: &str, node_ids: &[String]) -> anyhow::Result<Self> {
+        let kind = match std::env::var(CLOCK_ENV) {
+            Ok(s) => s.parse().context("parse FLY_CLOCK")?,
+            Err(_) => ClockKind::None,
+        };
+        Ok(MessageClock::new(kind, node_id, node_ids))
+    }
+    // The clock's reading after the last event, None if it's off
+    pub fn current(&self) -> Option<ClockStamp> {
+        match self.kind {
+            ClockKind::None => None,
+            ClockKind::Lamport => Some(ClockStamp::Lamport(self.lamport)),
+            ClockKind::Vector => Some(ClockStamp::Vector(self.vector.clone())),
+            ClockKind::Hybrid => Some(ClockStamp::Hybrid(self.hybrid.last())),
+        }
+    }
+    // Merge the stamp on a received message, if it has one of ours
+    pub fn receive(&mut self, msg: &Message) {
+        if self.kind == ClockKind::None {
+            return;
+        }
+        match (self.kind, &msg.body.clock) {
+            (ClockKind::Lamport, Some(ClockStamp::Lamport(remote))) => {
+                self.lamport = self.lamport.max(*remote);
+            }
+            (ClockKind::Vector, Some(ClockStamp::Vector(remote))) => {
+                for (node, seen) in remote {
+                    let local = self.vector.entry(node.clone()).or_default();
+                    *local = (*local).max(*seen);
+                }
+            }
+            (ClockKind::Hybrid, Some(ClockStamp::Hybrid(remote))) => {
+                self.hybrid.update(*remote);
+            }
+            _ => {}
+        }
+        self.tick();
+        trace!("clock {:?} on receive from {}", self.current(), msg.src);
+    }
+    // Tick for a send, stamping the message if it's going to another node.
+    // Clients don't get a stamp.
+    pub fn stamp(&mut self, msg: &mut Message) {
+        if self.kind == ClockKind::None || !self.node_ids.contains(&msg.dest) {
+            return;
+        }
+        self.tick();
+        msg.body.clock = self.current();
+        trace!("clock {:?} on send to {}", msg.body.clock, msg.dest);
+    }
+    fn tick(&mut self) {
+        match self.kind {
+            ClockKind::None => {}
+            ClockKind::Lamport => self.lamport += 1,
+            ClockKind::Vector => *self.vector.entry(self.node_id.clone()).or_default() += 1,
+            ClockKind::Hybrid => {
+                self.hybrid.now();
+            }
+        }
+    }
+}
+
+#[test]
+fn hybrid_clock_is_monotonic() {
+    let mut clock = HybridClock::default();
+    let a = clock.tick(1_000);
+    // Same millisecond, then the wall clock going backwards
+    let b = clock.tick(1_000);
+    let c = clock.tick(900);
+    assert!(a < b && b < c);
+    assert_eq!(physical_ms(c), 1_000);
+    // A remote timestamp from the future pulls the clock forward
+    let remote = 5_000 << LOGICAL_BITS;
+    assert!(clock.update(remote) > remote);
+    assert!(clock.tick(1_001) > remote);
+}
+
+#[test]
+fn vector_clocks_track_causality() {
+    use crate::msg::{Body, Payload};
+
+    let ids: Vec<String> = vec!["n0".to_string(), "n1".to_string(), "n2".to_string()];
+    let mut clocks: Vec<MessageClock> = ids
+        .iter()
+        .map(|id| MessageClock::new(ClockKind::Vector, id, &ids))
+        .collect();
+    let gossip = |clocks: &mut Vec<MessageClock>, from: usize, to: usize| {
+        let mut msg = Message {
+            src: ids[from].clone(),
+            dest: ids[to].clone(),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                clock: None,
+                payload: Payload::GossipCount {
+                    adds: Vec::new(),
+                    updates: Vec::new(),
+                },
+            },
+        };
+        clocks[from].stamp(&mut msg);
+        clocks[to].receive(&msg);
+        msg.body.clock.unwrap()
+    };
+    let a = gossip(&mut clocks, 0, 1);
+    let b = gossip(&mut clocks, 1, 2);
+    let c = gossip(&mut clocks, 0, 2);
+    assert!(a < b);
+    assert!(a < c);
+    // n0's second send hadn't heard of n1's
+    assert_eq!(b.partial_cmp(&c), None);
+    let json = serde_json::to_value(&a).unwrap();
+    assert_eq!(json, serde_json::json!({"vector": {"n0": 1}}));
+}
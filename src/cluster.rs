@@ -0,0 +1,513 @@
+use crate::kafka_log::{KeyData, MessageLog};
+use crate::msg::Payload;
+use anyhow::Context;
+use log::{debug, info};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Spread Kafka keys over the nodes with dynamic membership
+pub const CLUSTER_ENV: &str = "FLY_CLUSTER";
+// How often unanswered cluster messages are sent again, in milliseconds
+pub const CLUSTER_RETRY_MS_ENV: &str = "FLY_CLUSTER_RETRY_MS";
+// The nodes that start out in the cluster, as n0,n1. Any other node starts
+// outside it until it joins. Every node starts in it if this isn't set.
+pub const CLUSTER_MEMBERS_ENV: &str = "FLY_CLUSTER_MEMBERS";
+
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    pub retry_ms: u64,
+    pub members: Option<Vec<String>>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            retry_ms: 200,
+            members: None,
+        }
+    }
+}
+
+impl ClusterConfig {
+    // Config if FLY_CLUSTER is set
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let enabled = std::env::var(CLUSTER_ENV).is_ok_and(|v| v == "1" || v == "true");
+        if !enabled {
+            return Ok(None);
+        }
+        let mut config = ClusterConfig::default();
+        if let Ok(ms) = std::env::var(CLUSTER_RETRY_MS_ENV) {
+            config.retry_ms = ms.parse().context("parse FLY_CLUSTER_RETRY_MS")?;
+        }
+        if let Ok(members) = std::env::var(CLUSTER_MEMBERS_ENV) {
+            config.members = Some(
+                members
+                    .split(',')
+                    .map(|m| m.trim().to_string())
+                    .filter(|m| !m.is_empty())
+                    .collect(),
+            );
+        }
+        Ok(Some(config))
+    }
+}
+
+// The nodes in the cluster as of an epoch. Every change of members starts
+// a new epoch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClusterView {
+    pub epoch: u64,
+    pub members: BTreeSet<String>,
+}
+
+impl ClusterView {
+    // The member that orders joins and leaves
+    pub fn coordinator(&self) -> Option<&String> {
+        self.members.first()
+    }
+    // The member key belongs to, by rendezvous hashing: each member scores
+    // the key and the highest wins, so a change of members only moves the
+    // keys the joining or leaving node wins or held
+    pub fn owner(&self, key: &str) -> Option<&String> {
+        self.members.iter().max_by_key(|m| score(m, key))
+    }
+}
+
+// FNV-1a of member and key, so every node scores them the same. FNV alone
+// barely mixes its last few bytes into the high bits, so short keys would
+// nearly all go to one member; the MurmurHash3 finalizer spreads them.
+fn score(member: &str, key: &str) -> u64 {
+    let h = member
+        .bytes()
+        .chain([0])
+        .chain(key.bytes())
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    let h = (h ^ (h >> 33)).wrapping_mul(0xff51afd7ed558ccd);
+    let h = (h ^ (h >> 33)).wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+// Dynamic membership for a keyed store. Joins and leaves go to the
+// coordinator, which only starts a new epoch once every member has settled
+// into the last one. On each new view every node that was or now is a
+// member hands the keys it no longer owns to their new owners, and tells
+// each member when it's done even if it had nothing for it. Until a member
+// has heard that from all of them it's not settled, and shouldn't serve
+// the keys it owns.
+pub struct Cluster {
+    node_id: String,
+    config: ClusterConfig,
+    view: ClusterView,
+    // Members of the view before this one
+    previous: BTreeSet<String>,
+    // Nodes we're still waiting on a transfer from for this epoch
+    awaiting: BTreeSet<String>,
+    // As coordinator, members that haven't settled into this epoch yet
+    unsettled: BTreeSet<String>,
+    // Transfers not acknowledged yet, by destination
+    transfers: BTreeMap<String, Payload>,
+    // Join or Leave for this node, sent until a view has it
+    request: Option<Payload>,
+    last_retry_ms: u64,
+    messages: Vec<(String, Payload)>,
+}
+
+impl Cluster {
+    // A node starting out with members as the view. If node_id isn't one
+    // of them it's outside the cluster until it joins.
+    pub fn new(node_id: &str, members: &[String], config: ClusterConfig) -> Self {
+        Cluster {
+            node_id: node_id.to_string(),
+            config,
+            view: ClusterView {
+                epoch: 0,
+                members: members.iter().cloned().collect(),
+            },
+            previous: BTreeSet::new(),
+            awaiting: BTreeSet::new(),
+            unsettled: BTreeSet::new(),
+            transfers: BTreeMap::new(),
+            request: None,
+            last_retry_ms: 0,
+            messages: Vec::new(),
+        }
+    }
+    // A node starting out with the members from config, or with every node
+    // if it doesn't name them
+    pub fn from_config(node_id: &str, node_ids: &[String], config: ClusterConfig) -> Self {
+        let members = config.members.clone().unwrap_or_else(|| node_ids.to_vec());
+        Cluster::new(node_id, &members, config)
+    }
+    pub fn view(&self) -> &ClusterView {
+        &self.view
+    }
+    pub fn is_member(&self) -> bool {
+        self.view.members.contains(&self.node_id)
+    }
+    // Whether every transfer to this node for the current view has arrived,
+    // so the keys it owns are complete
+    pub fn is_settled(&self) -> bool {
+        self.awaiting.is_empty()
+    }
+    pub fn owner(&self, key: &str) -> Option<&String> {
+        self.view.owner(key)
+    }
+    // Ask to be added to the cluster, through the coordinator of the view
+    // this node knows about. The request goes out on the next tick.
+    pub fn join(&mut self) {
+        if self.is_member() {
+            return;
+        }
+        self.request = Some(Payload::Join {
+            node: self.node_id.clone(),
+        });
+        self.last_retry_ms = 0;
+    }
+    // Ask to be removed, handing every key on to the remaining members
+    pub fn leave(&mut self) {
+        if !self.is_member() {
+            return;
+        }
+        self.request = Some(Payload::Leave {
+            node: self.node_id.clone(),
+        });
+        self.last_retry_ms = 0;
+    }
+    // Whether this node has asked to join or leave and isn't done yet
+    pub fn is_changing(&self) -> bool {
+        self.request.is_some()
+    }
+    pub fn tick(&mut self, now_ms: u64, log: &mut dyn MessageLog) -> anyhow::Result<()> {
+        if now_ms >= self.last_retry_ms + self.config.retry_ms {
+            self.retry(now_ms, log)?;
+        }
+        Ok(())
+    }
+    // Handle a cluster message, returning false if payload isn't one
+    pub fn handle(
+        &mut self,
+        from: &str,
+        payload: &Payload,
+        log: &mut dyn MessageLog,
+    ) -> anyhow::Result<bool> {
+        match payload {
+            Payload::Join { node } => self.change(node, true, log)?,
+            Payload::Leave { node } => self.change(node, false, log)?,
+            Payload::ViewChange {
+                epoch,
+                members,
+                previous,
+            } => {
+                if *epoch > self.view.epoch {
+                    let view = ClusterView {
+                        epoch: *epoch,
+                        members: members.iter().cloned().collect(),
+                    };
+                    self.adopt(view, previous.iter().cloned().collect(), log)?;
+                } else if *epoch == self.view.epoch && self.is_member() && self.is_settled() {
+                    // Our ack went missing
+                    self.settled();
+                }
+            }
+            Payload::ViewChangeOk { epoch } => {
+                if *epoch == self.view.epoch {
+                    self.unsettled.remove(from);
+                }
+            }
+            Payload::TransferKeys { epoch, keys } => {
+                // Without the view yet we can't tell what's ours, so let the
+                // sender try again
+                if *epoch > self.view.epoch {
+                    return Ok(true);
+                }
+                if *epoch == self.view.epoch && self.awaiting.remove(from) {
+                    debug!("{} keys from {} for epoch {}", keys.len(), from, epoch);
+                    for data in keys {
+                        log.import(data.clone())
+                            .with_context(|| format!("import {:?} from {}", data.key, from))?;
+                    }
+                    if self.is_settled() {
+                        self.settled();
+                    }
+                }
+                self.messages
+                    .push((from.to_string(), Payload::TransferKeysOk { epoch: *epoch }));
+            }
+            Payload::TransferKeysOk { epoch } => {
+                if let Some(Payload::TransferKeys { epoch: sent, .. }) = self.transfers.get(from) {
+                    if sent == epoch {
+                        self.transfers.remove(from);
+                    }
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    // As coordinator, start an epoch with node added or removed. Requests
+    // that come while the last change is still settling are dropped, and
+    // sent again by the node that asked.
+    fn change(&mut self, node: &str, join: bool, log: &mut dyn MessageLog) -> anyhow::Result<()> {
+        let Some(coordinator) = self.view.coordinator().cloned() else {
+            return Ok(());
+        };
+        if coordinator != self.node_id {
+            let payload = match join {
+                true => Payload::Join {
+                    node: node.to_string(),
+                },
+                false => Payload::Leave {
+                    node: node.to_string(),
+                },
+            };
+            self.messages.push((coordinator, payload));
+            return Ok(());
+        }
+        if !self.unsettled.is_empty() || !self.is_settled() {
+            debug!("not changing the view for {} until it settles", node);
+            return Ok(());
+        }
+        let mut members = self.view.members.clone();
+        let changed = match join {
+            true => members.insert(node.to_string()),
+            false => members.remove(node),
+        };
+        if !changed {
+            // Already done, the node just didn't hear
+            self.messages.push((node.to_string(), self.view_change()));
+            return Ok(());
+        }
+        let view = ClusterView {
+            epoch: self.view.epoch + 1,
+            members,
+        };
+        // The view goes out ahead of the keys handed off in adopt, so a
+        // joining node knows the epoch by the time they reach it
+        let previous = self.view.members.clone();
+        let payload = Payload::ViewChange {
+            epoch: view.epoch,
+            members: view.members.iter().cloned().collect(),
+            previous: previous.iter().cloned().collect(),
+        };
+        for node in previous.union(&view.members) {
+            if *node != self.node_id {
+                self.messages.push((node.clone(), payload.clone()));
+            }
+        }
+        self.adopt(view, previous, log)
+    }
+    // Move to view, handing off every key held here that another member
+    // now owns
+    fn adopt(
+        &mut self,
+        view: ClusterView,
+        previous: BTreeSet<String>,
+        log: &mut dyn MessageLog,
+    ) -> anyhow::Result<()> {
+        info!("epoch {} has members {:?}", view.epoch, view.members);
+        self.view = view;
+        self.previous = previous;
+        self.awaiting.clear();
+        self.unsettled.clear();
+        self.transfers.clear();
+        let member = self.is_member();
+        let satisfied = match self.request {
+            Some(Payload::Join { .. }) => member,
+            Some(Payload::Leave { .. }) => !member,
+            _ => false,
+        };
+        if satisfied {
+            self.request = None;
+        }
+        if !member && !self.previous.contains(&self.node_id) {
+            return Ok(());
+        }
+
+        let mut outgoing: BTreeMap<String, Vec<KeyData>> = self
+            .view
+            .members
+            .iter()
+            .filter(|m| **m != self.node_id)
+            .map(|m| (m.clone(), Vec::new()))
+            .collect();
+        for key in log.keys() {
+            let Some(owner) = self.view.owner(&key).cloned() else {
+                continue;
+            };
+            if owner == self.node_id {
+                continue;
+            }
+            let data = log
+                .export(&key)
+                .with_context(|| format!("export {:?}", key))?;
+            log.remove(&key)
+                .with_context(|| format!("remove {:?} after export", key))?;
+            outgoing.entry(owner).or_default().push(data);
+        }
+        for (node, keys) in outgoing {
+            debug!("handing {} keys to {}", keys.len(), node);
+            let payload = Payload::TransferKeys {
+                epoch: self.view.epoch,
+                keys,
+            };
+            self.messages.push((node.clone(), payload.clone()));
+            self.transfers.insert(node, payload);
+        }
+
+        if member {
+            self.awaiting = self
+                .previous
+                .union(&self.view.members)
+                .filter(|n| **n != self.node_id)
+                .cloned()
+                .collect();
+            if self.view.coordinator() == Some(&self.node_id) {
+                self.unsettled = self.view.members.clone();
+            }
+            if self.is_settled() {
+                self.settled();
+            }
+        }
+        Ok(())
+    }
+    // Tell the coordinator this node has all of its keys for the epoch
+    fn settled(&mut self) {
+        info!("settled into epoch {}", self.view.epoch);
+        match self.view.coordinator().cloned() {
+            Some(c) if c == self.node_id => {
+                self.unsettled.remove(&c);
+            }
+            Some(c) => {
+                let payload = Payload::ViewChangeOk {
+                    epoch: self.view.epoch,
+                };
+                self.messages.push((c, payload));
+            }
+            None => {}
+        }
+    }
+    fn view_change(&self) -> Payload {
+        Payload::ViewChange {
+            epoch: self.view.epoch,
+            members: self.view.members.iter().cloned().collect(),
+            previous: self.previous.iter().cloned().collect(),
+        }
+    }
+    // Send again whatever hasn't been answered
+    fn retry(&mut self, now_ms: u64, log: &mut dyn MessageLog) -> anyhow::Result<()> {
+        self.last_retry_ms = now_ms;
+        // The view goes with each transfer, as the transfer is no use to a
+        // node that hasn't heard of the epoch
+        let view_change = self.view_change();
+        for (node, payload) in self.transfers.iter() {
+            self.messages.push((node.clone(), view_change.clone()));
+            self.messages.push((node.clone(), payload.clone()));
+        }
+        for node in self.unsettled.iter() {
+            if *node != self.node_id && !self.transfers.contains_key(node) {
+                self.messages.push((node.clone(), view_change.clone()));
+            }
+        }
+        if let (Some(request), Some(coordinator)) = (&self.request, self.view.coordinator()) {
+            let request = request.clone();
+            if *coordinator == self.node_id {
+                // Leaving as the coordinator
+                self.handle(&self.node_id.clone(), &request, log)?;
+            } else {
+                self.messages.push((coordinator.clone(), request));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn a_node_spawned_mid_run_joins_and_takes_its_keys() {
+    use crate::kafka_log::MemoryLog;
+    use crate::sim::{Process, Sim};
+
+    struct Store {
+        cluster: Cluster,
+        log: MemoryLog,
+    }
+    impl Process for Store {
+        fn handle(&mut self, from: &str, payload: Payload, _now_ms: u64) {
+            let handled = self.cluster.handle(from, &payload, &mut self.log);
+            assert!(handled.expect("handle cluster message"));
+        }
+        fn tick(&mut self, now_ms: u64) {
+            self.cluster.tick(now_ms, &mut self.log).expect("tick");
+        }
+        fn take_messages(&mut self) -> Vec<(String, Payload)> {
+            self.cluster.take_messages()
+        }
+    }
+
+    let ids: Vec<String> = (0..3).map(|i| format!("n{}", i)).collect();
+    let store = |id: &str| Store {
+        cluster: Cluster::new(id, &ids, ClusterConfig::default()),
+        log: MemoryLog::default(),
+    };
+    let mut sim = Sim::new(ids.iter().map(|id| (id.clone(), store(id))), 5);
+    let keys: Vec<String> = (0..40).map(|i| format!("k{}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+        let owner = sim.node("n0").cluster.owner(key).unwrap().clone();
+        let log = &mut sim.node_mut(&owner).log;
+        log.append(key, i, None).unwrap();
+        log.append(key, i + 100, None).unwrap();
+        log.commit("g", key, 1).unwrap();
+    }
+    // Every key is with its owner and nowhere else, with nothing lost
+    let placed = |s: &Sim<Store>| {
+        keys.iter().enumerate().all(|(i, key)| {
+            s.nodes().all(|(id, store)| {
+                let held = store.log.read(key, 0).unwrap();
+                match store.cluster.owner(key) == Some(id) {
+                    true => {
+                        held == [(1, i), (2, i + 100)] && store.log.committed("g", key) == Some(1)
+                    }
+                    false => held.is_empty(),
+                }
+            })
+        })
+    };
+    let settled = |s: &Sim<Store>, epoch: u64| {
+        s.nodes().all(|(_, store)| {
+            store.cluster.view().epoch == epoch
+                && store.cluster.is_settled()
+                && !store.cluster.is_changing()
+        })
+    };
+    sim.run_for(100);
+
+    let mut joiner = store("n3");
+    assert!(!joiner.cluster.is_member());
+    joiner.cluster.join();
+    sim.add_node("n3", joiner);
+    // The coordinator doesn't hear the first few join attempts
+    sim.partition(&["n0"]);
+    sim.run_for(500);
+    assert!(!sim.node("n3").cluster.is_member());
+    sim.heal();
+    assert!(sim.run_until(5_000, |s| settled(s, 1)));
+    assert!(sim.node("n3").cluster.is_member());
+    assert!(placed(&sim));
+    let moved = keys
+        .iter()
+        .filter(|k| sim.node("n3").cluster.owner(k).unwrap() == "n3")
+        .count();
+    assert!(moved > 0);
+    assert_eq!(sim.node("n3").log.keys().len(), moved);
+
+    // The coordinator itself leaves, and hands everything on
+    sim.node_mut("n0").cluster.leave();
+    assert!(sim.run_until(5_000, |s| settled(s, 2)));
+    assert!(!sim.node("n0").cluster.is_member());
+    assert!(sim.node("n0").log.keys().is_empty());
+    assert!(placed(&sim));
+}
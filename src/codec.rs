@@ -0,0 +1,297 @@
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::BufRead;
+
+// Wire format for the socket transports: json, msgpack or cbor. Maelstrom
+// only speaks JSON, so stdio ignores it.
+pub const CODEC_ENV: &str = "FLY_CODEC";
+
+// Largest length-prefixed frame that's read, so a corrupt length can't
+// make us allocate gigabytes
+const MAX_FRAME: usize = 64 << 20;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    // One JSON object per line, as Maelstrom sends them
+    #[default]
+    Json,
+    // MessagePack with field names, after a 4 byte big-endian length
+    MessagePack,
+    // CBOR, after a 4 byte big-endian length
+    Cbor,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "msgpack" => Ok(Codec::MessagePack),
+            "cbor" => Ok(Codec::Cbor),
+            _ => bail!("unknown codec {:?}", s),
+        }
+    }
+}
+
+impl Codec {
+    // Codec from FLY_CODEC, defaulting to JSON
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(CODEC_ENV) {
+            Ok(s) => s.parse().context("parse FLY_CODEC"),
+            Err(_) => Ok(Codec::Json),
+        }
+    }
+    // Append value to buf as one frame
+    pub fn encode<T: Serialize>(self, value: &T, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let body = match self {
+            Codec::Json => {
+                serde_json::to_writer(&mut *buf, value).context("encode json")?;
+                buf.push(b'\n');
+                return Ok(());
+            }
+            // Named, as messages flatten their payload into the body map
+            Codec::MessagePack => rmp_serde::to_vec_named(value).context("encode msgpack")?,
+            Codec::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(value, &mut body).context("encode cbor")?;
+                body
+            }
+        };
+        if body.len() > MAX_FRAME {
+            bail!("{} byte frame is over the limit", body.len());
+        }
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+        Ok(())
+    }
+    // The next frame's contents, or None if reader ended between frames
+    pub fn read_frame(self, reader: &mut impl BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+        if self == Codec::Json {
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line).context("read line")? == 0 {
+                    return Ok(None);
+                }
+                if !line.trim_ascii().is_empty() {
+                    return Ok(Some(line));
+                }
+            }
+        }
+        if reader.fill_buf().context("read frame")?.is_empty() {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).context("read frame length")?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            bail!("{} byte frame is over the limit", len);
+        }
+        let mut frame = vec![0; len];
+        reader.read_exact(&mut frame).context("read frame")?;
+        Ok(Some(frame))
+    }
+    pub fn decode<T: DeserializeOwned>(self, frame: &[u8]) -> anyhow::Result<T> {
+        match self {
+            Codec::Json => serde_json::from_slice(frame).context("decode json"),
+            Codec::MessagePack => rmp_serde::from_slice(frame).context("decode msgpack"),
+            Codec::Cbor => ciborium::from_reader(frame).context("decode cbor"),
+        }
+    }
+}
+
+#[test]
+fn every_payload_round_trips_through_every_codec() -> anyhow::Result<()> {
+    use crate::msg::{Body, Message, Payload};
+
+    // At least one body per Payload variant
+    let bodies = [
+        r#"{"type":"topology","topology":{"n0":["n1"],"n1":["n0"]}}"#,
+        r#"{"type":"topology_ok"}"#,
+        r#"{"type":"read","key":3}"#,
+        r#"{"type":"read_ok","messages":[1,2]}"#,
+        r#"{"type":"read_ok","value":3}"#,
+        r#"{"type":"write","key":1,"value":2}"#,
+        r#"{"type":"write_ok"}"#,
+        r#"{"type":"cas","key":1,"from":2,"to":3}"#,
+        r#"{"type":"cas_ok"}"#,
+        r#"{"type":"add","delta":4}"#,
+        r#"{"type":"add_ok"}"#,
+        r#"{"type":"broadcast","message":5}"#,
+        r#"{"type":"broadcast_ok"}"#,
+        r#"{"type":"gossip_echo","ids":[1],"causal":[{"message":1,"origin":"n0","seq":1,"deps":{"n1":2}}]}"#,
+        r#"{"type":"gossip_count","adds":[["n0",1,2]],"updates":[{"node":"n1","state":"suspect","incarnation":1}]}"#,
+        r#"{"type":"generate"}"#,
+        r#"{"type":"generate_ok","id":"01J0000000000000000000000"}"#,
+        r#"{"type":"echo","echo":"hi"}"#,
+        r#"{"type":"echo_ok","echo":"hi"}"#,
+        r#"{"type":"init","node_id":"n0","node_ids":["n0","n1"]}"#,
+        r#"{"type":"init_ok"}"#,
+        r#"{"type":"send","key":"k","msg":7,"sub_key":"s"}"#,
+        r#"{"type":"send_ok","offset":1}"#,
+        r#"{"type":"send_batch","msgs":[{"key":"k","msg":1},{"key":"j","msg":2,"sub_key":"s"}]}"#,
+        r#"{"type":"send_batch_ok","offsets":[1,1]}"#,
+        r#"{"type":"poll","offsets":{"k":1}}"#,
+        r#"{"type":"poll_ok","msgs":{"k":[[1,7],[2,8]]}}"#,
+        r#"{"type":"commit_offsets","offsets":{"k":1},"group":"g"}"#,
+        r#"{"type":"commit_offsets_ok"}"#,
+        r#"{"type":"list_committed_offsets","keys":["k"]}"#,
+        r#"{"type":"list_committed_offsets_ok","offsets":{"k":1}}"#,
+        r#"{"type":"join_group","group":"g","member":"c1","keys":["k"],"strategy":"round_robin"}"#,
+        r#"{"type":"join_group_ok","generation":1,"keys":["k"]}"#,
+        r#"{"type":"leave_group","group":"g","member":"c1"}"#,
+        r#"{"type":"leave_group_ok","generation":2}"#,
+        r#"{"type":"sync_group","group":"g","member":"c1"}"#,
+        r#"{"type":"sync_group_ok","generation":1,"keys":[]}"#,
+        r#"{"type":"txn","txn":[["r",1,null],["w",1,2]]}"#,
+        r#"{"type":"txn_ok","txn":[["r",1,3],["w",1,2]]}"#,
+        r#"{"type":"commit_txn","start_ts":4,"writes":[[1,2]]}"#,
+        r#"{"type":"commit_txn_ok","commit_ts":5,"seq":2}"#,
+        r#"{"type":"replicate_writes","writes":[{"seq":1,"version":{"counter":2,"node":"n0"},"writes":[[1,2]]}]}"#,
+        r#"{"type":"replicate_writes_ok","seq":1}"#,
+        r#"{"type":"request_vote","term":2,"last_log_index":3,"last_log_term":1}"#,
+        r#"{"type":"request_vote_ok","term":2,"vote_granted":true}"#,
+        r#"{"type":"append_entries","term":2,"prev_log_index":1,"prev_log_term":1,"entries":[{"term":2,"command":{"write":[1,2]}},{"term":2,"command":null}],"leader_commit":1}"#,
+        r#"{"type":"append_entries_ok","term":2,"success":false,"match_index":1}"#,
+        r#"{"type":"prepare","ballot":7,"from_slot":3}"#,
+        r#"{"type":"promise","ballot":7,"ok":true,"promised":7,"accepted":[{"slot":3,"ballot":4,"value":{"proposed_in":4,"command":[1,"a",1.5]}}],"decided":[[2,{"proposed_in":1,"command":null}]]}"#,
+        r#"{"type":"accept","ballot":7,"slot":3,"value":{"proposed_in":7,"command":-1}}"#,
+        r#"{"type":"accepted","ballot":7,"slot":3,"ok":true,"promised":7}"#,
+        r#"{"type":"decide","decided":[[3,{"proposed_in":7,"command":"x"}]]}"#,
+        r#"{"type":"paxos_heartbeat","ballot":7,"sent_ms":100,"chosen_up_to":3}"#,
+        r#"{"type":"paxos_heartbeat_ok","ballot":7,"sent_ms":100,"chosen_up_to":3}"#,
+        r#"{"type":"ping","seq":1,"updates":[{"node":"n1","state":"suspect","incarnation":2}]}"#,
+        r#"{"type":"ping_req","seq":1,"target":"n2","updates":[]}"#,
+        r#"{"type":"ping_ack","seq":1,"updates":[{"node":"n2","state":"dead","incarnation":0}]}"#,
+        r#"{"type":"heartbeat"}"#,
+        r#"{"type":"join_cluster"}"#,
+        r#"{"type":"join_cluster_ok","epoch":1}"#,
+        r#"{"type":"leave_cluster"}"#,
+        r#"{"type":"leave_cluster_ok","epoch":2}"#,
+        r#"{"type":"join","node":"n3"}"#,
+        r#"{"type":"leave","node":"n3"}"#,
+        r#"{"type":"view_change","epoch":1,"members":["n0","n3"],"previous":["n0"]}"#,
+        r#"{"type":"view_change_ok","epoch":1}"#,
+        r#"{"type":"transfer_keys","epoch":1,"keys":[{"key":"k","entries":[{"offset":1,"msg":7,"timestamp_ms":1700000000000,"sub_key":null}],"committed":{"g":1}}]}"#,
+        r#"{"type":"transfer_keys_ok","epoch":1}"#,
+        r#"{"type":"error","code":11,"text":"try again"}"#,
+    ];
+    let payloads: Vec<Payload> = bodies
+        .iter()
+        .map(|b| serde_json::from_str(b).with_context(|| format!("parse {}", b)))
+        .collect::<anyhow::Result<_>>()?;
+
+    // Exhaustive, so a new variant doesn't build until it has a sample
+    let mut covered = std::collections::BTreeSet::new();
+    for payload in payloads.iter() {
+        covered.insert(match payload {
+            Payload::Topology { .. } => 0,
+            Payload::TopologyOk => 1,
+            Payload::Read { .. } => 2,
+            Payload::ReadOk { .. } => 3,
+            Payload::Write { .. } => 4,
+            Payload::WriteOk => 5,
+            Payload::Cas { .. } => 6,
+            Payload::CasOk => 7,
+            Payload::Add { .. } => 8,
+            Payload::AddOk => 9,
+            Payload::Broadcast { .. } => 10,
+            Payload::BroadcastOk => 11,
+            Payload::GossipEcho { .. } => 12,
+            Payload::GossipCount { .. } => 13,
+            Payload::Generate => 14,
+            Payload::GenerateOk { .. } => 15,
+            Payload::Echo { .. } => 16,
+            Payload::EchoOk { .. } => 17,
+            Payload::Init { .. } => 18,
+            Payload::InitOk => 19,
+            Payload::Send { .. } => 20,
+            Payload::SendOk { .. } => 21,
+            Payload::SendBatch { .. } => 22,
+            Payload::SendBatchOk { .. } => 23,
+            Payload::Poll { .. } => 24,
+            Payload::PollOk { .. } => 25,
+            Payload::CommitOffsets { .. } => 26,
+            Payload::CommitOffsetsOk => 27,
+            Payload::ListCommittedOffsets { .. } => 28,
+            Payload::ListCommittedOffsetsOk { .. } => 29,
+            Payload::JoinGroup { .. } => 30,
+            Payload::JoinGroupOk { .. } => 31,
+            Payload::LeaveGroup { .. } => 32,
+            Payload::LeaveGroupOk { .. } => 33,
+            Payload::SyncGroup { .. } => 34,
+            Payload::SyncGroupOk { .. } => 35,
+            Payload::Txn { .. } => 36,
+            Payload::TxnOk { .. } => 37,
+            Payload::CommitTxn { .. } => 38,
+            Payload::CommitTxnOk { .. } => 39,
+            Payload::ReplicateWrites { .. } => 40,
+            Payload::ReplicateWritesOk { .. } => 41,
+            Payload::RequestVote { .. } => 42,
+            Payload::RequestVoteOk { .. } => 43,
+            Payload::AppendEntries { .. } => 44,
+            Payload::AppendEntriesOk { .. } => 45,
+            Payload::Prepare { .. } => 46,
+            Payload::Promise { .. } => 47,
+            Payload::Accept { .. } => 48,
+            Payload::Accepted { .. } => 49,
+            Payload::Decide { .. } => 50,
+            Payload::PaxosHeartbeat { .. } => 51,
+            Payload::PaxosHeartbeatOk { .. } => 52,
+            Payload::Ping { .. } => 53,
+            Payload::PingReq { .. } => 54,
+            Payload::PingAck { .. } => 55,
+            Payload::Heartbeat => 56,
+            Payload::JoinCluster => 57,
+            Payload::JoinClusterOk { .. } => 58,
+            Payload::LeaveCluster => 59,
+            Payload::LeaveClusterOk { .. } => 60,
+            Payload::Join { .. } => 61,
+            Payload::Leave { .. } => 62,
+            Payload::ViewChange { .. } => 63,
+            Payload::ViewChangeOk { .. } => 64,
+            Payload::TransferKeys { .. } => 65,
+            Payload::TransferKeysOk { .. } => 66,
+            Payload::Error { .. } => 67,
+        });
+    }
+    assert_eq!(covered.len(), 68);
+
+    let clock: Option<crate::clock::ClockStamp> =
+        serde_json::from_str(r#"{"vector":{"n0":2,"n1":1}}"#)?;
+    for codec in [Codec::Json, Codec::MessagePack, Codec::Cbor] {
+        let mut wire = Vec::new();
+        let messages: Vec<Message> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| Message {
+                src: "n0".to_string(),
+                dest: "n1".to_string(),
+                body: Body {
+                    msg_id: Some(i),
+                    in_reply_to: (i % 2 == 0).then_some(i + 1),
+                    clock: clock.clone(),
+                    payload: payload.clone(),
+                },
+            })
+            .collect();
+        for msg in messages.iter() {
+            codec.encode(msg, &mut wire)?;
+        }
+        // Frames come back one at a time from the same stream
+        let mut reader = &wire[..];
+        for msg in messages.iter() {
+            let frame = codec.read_frame(&mut reader)?.context("frame")?;
+            let expected = serde_json::to_value(msg)?;
+            // Compared as JSON values, which sort their maps
+            let generic: serde_json::Value = codec.decode(&frame)?;
+            assert_eq!(generic, expected, "{:?}", codec);
+            let decoded: Message = codec.decode(&frame)?;
+            assert_eq!(serde_json::to_value(&decoded)?, expected, "{:?}", codec);
+        }
+        assert!(codec.read_frame(&mut reader)?.is_none());
+    }
+    Ok(())
+}
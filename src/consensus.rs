@@ -0,0 +1,193 @@
+use crate::msg::Payload;
+use crate::paxos::{Paxos, PaxosConfig};
+use crate::raft::{Raft, RaftConfig, Role};
+use anyhow::{bail, Context};
+
+pub const CONSENSUS_ENV: &str = "FLY_CONSENSUS";
+
+// A replicated log driven without I/O: the owner passes in ticks and
+// payloads from other nodes and sends whatever take_messages returns.
+// Positions are Raft log indexes or Paxos slots.
+pub trait Consensus {
+    // Append command if we lead, returning its position and a tag that
+    // take_committed returns with it only if it was this proposal that was
+    // committed there. Otherwise returns the leader, if known.
+    fn propose(&mut self, command: serde_json::Value) -> Result<(u64, u64), Option<String>>;
+    fn tick(&mut self, now_ms: u64);
+    // Returns false for payloads that aren't this protocol's
+    fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool;
+    fn take_messages(&mut self) -> Vec<(String, Payload)>;
+    // Newly committed commands in order, as (position, tag, command)
+    fn take_committed(&mut self) -> Vec<(u64, u64, serde_json::Value)>;
+    fn is_leader(&self) -> bool;
+    // Everything up to this position is committed
+    fn commit_index(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Raft,
+    Paxos,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "raft" => Ok(Backend::Raft),
+            "paxos" => Ok(Backend::Paxos),
+            _ => bail!("unknown consensus backend {:?}", s),
+        }
+    }
+}
+
+impl Backend {
+    // Backend from FLY_CONSENSUS, defaulting to Raft
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(CONSENSUS_ENV) {
+            Ok(s) => s.parse().context("parse FLY_CONSENSUS"),
+            Err(_) => Ok(Backend::Raft),
+        }
+    }
+    pub fn start(
+        &self,
+        id: &str,
+        node_ids: &[String],
+        seed: u64,
+        now_ms: u64,
+    ) -> Box<dyn Consensus> {
+        match self {
+            Backend::Raft => Box::new(Raft::new(id, node_ids, RaftConfig::default(), seed, now_ms)),
+            Backend::Paxos => Box::new(Paxos::new(
+                id,
+                node_ids,
+                PaxosConfig::default(),
+                seed,
+                now_ms,
+            )),
+        }
+    }
+}
+
+impl Consensus for Raft {
+    fn propose(&mut self, command: serde_json::Value) -> Result<(u64, u64), Option<String>> {
+        Raft::propose(self, command)
+    }
+    fn tick(&mut self, now_ms: u64) {
+        Raft::tick(self, now_ms)
+    }
+    fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool {
+        Raft::handle(self, from, payload, now_ms)
+    }
+    fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        Raft::take_messages(self)
+    }
+    fn take_committed(&mut self) -> Vec<(u64, u64, serde_json::Value)> {
+        Raft::take_committed(self)
+    }
+    fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+    fn commit_index(&self) -> u64 {
+        Raft::commit_index(self)
+    }
+}
+
+impl Consensus for Paxos {
+    fn propose(&mut self, command: serde_json::Value) -> Result<(u64, u64), Option<String>> {
+        Paxos::propose(self, command)
+    }
+    fn tick(&mut self, now_ms: u64) {
+        Paxos::tick(self, now_ms)
+    }
+    fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool {
+        Paxos::handle(self, from, payload, now_ms)
+    }
+    fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        Paxos::take_messages(self)
+    }
+    fn take_committed(&mut self) -> Vec<(u64, u64, serde_json::Value)> {
+        Paxos::take_committed(self)
+    }
+    fn is_leader(&self) -> bool {
+        Paxos::is_leader(self)
+    }
+    fn commit_index(&self) -> u64 {
+        self.chosen_up_to()
+    }
+}
+
+// Any backend can run in the simulator
+impl<C: Consensus> crate::sim::Process for C {
+    fn handle(&mut self, from: &str, payload: Payload, now_ms: u64) {
+        Consensus::handle(self, from, &payload, now_ms);
+    }
+    fn tick(&mut self, now_ms: u64) {
+        Consensus::tick(self, now_ms)
+    }
+    fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        Consensus::take_messages(self)
+    }
+}
+
+// Commit a write, cut the leader and one follower off, commit on the
+// majority side, heal, and check the old leader stepped down and every
+// node applied the same writes in log order
+#[cfg(test)]
+fn survives_partition<C: Consensus>(make: impl Fn(&str, &[String], u64) -> C) {
+    use crate::kv::{KvCommand, KvStore};
+    use crate::sim::Sim;
+    use std::collections::HashMap;
+
+    let ids: Vec<String> = (0..5).map(|i| format!("n{}", i)).collect();
+    let nodes = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.clone(), make(id, &ids, i as u64)));
+    let mut sim = Sim::new(nodes, 7);
+    let leader = |sim: &Sim<C>, not: Option<&String>| {
+        sim.nodes()
+            .find(|(id, c)| c.is_leader() && Some(*id) != not)
+            .map(|(id, _)| id.clone())
+    };
+    let write = |key, value| serde_json::to_value(KvCommand::Write { key, value }).unwrap();
+
+    assert!(sim.run_until(2_000, |s| leader(s, None).is_some()));
+    let first = leader(&sim, None).unwrap();
+    let (index, _) = sim.node_mut(&first).propose(write(1, 10)).unwrap();
+    assert!(sim.run_until(500, |s| s.node(&first).commit_index() >= index));
+
+    let follower = ids.iter().find(|id| **id != first).unwrap().clone();
+    sim.partition(&[&first, &follower]);
+    let (lost, _) = sim.node_mut(&first).propose(write(1, 11)).unwrap();
+    assert!(sim.run_until(2_000, |s| leader(s, Some(&first)).is_some()));
+    let second = leader(&sim, Some(&first)).unwrap();
+    let (index, _) = sim.node_mut(&second).propose(write(1, 12)).unwrap();
+    assert!(sim.run_until(500, |s| s.node(&second).commit_index() >= index));
+    assert!(sim.node(&first).commit_index() < lost);
+
+    sim.heal();
+    assert!(sim.run_until(2_000, |s| s.nodes().all(|(_, c)| c.commit_index() >= index)));
+    assert!(!sim.node(&first).is_leader());
+    let mut stores: HashMap<&String, KvStore> = HashMap::new();
+    for id in ids.iter() {
+        let store = stores.entry(id).or_default();
+        let committed = sim.node_mut(id).take_committed();
+        assert!(committed.windows(2).all(|w| w[0].0 < w[1].0));
+        for (_, _, command) in committed {
+            store.apply(&serde_json::from_value::<KvCommand>(command).unwrap());
+        }
+    }
+    let mut expected = KvStore::default();
+    expected.apply(&KvCommand::Write { key: 1, value: 12 });
+    for id in ids.iter() {
+        assert_eq!(stores[id], expected, "{} diverged", id);
+    }
+}
+
+#[test]
+fn raft_and_paxos_survive_the_same_partition() {
+    survives_partition(|id, ids, seed| Raft::new(id, ids, RaftConfig::default(), seed, 0));
+    survives_partition(|id, ids, seed| Paxos::new(id, ids, PaxosConfig::default(), seed, 0));
+}
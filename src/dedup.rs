@@ -0,0 +1,101 @@
+use crate::msg::Message;
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+// Replies we've already sent, keyed on the (src, msg_id) of the request
+// they answer, so a client retrying after a timeout gets the original
+// reply back instead of the request being applied twice.
+pub struct DedupCache {
+    capacity: usize,
+    ttl: Duration,
+    replies: HashMap<(String, usize), Message>,
+    // Insertion order, oldest first, for evicting by size and age
+    order: VecDeque<(Instant, (String, usize))>,
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        DedupCache::new(DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+
+impl DedupCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        DedupCache {
+            capacity,
+            ttl,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.replies.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+    // The reply we sent the first time we saw this request, if any
+    pub fn lookup(&mut self, request: &Message) -> Option<Message> {
+        self.evict(Instant::now());
+        let msg_id = request.body.msg_id?;
+        self.replies.get(&(request.src.clone(), msg_id)).cloned()
+    }
+    // Remember an outgoing reply; anything that isn't a reply is ignored
+    pub fn record(&mut self, reply: &Message) {
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            return;
+        };
+        let key = (reply.dest.clone(), in_reply_to);
+        let now = Instant::now();
+        if self.replies.insert(key.clone(), reply.clone()).is_none() {
+            self.order.push_back((now, key));
+        }
+        self.evict(now);
+    }
+    fn evict(&mut self, now: Instant) {
+        while let Some((at, key)) = self.order.front() {
+            if self.order.len() <= self.capacity && now.duration_since(*at) < self.ttl {
+                break;
+            }
+            debug!("evicting cached reply for {:?}", key);
+            self.replies.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[test]
+fn dedup_replays_and_evicts() {
+    use crate::msg::{Body, Payload};
+
+    let msg = |src: &str, dest: &str, msg_id, in_reply_to, payload| Message {
+        src: src.to_string(),
+        dest: dest.to_string(),
+        body: Body {
+            msg_id: Some(msg_id),
+            in_reply_to,
+            clock: None,
+            payload,
+        },
+    };
+    let mut cache = DedupCache::new(2, Duration::from_secs(60));
+    for i in 1..=3 {
+        cache.record(&msg("n0", "c1", 10 + i, Some(i), Payload::AddOk));
+    }
+    assert_eq!(cache.len(), 2);
+    assert!(cache
+        .lookup(&msg("c1", "n0", 1, None, Payload::Add { delta: 1 }))
+        .is_none());
+    let replay = cache
+        .lookup(&msg("c1", "n0", 3, None, Payload::Add { delta: 1 }))
+        .expect("cached reply");
+    assert_eq!(replay.body.msg_id, Some(13));
+
+    let mut cache = DedupCache::new(2, Duration::ZERO);
+    cache.record(&msg("n0", "c1", 11, Some(1), Payload::AddOk));
+    assert!(cache.is_empty());
+}
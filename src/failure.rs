@@ -0,0 +1,172 @@
+use crate::msg::Injected;
+use anyhow::Context;
+use log::info;
+use std::collections::{BTreeMap, VecDeque};
+
+// Suspect a peer once phi goes over this. Setting it turns the detector on.
+pub const PHI_THRESHOLD_ENV: &str = "FLY_PHI_THRESHOLD";
+// How often to send peers a heartbeat, in milliseconds
+pub const PHI_HEARTBEAT_MS_ENV: &str = "FLY_PHI_HEARTBEAT_MS";
+
+#[derive(Clone, Debug)]
+pub struct PhiConfig {
+    pub threshold: f64,
+    pub heartbeat_ms: u64,
+    // Intervals kept per peer to estimate the next one from
+    pub window: usize,
+    // Floor on the standard deviation, so a peer that's been very regular
+    // isn't suspected the moment it's a little late
+    pub min_std_dev_ms: f64,
+}
+
+impl Default for PhiConfig {
+    fn default() -> Self {
+        PhiConfig {
+            threshold: 8.0,
+            heartbeat_ms: 100,
+            window: 100,
+            min_std_dev_ms: 50.0,
+        }
+    }
+}
+
+impl PhiConfig {
+    // Config if FLY_PHI_THRESHOLD is set
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(threshold) = std::env::var(PHI_THRESHOLD_ENV) else {
+            return Ok(None);
+        };
+        let mut config = PhiConfig {
+            threshold: threshold.parse().context("parse FLY_PHI_THRESHOLD")?,
+            ..Default::default()
+        };
+        if let Ok(ms) = std::env::var(PHI_HEARTBEAT_MS_ENV) {
+            config.heartbeat_ms = ms.parse().context("parse FLY_PHI_HEARTBEAT_MS")?;
+        }
+        Ok(Some(config))
+    }
+}
+
+struct Arrivals {
+    last_ms: u64,
+    intervals: VecDeque<f64>,
+    suspected: bool,
+}
+
+// Phi-accrual failure detection: rather than a yes or no, how unlikely it
+// is, given the gaps between messages from a peer so far, that the next
+// one still hasn't arrived. phi of 1 means a 10% chance it's just late, 2
+// means 1%, and so on.
+pub struct PhiDetector {
+    config: PhiConfig,
+    peers: BTreeMap<String, Arrivals>,
+}
+
+impl PhiDetector {
+    pub fn new(peers: &[String], config: PhiConfig, now_ms: u64) -> Self {
+        // Start as if a heartbeat had just come in, at about the rate
+        // they're sent
+        let first = config.heartbeat_ms as f64;
+        let peers = peers
+            .iter()
+            .map(|p| {
+                let arrivals = Arrivals {
+                    last_ms: now_ms,
+                    intervals: VecDeque::from([first - first / 4.0, first + first / 4.0]),
+                    suspected: false,
+                };
+                (p.clone(), arrivals)
+            })
+            .collect();
+        PhiDetector { config, peers }
+    }
+    pub fn config(&self) -> &PhiConfig {
+        &self.config
+    }
+    // Record a heartbeat, or any other message, from peer
+    pub fn heartbeat(&mut self, peer: &str, now_ms: u64) {
+        let Some(arrivals) = self.peers.get_mut(peer) else {
+            return;
+        };
+        let interval = now_ms.saturating_sub(arrivals.last_ms) as f64;
+        arrivals.last_ms = now_ms;
+        if arrivals.intervals.len() >= self.config.window {
+            arrivals.intervals.pop_front();
+        }
+        arrivals.intervals.push_back(interval);
+    }
+    // Suspicion level of peer now, 0 for peers we don't know
+    pub fn phi(&self, peer: &str, now_ms: u64) -> f64 {
+        let Some(arrivals) = self.peers.get(peer) else {
+            return 0.0;
+        };
+        let n = arrivals.intervals.len() as f64;
+        let mean = arrivals.intervals.iter().sum::<f64>() / n;
+        let variance = arrivals
+            .intervals
+            .iter()
+            .map(|i| (i - mean) * (i - mean))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(self.config.min_std_dev_ms);
+        let elapsed = now_ms.saturating_sub(arrivals.last_ms) as f64;
+        // Logistic approximation of the normal distribution's tail
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+    // Peers that crossed the threshold either way since the last check, as
+    // events for the node
+    pub fn check(&mut self, now_ms: u64) -> Vec<Injected> {
+        let mut events = Vec::new();
+        let peers: Vec<String> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            let phi = self.phi(&peer, now_ms);
+            let arrivals = self.peers.get_mut(&peer).expect("known peer");
+            let suspected = phi > self.config.threshold;
+            if suspected == arrivals.suspected {
+                continue;
+            }
+            arrivals.suspected = suspected;
+            if suspected {
+                info!("suspecting {} with phi {:.1}", peer, phi);
+                events.push(Injected::PeerSuspected(peer));
+            } else {
+                info!("{} recovered", peer);
+                events.push(Injected::PeerRecovered(peer));
+            }
+        }
+        events
+    }
+}
+
+#[test]
+fn phi_rises_with_silence_and_recovers() {
+    let peers = vec!["n1".to_string()];
+    let mut detector = PhiDetector::new(&peers, PhiConfig::default(), 0);
+    for t in (100..=2_000).step_by(100) {
+        detector.heartbeat("n1", t);
+    }
+    assert!(detector.phi("n1", 2_050) < 1.0);
+    assert!(detector.check(2_100).is_empty());
+    // Phi only grows while nothing arrives
+    let phis: Vec<f64> = (2_100..2_600)
+        .step_by(100)
+        .map(|t| detector.phi("n1", t))
+        .collect();
+    assert!(phis.windows(2).all(|w| w[0] <= w[1]));
+    assert!(matches!(
+        detector.check(2_600).as_slice(),
+        [Injected::PeerSuspected(p)] if p == "n1"
+    ));
+    assert!(detector.check(2_700).is_empty());
+    detector.heartbeat("n1", 2_800);
+    assert!(matches!(
+        detector.check(2_810).as_slice(),
+        [Injected::PeerRecovered(p)] if p == "n1"
+    ));
+}
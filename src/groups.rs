@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AssignmentStrategy {
+    // Each member gets a contiguous run of the sorted keys
+    #[default]
+    Range,
+    // Sorted keys are dealt out to members in turn
+    RoundRobin,
+}
+
+// Members of a group share one subscription, the union of the keys each
+// of them asked for, which is split between them on every rebalance
+#[derive(Default)]
+struct Group {
+    strategy: AssignmentStrategy,
+    // Bumped every time membership or subscriptions change
+    generation: usize,
+    // member -> keys it subscribed to
+    members: BTreeMap<String, BTreeSet<String>>,
+    assignment: HashMap<String, Vec<String>>,
+}
+
+impl Group {
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        let members: Vec<String> = self.members.keys().cloned().collect();
+        let keys: BTreeSet<String> = self.members.values().flatten().cloned().collect();
+        let keys: Vec<String> = keys.into_iter().collect();
+        self.assignment = assign(self.strategy, &members, &keys);
+    }
+}
+
+// Group membership and key assignment for the groups this node coordinates
+#[derive(Default)]
+pub struct GroupCoordinator {
+    groups: HashMap<String, Group>,
+}
+
+impl GroupCoordinator {
+    // Add member to group, or update its subscription, returning the
+    // generation and the keys assigned to member. The strategy is fixed by
+    // the first member to join an empty group.
+    pub fn join(
+        &mut self,
+        group: &str,
+        member: &str,
+        keys: Vec<String>,
+        strategy: Option<AssignmentStrategy>,
+    ) -> (usize, Vec<String>) {
+        let g = self.groups.entry(group.to_string()).or_default();
+        if g.members.is_empty() {
+            g.strategy = strategy.unwrap_or_default();
+        }
+        let keys: BTreeSet<String> = keys.into_iter().collect();
+        if g.members.get(member) != Some(&keys) {
+            g.members.insert(member.to_string(), keys);
+            g.rebalance();
+        }
+        self.assignment(group, member).unwrap_or_default()
+    }
+    // Remove member from group, returning the new generation
+    pub fn leave(&mut self, group: &str, member: &str) -> usize {
+        let Some(g) = self.groups.get_mut(group) else {
+            return 0;
+        };
+        if g.members.remove(member).is_some() {
+            g.rebalance();
+        }
+        // An empty group is kept so its generation keeps counting up, and
+        // a member from before it emptied can't pass for a current one
+        g.generation
+    }
+    // The current generation and member's keys, if it's in group
+    pub fn assignment(&self, group: &str, member: &str) -> Option<(usize, Vec<String>)> {
+        let g = self.groups.get(group)?;
+        let keys = g.assignment.get(member)?;
+        Some((g.generation, keys.clone()))
+    }
+}
+
+// Split keys between members, both of which should already be sorted
+pub fn assign(
+    strategy: AssignmentStrategy,
+    members: &[String],
+    keys: &[String],
+) -> HashMap<String, Vec<String>> {
+    let mut assignment: HashMap<String, Vec<String>> =
+        members.iter().map(|m| (m.clone(), Vec::new())).collect();
+    if members.is_empty() {
+        return assignment;
+    }
+    match strategy {
+        AssignmentStrategy::Range => {
+            let per_member = keys.len() / members.len();
+            let extra = keys.len() % members.len();
+            let mut keys = keys.iter();
+            for (i, member) in members.iter().enumerate() {
+                let n = per_member + usize::from(i < extra);
+                assignment
+                    .get_mut(member)
+                    .expect("member in assignment")
+                    .extend(keys.by_ref().take(n).cloned());
+            }
+        }
+        AssignmentStrategy::RoundRobin => {
+            for (i, key) in keys.iter().enumerate() {
+                assignment
+                    .get_mut(&members[i % members.len()])
+                    .expect("member in assignment")
+                    .push(key.clone());
+            }
+        }
+    }
+    assignment
+}
+
+// The node that coordinates group. Every node picks the same one since it
+// only depends on the group name and the node ids from init.
+pub fn coordinator_for<'n>(group: &str, node_ids: &'n [String]) -> Option<&'n String> {
+    if node_ids.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<&String> = node_ids.iter().collect();
+    sorted.sort();
+    // FNV-1a, so the choice doesn't depend on the process's hasher seed
+    let hash = group.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    Some(sorted[(hash % sorted.len() as u64) as usize])
+}
+
+#[test]
+fn groups_rebalance_on_join_and_leave() {
+    let keys = |ks: &[&str]| ks.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+    let members = keys(&["a", "b"]);
+    let topics = keys(&["k1", "k2", "k3"]);
+    let range = assign(AssignmentStrategy::Range, &members, &topics);
+    assert_eq!(range["a"], keys(&["k1", "k2"]));
+    assert_eq!(range["b"], keys(&["k3"]));
+    let round_robin = assign(AssignmentStrategy::RoundRobin, &members, &topics);
+    assert_eq!(round_robin["a"], keys(&["k1", "k3"]));
+    assert_eq!(round_robin["b"], keys(&["k2"]));
+
+    let mut coordinator = GroupCoordinator::default();
+    assert_eq!(
+        coordinator.join("g", "a", topics.clone(), None),
+        (1, topics.clone())
+    );
+    assert_eq!(coordinator.join("g", "b", keys(&["k3"]), None).0, 2);
+    // Rejoining with the same subscription doesn't rebalance
+    assert_eq!(
+        coordinator.join("g", "b", keys(&["k3"]), None),
+        (2, keys(&["k3"]))
+    );
+    assert_eq!(coordinator.leave("g", "a"), 3);
+    assert_eq!(coordinator.assignment("g", "b"), Some((3, keys(&["k3"]))));
+    // The group outlives its last member, so generations don't restart
+    assert_eq!(coordinator.leave("g", "b"), 4);
+    assert_eq!(coordinator.assignment("g", "b"), None);
+    assert_eq!(
+        coordinator.join(
+            "g",
+            "a",
+            topics.clone(),
+            Some(AssignmentStrategy::RoundRobin)
+        ),
+        (5, topics.clone())
+    );
+
+    let nodes = keys(&["n1", "n0", "n2"]);
+    let reversed: Vec<String> = nodes.iter().rev().cloned().collect();
+    assert_eq!(
+        coordinator_for("g", &nodes),
+        coordinator_for("g", &reversed)
+    );
+}
@@ -0,0 +1,315 @@
+use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::runtime::Node;
+use crate::sink::VecSink;
+use anyhow::{bail, Context};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// Drives one node in-process the way the runtime would, handing back the
+// messages each event made it send. Time only moves when advanced, so
+// timeouts can be tested without waiting for them.
+pub struct Harness<N: Node> {
+    node: N,
+    sink: VecSink,
+    node_id: String,
+    next_msg_id: usize,
+    now: Instant,
+}
+
+impl<N: Node> Harness<N> {
+    // A node initialised as node_id among node_ids, with its init_ok taken
+    pub fn new(node_id: &str, node_ids: &[&str]) -> anyhow::Result<Self> {
+        let sink = VecSink::default();
+        let init = Message {
+            src: "c0".to_string(),
+            dest: node_id.to_string(),
+            body: Body {
+                msg_id: Some(0),
+                in_reply_to: None,
+                clock: None,
+                payload: Payload::Init {
+                    node_id: node_id.to_string(),
+                    node_ids: node_ids.iter().map(|n| n.to_string()).collect(),
+                },
+            },
+        };
+        let node = N::from_init(Event::Message(init), Box::new(sink.clone()))?;
+        let sent = sink.take();
+        if sent.len() != 1 || !matches!(sent[0].body.payload, Payload::InitOk) {
+            bail!("expected init_ok, got {:?}", sent);
+        }
+        Ok(Harness {
+            node,
+            sink,
+            node_id: node_id.to_string(),
+            next_msg_id: 1,
+            now: Instant::now(),
+        })
+    }
+    pub fn node(&mut self) -> &mut N {
+        &mut self.node
+    }
+    pub fn advance(&mut self, by: Duration) {
+        self.now += by;
+    }
+    // Feed one event, with the clock and retry handling the runtime does
+    // before step
+    pub fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<Vec<Message>> {
+        if let Event::Message(ref msg) = input {
+            if let Some(clock) = self.node.clock() {
+                clock.receive(msg);
+            }
+            if let Some(reply) = self.node.dedup().and_then(|d| d.lookup(msg)) {
+                self.node.send(reply)?;
+                return Ok(self.sink.take());
+            }
+        }
+        self.node.step_at(input, self.now)?;
+        Ok(self.sink.take())
+    }
+    // Send payload from src with a fresh msg_id, returning what it made the
+    // node send
+    pub fn message(&mut self, src: &str, payload: Payload) -> anyhow::Result<Vec<Message>> {
+        let msg = Message {
+            src: src.to_string(),
+            dest: self.node_id.clone(),
+            body: Body {
+                msg_id: Some(self.next_msg_id),
+                in_reply_to: None,
+                clock: None,
+                payload,
+            },
+        };
+        self.next_msg_id += 1;
+        self.step(Event::Message(msg))
+    }
+    pub fn inject(&mut self, injected: Injected) -> anyhow::Result<Vec<Message>> {
+        self.step(Event::Injected(injected))
+    }
+    // Send payload from client c1 and return the payload of the reply
+    pub fn call(&mut self, payload: Payload) -> anyhow::Result<Payload> {
+        let msg_id = self.next_msg_id;
+        let sent = self.message("c1", payload)?;
+        sent.into_iter()
+            .find(|m| m.dest == "c1" && m.body.in_reply_to == Some(msg_id))
+            .map(|m| m.body.payload)
+            .with_context(|| format!("no reply to msg {}", msg_id))
+    }
+}
+
+// Harnessed nodes wired together, with no latency and simulated time.
+// Messages between them go through JSON, as on a real transport, and are
+// delivered in order until none are left; those to anything else are kept
+// as replies for the client.
+pub struct HarnessNet<N: Node> {
+    nodes: BTreeMap<String, Harness<N>>,
+    // One side of a partition. Messages across it are lost, though clients
+    // still reach every node.
+    side: BTreeSet<String>,
+    in_flight: VecDeque<Message>,
+    replies: Vec<Message>,
+    next_msg_id: usize,
+}
+
+impl<N: Node> HarnessNet<N> {
+    pub fn new(node_ids: &[&str]) -> anyhow::Result<Self> {
+        let mut nodes = BTreeMap::new();
+        for id in node_ids {
+            nodes.insert(id.to_string(), Harness::new(id, node_ids)?);
+        }
+        Ok(HarnessNet {
+            nodes,
+            side: BTreeSet::new(),
+            in_flight: VecDeque::new(),
+            replies: Vec::new(),
+            next_msg_id: 1,
+        })
+    }
+    pub fn node(&mut self, id: &str) -> &mut Harness<N> {
+        self.nodes.get_mut(id).expect("node in network")
+    }
+    // Cut every link between a node in side and one outside it, both ways
+    pub fn partition(&mut self, side: &[&str]) {
+        self.side = side.iter().map(|n| n.to_string()).collect();
+    }
+    pub fn heal(&mut self) {
+        self.side.clear();
+    }
+    pub fn advance(&mut self, by: Duration) {
+        for node in self.nodes.values_mut() {
+            node.advance(by);
+        }
+    }
+    // Queue msg for delivery
+    pub fn send(&mut self, msg: Message) {
+        self.in_flight.push_back(msg);
+    }
+    // Queue payload from client c1 to dest, returning its msg_id
+    pub fn request(&mut self, dest: &str, payload: Payload) -> usize {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        self.send(Message {
+            src: "c1".to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                clock: None,
+                payload,
+            },
+        });
+        msg_id
+    }
+    // Deliver messages until there are none left
+    pub fn deliver(&mut self) -> anyhow::Result<()> {
+        while let Some(msg) = self.in_flight.pop_front() {
+            let msg: Message = serde_json::from_str(&serde_json::to_string(&msg)?)?;
+            if !self.nodes.contains_key(&msg.dest) {
+                self.replies.push(msg);
+                continue;
+            }
+            let cut = self.nodes.contains_key(&msg.src)
+                && self.side.contains(&msg.src) != self.side.contains(&msg.dest);
+            if cut {
+                continue;
+            }
+            let dest = msg.dest.clone();
+            let sent = self.node(&dest).step(Event::Message(msg))?;
+            self.in_flight.extend(sent);
+        }
+        Ok(())
+    }
+    // Tick every node, then deliver what that sends
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        for node in self.nodes.values_mut() {
+            let sent = node.inject(Injected::GossipNow)?;
+            self.in_flight.extend(sent);
+        }
+        self.deliver()
+    }
+    // Advance time a millisecond at a time, ticking every node each one
+    pub fn run(&mut self, ms: u64) -> anyhow::Result<()> {
+        for _ in 0..ms {
+            self.advance(Duration::from_millis(1));
+            self.tick()?;
+        }
+        Ok(())
+    }
+    // Run until the client's reply to msg_id comes, for at most max_ms
+    pub fn wait(&mut self, msg_id: usize, max_ms: u64) -> anyhow::Result<Message> {
+        for _ in 0..=max_ms {
+            if let Some(i) = self
+                .replies
+                .iter()
+                .position(|m| m.body.in_reply_to == Some(msg_id))
+            {
+                return Ok(self.replies.remove(i));
+            }
+            self.run(1)?;
+        }
+        bail!("no reply to msg {} in {}ms", msg_id, max_ms)
+    }
+    // Take the client's reply to msg_id, if it has come
+    pub fn reply(&mut self, msg_id: usize) -> Option<Payload> {
+        let i = self
+            .replies
+            .iter()
+            .position(|m| m.body.in_reply_to == Some(msg_id))?;
+        Some(self.replies.remove(i).body.payload)
+    }
+    // Send payload from client c1 to dest and return the payload of the
+    // reply once everything has been delivered
+    pub fn call(&mut self, dest: &str, payload: Payload) -> anyhow::Result<Payload> {
+        let msg_id = self.request(dest, payload);
+        self.deliver()?;
+        self.reply(msg_id)
+            .with_context(|| format!("no reply to msg {}", msg_id))
+    }
+}
+
+#[test]
+fn echo_node_reads_back_broadcasts_and_gossips_them() -> anyhow::Result<()> {
+    use crate::EchoNode::EchoNode;
+    use std::collections::HashMap;
+
+    let mut h = Harness::<EchoNode>::new("n0", &["n0", "n1"])?;
+    let topology = HashMap::from([
+        ("n0".to_string(), vec!["n1".to_string()]),
+        ("n1".to_string(), vec!["n0".to_string()]),
+    ]);
+    assert!(matches!(
+        h.call(Payload::Topology { topology })?,
+        Payload::TopologyOk
+    ));
+    assert!(matches!(
+        h.call(Payload::Broadcast { message: 1 })?,
+        Payload::BroadcastOk
+    ));
+    assert!(matches!(
+        h.call(Payload::Read { key: None })?,
+        Payload::ReadOk { messages: Some(messages), .. } if messages == [1]
+    ));
+    // Gossip carries it to the neighbour
+    let sent = h.inject(Injected::GossipNow)?;
+    assert!(sent.iter().any(|m| m.dest == "n1"
+        && matches!(m.body.payload, Payload::GossipEcho { ref ids, .. } if ids.contains(&1))));
+    Ok(())
+}
+
+#[test]
+fn count_node_adds_up_deltas() -> anyhow::Result<()> {
+    use crate::CountNode::CountNode;
+
+    let mut h = Harness::<CountNode>::new("n0", &["n0"])?;
+    for delta in [3, 4] {
+        assert!(matches!(h.call(Payload::Add { delta })?, Payload::AddOk));
+    }
+    assert!(matches!(
+        h.call(Payload::Read { key: None })?,
+        Payload::ReadOk { value: Some(7), .. }
+    ));
+    Ok(())
+}
+
+#[test]
+fn kafka_node_sends_polls_and_commits() -> anyhow::Result<()> {
+    use crate::KafkaNode::KafkaNode;
+    use std::collections::HashMap;
+
+    let mut h = Harness::<KafkaNode>::new("n0", &["n0"])?;
+    let mut offsets = Vec::new();
+    for msg in [10, 11] {
+        match h.call(Payload::Send {
+            key: "k".to_string(),
+            msg,
+            sub_key: None,
+        })? {
+            Payload::SendOk { offset } => offsets.push(offset),
+            other => bail!("expected send_ok, got {:?}", other),
+        }
+    }
+    assert!(offsets[0] < offsets[1]);
+
+    let poll = Payload::Poll {
+        offsets: HashMap::from([("k".to_string(), offsets[0])]),
+    };
+    assert!(matches!(
+        h.call(poll)?,
+        Payload::PollOk { msgs } if msgs["k"] == [(offsets[0], 10), (offsets[1], 11)]
+    ));
+    let commit = Payload::CommitOffsets {
+        offsets: HashMap::from([("k".to_string(), offsets[1])]),
+        group: None,
+    };
+    assert!(matches!(h.call(commit)?, Payload::CommitOffsetsOk));
+    let list = Payload::ListCommittedOffsets {
+        keys: vec!["k".to_string(), "missing".to_string()],
+        group: None,
+    };
+    assert!(matches!(
+        h.call(list)?,
+        Payload::ListCommittedOffsetsOk { offsets: committed }
+            if committed == HashMap::from([("k".to_string(), offsets[1])])
+    ));
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use anyhow::{bail, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Environment variable used to pick the strategy for answering `generate`
+pub const ID_STRATEGY_ENV: &str = "FLY_ID_STRATEGY";
+
+// 2023-01-01T00:00:00Z, so the 41 bit timestamp lasts until ~2092
+const SNOWFLAKE_EPOCH_MS: u64 = 1_672_531_200_000;
+const NODE_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const MAX_NODE_INDEX: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum GeneratedId {
+    Int(u64),
+    Str(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdStrategy {
+    Uuid,
+    // `<node_id>-<counter>`. The counter isn't saved anywhere, so ids are
+    // only unique within one run of a node and repeat after a restart.
+    Counter,
+    // 41 bits of ms timestamp, 10 bits of node index and 12 bits of sequence
+    Snowflake,
+    // Lexicographically sortable 128 bit ids
+    Ulid,
+}
+
+impl std::str::FromStr for IdStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "uuid" => Ok(IdStrategy::Uuid),
+            "counter" => Ok(IdStrategy::Counter),
+            "snowflake" => Ok(IdStrategy::Snowflake),
+            "ulid" => Ok(IdStrategy::Ulid),
+            _ => bail!("unknown id strategy {:?}", s),
+        }
+    }
+}
+
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    node_id: String,
+    node_index: u64,
+    counter: u64,
+    last_ms: u64,
+    sequence: u64,
+    ulid: ulid::Generator,
+}
+
+impl IdGenerator {
+    pub fn new(strategy: IdStrategy, node_id: &str, node_ids: &[String]) -> anyhow::Result<Self> {
+        // Only snowflake ids use the index. A node missing from node_ids
+        // has none, and giving all such nodes the same one would let their
+        // ids collide.
+        let node_index = node_ids.iter().position(|n| n == node_id).map(|i| i as u64);
+        if strategy == IdStrategy::Snowflake {
+            match node_index {
+                None => bail!(
+                    "{} isn't in node_ids, so has no snowflake node index",
+                    node_id
+                ),
+                Some(i) if i > MAX_NODE_INDEX => {
+                    bail!("node index {} doesn't fit in a snowflake id", i)
+                }
+                Some(_) => {}
+            }
+        }
+        let node_index = node_index.unwrap_or_default();
+        Ok(IdGenerator {
+            strategy,
+            node_id: node_id.to_string(),
+            node_index,
+            counter: 0,
+            last_ms: 0,
+            sequence: 0,
+            ulid: ulid::Generator::new(),
+        })
+    }
+    // Strategy from FLY_ID_STRATEGY, defaulting to UUIDv4
+    pub fn from_env(node_id: &str, node_ids: &[String]) -> anyhow::Result<Self> {
+        let strategy = match std::env::var(ID_STRATEGY_ENV) {
+            Ok(s) => s.parse().context("parse FLY_ID_STRATEGY")?,
+            Err(_) => IdStrategy::Uuid,
+        };
+        IdGenerator::new(strategy, node_id, node_ids)
+    }
+    pub fn next_id(&mut self) -> anyhow::Result<GeneratedId> {
+        Ok(match self.strategy {
+            IdStrategy::Uuid => GeneratedId::Str(Uuid::new_v4().to_string()),
+            IdStrategy::Counter => {
+                self.counter += 1;
+                GeneratedId::Str(format!("{}-{}", self.node_id, self.counter))
+            }
+            IdStrategy::Snowflake => GeneratedId::Int(self.next_snowflake(now_ms())),
+            IdStrategy::Ulid => GeneratedId::Str(
+                self.ulid
+                    .generate()
+                    .context("ulid random part overflowed")?
+                    .to_string(),
+            ),
+        })
+    }
+    fn next_snowflake(&mut self, now_ms: u64) -> u64 {
+        let now_ms = now_ms.saturating_sub(SNOWFLAKE_EPOCH_MS);
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.sequence = 0;
+        } else {
+            // Either the same millisecond or the clock went backwards. In
+            // both cases keep counting from the last timestamp we handed
+            // out, borrowing the next millisecond when the sequence runs out,
+            // rather than blocking or risking a duplicate.
+            if now_ms < self.last_ms {
+                warn!(
+                    "clock went backwards by {}ms, reusing last timestamp",
+                    self.last_ms - now_ms
+                );
+            }
+            self.sequence += 1;
+            if self.sequence > MAX_SEQUENCE {
+                self.last_ms += 1;
+                self.sequence = 0;
+            }
+        }
+        (self.last_ms << (NODE_BITS + SEQUENCE_BITS))
+            | (self.node_index << SEQUENCE_BITS)
+            | self.sequence
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[test]
+fn snowflake_ids_increase_across_clock_regression() {
+    let node_ids = vec!["n0".to_string(), "n1".to_string()];
+    let mut gen = IdGenerator::new(IdStrategy::Snowflake, "n1", &node_ids).unwrap();
+    let t = SNOWFLAKE_EPOCH_MS + 1_000;
+    let mut ids = vec![gen.next_snowflake(t), gen.next_snowflake(t)];
+    // clock jumps back, then a full sequence worth of ids in one millisecond
+    ids.push(gen.next_snowflake(t - 500));
+    for _ in 0..=MAX_SEQUENCE {
+        ids.push(gen.next_snowflake(t));
+    }
+    ids.push(gen.next_snowflake(t + 5));
+    assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    assert!(ids
+        .iter()
+        .all(|id| (id >> SEQUENCE_BITS) & MAX_NODE_INDEX == 1));
+
+    assert!(IdGenerator::new(IdStrategy::Snowflake, "n2", &node_ids).is_err());
+
+    let mut gen = IdGenerator::new(IdStrategy::Counter, "n1", &node_ids).unwrap();
+    assert_eq!(gen.next_id().unwrap(), GeneratedId::Str("n1-1".to_string()));
+    assert_eq!(
+        serde_json::to_string(&GeneratedId::Int(7)).unwrap(),
+        "7".to_string()
+    );
+}
@@ -0,0 +1,341 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+// Keep at most this many messages per key
+pub const RETAIN_MESSAGES_ENV: &str = "FLY_KAFKA_RETAIN_MESSAGES";
+// Drop messages older than this many milliseconds
+pub const RETAIN_MS_ENV: &str = "FLY_KAFKA_RETAIN_MS";
+// Drop messages below the offset every group has committed for a key
+pub const DELETE_COMMITTED_ENV: &str = "FLY_KAFKA_DELETE_COMMITTED";
+// Keep only the latest message for each sub-key
+pub const COMPACT_ENV: &str = "FLY_KAFKA_COMPACT";
+
+// Offsets committed without a group belong to this one
+pub const DEFAULT_GROUP: &str = "";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub offset: usize,
+    pub msg: usize,
+    pub timestamp_ms: u64,
+    pub sub_key: Option<String>,
+}
+
+// One message of an atomic multi-key send
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BatchMessage {
+    pub key: String,
+    pub msg: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_key: Option<String>,
+}
+
+// Everything a node holds for one key, to hand it to another node
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyData {
+    pub key: String,
+    pub entries: Vec<Entry>,
+    // group -> committed offset
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub committed: BTreeMap<String, usize>,
+}
+
+// Storage behind KafkaNode's send/poll/commit_offsets. Offsets within a key
+// start at 1 and increase by one per appended message, though retention can
+// leave gaps.
+pub trait MessageLog {
+    // Append msg to key's log, returning the offset it was given
+    fn append(&mut self, key: &str, msg: usize, sub_key: Option<&str>) -> anyhow::Result<usize>;
+    // Messages in key's log at or after offset. If offset has already been
+    // removed by retention this starts from the earliest one still kept.
+    fn read(&self, key: &str, offset: usize) -> anyhow::Result<Vec<(usize, usize)>>;
+    // Append every message in msgs or none of them, returning their offsets
+    // in the same order
+    fn append_batch(&mut self, msgs: &[BatchMessage]) -> anyhow::Result<Vec<usize>>;
+    // Committed offsets are per (group, key) and only ever move forward
+    fn commit(&mut self, group: &str, key: &str, offset: usize) -> anyhow::Result<()>;
+    fn committed(&self, group: &str, key: &str) -> Option<usize>;
+    // Remove whatever policy says has expired, returning how many messages
+    // were removed
+    fn retain(&mut self, policy: &RetentionPolicy, now_ms: u64) -> anyhow::Result<usize>;
+    // Keys with messages or committed offsets here
+    fn keys(&self) -> Vec<String>;
+    fn export(&self, key: &str) -> anyhow::Result<KeyData>;
+    // Take in a key from another node. Entries at offsets already here are
+    // skipped, so importing the same data twice is harmless.
+    fn import(&mut self, data: KeyData) -> anyhow::Result<()>;
+    // Forget key once it's been handed to another node
+    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+    // Make everything written so far durable
+    fn sync(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    // The state to write to a snapshot, or None for logs that are already
+    // durable on their own
+    fn snapshot(&self) -> Option<&MemoryLog> {
+        None
+    }
+}
+
+// group -> key -> committed offset
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CommittedOffsets(HashMap<String, HashMap<String, usize>>);
+
+impl CommittedOffsets {
+    // Returns whether the offset moved forward
+    pub fn commit(&mut self, group: &str, key: &str, offset: usize) -> bool {
+        let committed = self
+            .0
+            .entry(group.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_default();
+        if *committed >= offset {
+            return false;
+        }
+        *committed = offset;
+        true
+    }
+    pub fn get(&self, group: &str, key: &str) -> Option<usize> {
+        self.0.get(group).and_then(|g| g.get(key)).cloned()
+    }
+    // The lowest offset any group has committed for key, i.e. what every
+    // consumer of key has got past
+    pub fn lowest(&self, key: &str) -> Option<usize> {
+        self.0.values().filter_map(|g| g.get(key)).min().cloned()
+    }
+    // group -> committed offset for key
+    pub fn for_key(&self, key: &str) -> BTreeMap<String, usize> {
+        self.0
+            .iter()
+            .filter_map(|(group, offsets)| offsets.get(key).map(|o| (group.clone(), *o)))
+            .collect()
+    }
+    pub fn remove_key(&mut self, key: &str) {
+        for offsets in self.0.values_mut() {
+            offsets.remove(key);
+        }
+        self.0.retain(|_, offsets| !offsets.is_empty());
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, usize)> {
+        self.0.iter().flat_map(|(group, offsets)| {
+            offsets
+                .iter()
+                .map(move |(key, offset)| (group.as_str(), key.as_str(), *offset))
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.0.values().map(|g| g.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub delete_committed: bool,
+    pub compact: bool,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let flag = |name| std::env::var(name).is_ok_and(|v| v == "1" || v == "true");
+        let mut policy = RetentionPolicy {
+            delete_committed: flag(DELETE_COMMITTED_ENV),
+            compact: flag(COMPACT_ENV),
+            ..Default::default()
+        };
+        if let Ok(n) = std::env::var(RETAIN_MESSAGES_ENV) {
+            policy.max_messages = Some(n.parse().context("parse retained message count")?);
+        }
+        if let Ok(ms) = std::env::var(RETAIN_MS_ENV) {
+            policy.max_age = Some(Duration::from_millis(
+                ms.parse().context("parse retention age")?,
+            ));
+        }
+        Ok(policy)
+    }
+    pub fn is_enabled(&self) -> bool {
+        *self != RetentionPolicy::default()
+    }
+    // Offsets of the entries (oldest first) that the policy removes. The
+    // newest entry of a key is always kept so offsets keep increasing.
+    pub fn expired(
+        &self,
+        entries: &[Entry],
+        committed: Option<usize>,
+        now_ms: u64,
+    ) -> HashSet<usize> {
+        let Some((newest, older)) = entries.split_last() else {
+            return HashSet::new();
+        };
+        let mut expired = HashSet::new();
+        if let Some(max) = self.max_messages {
+            let excess = entries.len().saturating_sub(max.max(1));
+            expired.extend(older.iter().take(excess).map(|e| e.offset));
+        }
+        if let Some(max_age) = self.max_age {
+            let cutoff = now_ms.saturating_sub(max_age.as_millis() as u64);
+            expired.extend(
+                older
+                    .iter()
+                    .filter(|e| e.timestamp_ms < cutoff)
+                    .map(|e| e.offset),
+            );
+        }
+        if let (true, Some(committed)) = (self.delete_committed, committed) {
+            expired.extend(
+                older
+                    .iter()
+                    .filter(|e| e.offset < committed)
+                    .map(|e| e.offset),
+            );
+        }
+        if self.compact {
+            let mut latest: HashSet<&str> = newest.sub_key.iter().map(|s| s.as_str()).collect();
+            for e in older.iter().rev() {
+                if let Some(sub_key) = e.sub_key.as_deref() {
+                    if !latest.insert(sub_key) {
+                        expired.insert(e.offset);
+                    }
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct MemoryLog {
+    logs: HashMap<String, Vec<Entry>>,
+    committed: CommittedOffsets,
+}
+
+impl MessageLog for MemoryLog {
+    fn append(&mut self, key: &str, msg: usize, sub_key: Option<&str>) -> anyhow::Result<usize> {
+        let entry = self.logs.entry(key.to_string()).or_default();
+        let offset = entry[..].last().map(|e| e.offset).unwrap_or(0) + 1;
+        entry.push(Entry {
+            offset,
+            msg,
+            timestamp_ms: crate::ids::now_ms(),
+            sub_key: sub_key.map(|s| s.to_string()),
+        });
+        Ok(offset)
+    }
+    fn read(&self, key: &str, offset: usize) -> anyhow::Result<Vec<(usize, usize)>> {
+        Ok(self
+            .logs
+            .get(key)
+            .map(|log| {
+                log.iter()
+                    .filter(|e| e.offset >= offset)
+                    .map(|e| (e.offset, e.msg))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+    // Appending to memory can't fail part way through
+    fn append_batch(&mut self, msgs: &[BatchMessage]) -> anyhow::Result<Vec<usize>> {
+        msgs.iter()
+            .map(|m| self.append(&m.key, m.msg, m.sub_key.as_deref()))
+            .collect()
+    }
+    fn commit(&mut self, group: &str, key: &str, offset: usize) -> anyhow::Result<()> {
+        self.committed.commit(group, key, offset);
+        Ok(())
+    }
+    fn committed(&self, group: &str, key: &str) -> Option<usize> {
+        self.committed.get(group, key)
+    }
+    fn retain(&mut self, policy: &RetentionPolicy, now_ms: u64) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for (key, log) in self.logs.iter_mut() {
+            let expired = policy.expired(log, self.committed.lowest(key), now_ms);
+            removed += expired.len();
+            log.retain(|e| !expired.contains(&e.offset));
+        }
+        Ok(removed)
+    }
+    fn keys(&self) -> Vec<String> {
+        let keys: BTreeSet<&str> = self
+            .logs
+            .keys()
+            .map(|k| k.as_str())
+            .chain(self.committed.iter().map(|(_, key, _)| key))
+            .collect();
+        keys.into_iter().map(|k| k.to_string()).collect()
+    }
+    fn export(&self, key: &str) -> anyhow::Result<KeyData> {
+        Ok(KeyData {
+            key: key.to_string(),
+            entries: self.logs.get(key).cloned().unwrap_or_default(),
+            committed: self.committed.for_key(key),
+        })
+    }
+    fn import(&mut self, data: KeyData) -> anyhow::Result<()> {
+        let log = self.logs.entry(data.key.clone()).or_default();
+        let last = log.last().map(|e| e.offset).unwrap_or(0);
+        log.extend(data.entries.into_iter().filter(|e| e.offset > last));
+        for (group, offset) in data.committed {
+            self.committed.commit(&group, &data.key, offset);
+        }
+        Ok(())
+    }
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.logs.remove(key);
+        self.committed.remove_key(key);
+        Ok(())
+    }
+    fn snapshot(&self) -> Option<&MemoryLog> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+fn committed_policy() -> RetentionPolicy {
+    RetentionPolicy {
+        delete_committed: true,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn retention_and_compaction() -> anyhow::Result<()> {
+    let mut log = MemoryLog::default();
+    for (msg, sub_key) in [(1, "a"), (2, "b"), (3, "a"), (4, "c"), (5, "a")] {
+        log.append("k", msg, Some(sub_key))?;
+    }
+    log.append("k", 6, None)?;
+
+    let compact = RetentionPolicy {
+        compact: true,
+        ..Default::default()
+    };
+    assert_eq!(log.retain(&compact, 0)?, 2);
+    assert_eq!(log.read("k", 0)?, vec![(2, 2), (4, 4), (5, 5), (6, 6)]);
+
+    log.commit(DEFAULT_GROUP, "k", 5)?;
+    log.commit("g1", "k", 3)?;
+    log.retain(&committed_policy(), 0)?;
+    assert_eq!(log.read("k", 0)?[0], (4, 4));
+    log.commit("g1", "k", 6)?;
+    log.retain(&committed_policy(), 0)?;
+    // Offset 3 is gone, so the poll starts from the earliest one kept
+    assert_eq!(log.read("k", 3)?, vec![(5, 5), (6, 6)]);
+
+    let by_count = RetentionPolicy {
+        max_messages: Some(0),
+        max_age: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    log.retain(&by_count, u64::MAX)?;
+    assert_eq!(log.read("k", 0)?, vec![(6, 6)]);
+    assert_eq!(log.append("k", 7, None)?, 7);
+    Ok(())
+}
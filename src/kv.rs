@@ -0,0 +1,74 @@
+use crate::msg::{error_code, Payload};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// A lin-kv operation, as it's stored in the replicated log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+pub enum KvCommand {
+    Read { key: usize },
+    Write { key: usize, value: usize },
+    Cas { key: usize, from: usize, to: usize },
+}
+
+impl KvCommand {
+    // The command for a client request, if it's a lin-kv one
+    pub fn from_payload(payload: &Payload) -> Option<Self> {
+        match payload {
+            Payload::Read { key: Some(key) } => Some(KvCommand::Read { key: *key }),
+            Payload::Write { key, value } => Some(KvCommand::Write {
+                key: *key,
+                value: *value,
+            }),
+            Payload::Cas { key, from, to } => Some(KvCommand::Cas {
+                key: *key,
+                from: *from,
+                to: *to,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct KvStore {
+    values: HashMap<usize, usize>,
+}
+
+impl KvStore {
+    // Apply command, returning the reply for the client that sent it
+    pub fn apply(&mut self, command: &KvCommand) -> Payload {
+        match command {
+            KvCommand::Read { key } => match self.values.get(key) {
+                Some(value) => Payload::ReadOk {
+                    messages: None,
+                    value: Some(*value),
+                },
+                None => missing(*key),
+            },
+            KvCommand::Write { key, value } => {
+                self.values.insert(*key, *value);
+                Payload::WriteOk
+            }
+            KvCommand::Cas { key, from, to } => match self.values.get_mut(key) {
+                Some(value) if *value == *from => {
+                    *value = *to;
+                    Payload::CasOk
+                }
+                Some(value) => Payload::Error {
+                    code: error_code::PRECONDITION_FAILED,
+                    text: format!("expected {} but key {} is {}", from, key, value),
+                },
+                None => missing(*key),
+            },
+        }
+    }
+}
+
+fn missing(key: usize) -> Payload {
+    Payload::Error {
+        code: error_code::KEY_DOES_NOT_EXIST,
+        text: format!("key {} doesn't exist", key),
+    }
+}
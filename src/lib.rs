@@ -1,10 +1,42 @@
+#![allow(non_snake_case)]
+
 pub mod CountNode;
 pub mod EchoNode;
 pub mod KafkaNode;
+pub mod LinKvNode;
+pub mod TxnNode;
+pub mod broadcast;
+pub mod clock;
+pub mod cluster;
+pub mod codec;
+pub mod consensus;
+pub mod dedup;
+pub mod failure;
+pub mod groups;
+pub mod harness;
+pub mod ids;
+pub mod kafka_log;
+pub mod kv;
+pub mod membership;
 pub mod msg;
+pub mod paxos;
+pub mod raft;
+pub mod runtime;
+pub mod sim;
+pub mod sink;
+pub mod snapshot;
+pub mod transport;
+pub mod txn;
+pub mod wal;
+pub mod writer;
 
 #[test]
 fn func_test() -> anyhow::Result<()> {
+    use crate::msg::{Event, Message};
+    use crate::sink::StdoutSink;
+    use crate::EchoNode::EchoNode;
+    use anyhow::Context;
+
     //{"id":5,"src":"c2","dest":"n0","body":{"type":"topology","topology":{"n0":["n1"],"n1":["n0"]},"msg_id":1}}
     //{"id":12,"src":"c4","dest":"n0","body":{"type":"broadcast","message":1,"msg_id":2}}
     let init_msg: Message = serde_json::from_str(
@@ -12,9 +44,7 @@ fn func_test() -> anyhow::Result<()> {
     )
     .context("failed to deserialize init")?;
 
-    let stdout = std::io::stdout().lock();
-    let (tx, rx) = channel();
-    let mut state = EchoNode::new(Event::Message(init_msg), stdout, tx.clone());
+    let _state = EchoNode::new(Event::Message(init_msg), Box::new(StdoutSink::stdout()))?;
 
     //drop(stdin);
     //drop(stdin);
@@ -42,3 +72,17 @@ fn func_test() -> anyhow::Result<()> {
     */
     Ok(())
 }
+
+#[test]
+fn new_rejects_non_init() {
+    use crate::msg::{Event, Message};
+    use crate::sink::VecSink;
+    use crate::CountNode::CountNode;
+
+    let echo: Message = serde_json::from_str(
+        "{\"src\": \"c1\",\"dest\": \"n0\",\"body\": {\"type\": \"echo\", \"msg_id\": 2, \"echo\": \"Please echo 35\"}}"
+    )
+    .unwrap();
+    assert!(CountNode::new(Event::Message(echo), Box::new(VecSink::default())).is_err());
+    assert!(CountNode::new(Event::EOF, Box::new(VecSink::default())).is_err());
+}
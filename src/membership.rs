@@ -0,0 +1,496 @@
+use crate::msg::Payload;
+use log::{debug, info};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+pub const SWIM_ENV: &str = "FLY_SWIM";
+pub const SWIM_PROBE_MS_ENV: &str = "FLY_SWIM_PROBE_MS";
+
+// Most membership updates piggybacked on one message
+const MAX_PIGGYBACK: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    // Missed a probe. Still counted as live until the suspicion times out,
+    // giving it the chance to refute.
+    Suspect,
+    Dead,
+}
+
+// What one node believes about another, spread on probe and gossip messages
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemberUpdate {
+    pub node: String,
+    pub state: MemberState,
+    // Only the node itself raises this, to refute being suspected
+    pub incarnation: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SwimConfig {
+    pub probe_interval_ms: u64,
+    // Without an ack this long after a ping, ask others to ping the target
+    pub ack_timeout_ms: u64,
+    pub indirect_probes: usize,
+    pub suspicion_timeout_ms: u64,
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        SwimConfig {
+            probe_interval_ms: 300,
+            ack_timeout_ms: 100,
+            indirect_probes: 3,
+            suspicion_timeout_ms: 1_500,
+        }
+    }
+}
+
+impl SwimConfig {
+    // Config if FLY_SWIM is set, with the probe interval from
+    // FLY_SWIM_PROBE_MS and the other timeouts scaled to match
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let enabled = std::env::var(SWIM_ENV).is_ok_and(|v| v == "1" || v == "true");
+        if !enabled {
+            return Ok(None);
+        }
+        let mut config = SwimConfig::default();
+        if let Ok(ms) = std::env::var(SWIM_PROBE_MS_ENV) {
+            let probe_interval_ms: u64 = ms.parse()?;
+            config = SwimConfig {
+                probe_interval_ms,
+                ack_timeout_ms: probe_interval_ms / 3,
+                suspicion_timeout_ms: probe_interval_ms * 5,
+                ..config
+            };
+        }
+        Ok(Some(config))
+    }
+}
+
+struct Member {
+    state: MemberState,
+    incarnation: u64,
+    changed_ms: u64,
+}
+
+struct Probe {
+    target: String,
+    seq: u64,
+    sent_ms: u64,
+    indirect: bool,
+}
+
+// SWIM membership: every probe interval ping one member, in a shuffled
+// round robin. Without an ack ask a few others to ping it, and without one
+// by the end of the interval suspect it. Suspects are declared dead unless
+// they refute in time. Changes ride along on the probe messages. Like the
+// consensus modules it does no I/O.
+pub struct Swim {
+    id: String,
+    config: SwimConfig,
+    rng: StdRng,
+    incarnation: u64,
+    members: BTreeMap<String, Member>,
+    order: Vec<String>,
+    next_target: usize,
+    probe: Option<Probe>,
+    next_probe_ms: u64,
+    seq: u64,
+    // Our ping seq -> (who asked us to ping, their seq, when we pinged)
+    relays: HashMap<u64, (String, u64, u64)>,
+    // Updates to spread, with how many more messages to put each on
+    updates: Vec<(MemberUpdate, usize)>,
+    outbox: Vec<(String, Payload)>,
+}
+
+impl Swim {
+    pub fn new(id: &str, node_ids: &[String], config: SwimConfig, seed: u64, now_ms: u64) -> Self {
+        let members = node_ids
+            .iter()
+            .filter(|n| *n != id)
+            .map(|n| {
+                let member = Member {
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                    changed_ms: now_ms,
+                };
+                (n.clone(), member)
+            })
+            .collect();
+        Swim {
+            id: id.to_string(),
+            next_probe_ms: now_ms + config.probe_interval_ms,
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            incarnation: 0,
+            members,
+            order: Vec::new(),
+            next_target: 0,
+            probe: None,
+            seq: 0,
+            relays: HashMap::new(),
+            updates: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+    pub fn state(&self, node: &str) -> Option<MemberState> {
+        self.members.get(node).map(|m| m.state)
+    }
+    // Whether node is a member not declared dead. Nodes we've never heard
+    // of aren't live.
+    pub fn is_live(&self, node: &str) -> bool {
+        node == self.id || self.state(node).is_some_and(|s| s != MemberState::Dead)
+    }
+    // Other members not declared dead
+    pub fn live_peers(&self) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, m)| m.state != MemberState::Dead)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+    pub fn tick(&mut self, now_ms: u64) {
+        if let Some(probe) = self.probe.as_mut() {
+            if now_ms >= probe.sent_ms + self.config.probe_interval_ms {
+                let target = probe.target.clone();
+                self.probe = None;
+                if self.state(&target) == Some(MemberState::Alive) {
+                    let incarnation = self.members[&target].incarnation;
+                    info!("{} suspects {}", self.id, target);
+                    self.apply(
+                        MemberUpdate {
+                            node: target,
+                            state: MemberState::Suspect,
+                            incarnation,
+                        },
+                        now_ms,
+                    );
+                }
+            } else if !probe.indirect && now_ms >= probe.sent_ms + self.config.ack_timeout_ms {
+                probe.indirect = true;
+                let (target, seq) = (probe.target.clone(), probe.seq);
+                let mut helpers: Vec<String> = self
+                    .live_peers()
+                    .into_iter()
+                    .filter(|n| *n != target)
+                    .collect();
+                helpers.shuffle(&mut self.rng);
+                helpers.truncate(self.config.indirect_probes);
+                for helper in helpers {
+                    let updates = self.piggyback(&helper);
+                    let target = target.clone();
+                    self.outbox.push((
+                        helper,
+                        Payload::PingReq {
+                            seq,
+                            target,
+                            updates,
+                        },
+                    ));
+                }
+            }
+        }
+        let expired: Vec<MemberUpdate> = self
+            .members
+            .iter()
+            .filter(|(_, m)| {
+                m.state == MemberState::Suspect
+                    && now_ms >= m.changed_ms + self.config.suspicion_timeout_ms
+            })
+            .map(|(node, m)| MemberUpdate {
+                node: node.clone(),
+                state: MemberState::Dead,
+                incarnation: m.incarnation,
+            })
+            .collect();
+        for update in expired {
+            info!("{} declares {} dead", self.id, update.node);
+            self.apply(update, now_ms);
+        }
+        let interval = self.config.probe_interval_ms;
+        self.relays
+            .retain(|_, (_, _, sent)| now_ms < *sent + interval);
+        if self.probe.is_none() && now_ms >= self.next_probe_ms {
+            self.next_probe_ms = now_ms + interval;
+            if let Some(target) = self.next_probe_target() {
+                self.seq += 1;
+                self.probe = Some(Probe {
+                    target: target.clone(),
+                    seq: self.seq,
+                    sent_ms: now_ms,
+                    indirect: false,
+                });
+                self.ping(target, self.seq);
+            }
+        }
+    }
+    // Handle a SWIM payload from another node, returning false for
+    // payloads that aren't SWIM's
+    pub fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool {
+        let updates = match payload {
+            Payload::Ping { updates, .. }
+            | Payload::PingReq { updates, .. }
+            | Payload::PingAck { updates, .. } => updates,
+            _ => return false,
+        };
+        self.absorb(updates, now_ms);
+        match payload {
+            Payload::Ping { seq, .. } => {
+                let updates = self.piggyback(from);
+                self.outbox
+                    .push((from.to_string(), Payload::PingAck { seq: *seq, updates }));
+            }
+            Payload::PingReq { seq, target, .. } => {
+                self.seq += 1;
+                self.relays
+                    .insert(self.seq, (from.to_string(), *seq, now_ms));
+                self.ping(target.clone(), self.seq);
+            }
+            Payload::PingAck { seq, .. } => {
+                if self.probe.as_ref().is_some_and(|p| p.seq == *seq) {
+                    self.probe = None;
+                } else if let Some((requester, their_seq, _)) = self.relays.remove(seq) {
+                    let updates = self.piggyback(&requester);
+                    self.outbox.push((
+                        requester,
+                        Payload::PingAck {
+                            seq: their_seq,
+                            updates,
+                        },
+                    ));
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        std::mem::take(&mut self.outbox)
+    }
+    // Apply updates piggybacked on any message
+    pub fn absorb(&mut self, updates: &[MemberUpdate], now_ms: u64) {
+        for update in updates {
+            self.apply(update.clone(), now_ms);
+        }
+    }
+
+    // Round robin over every other member in a random order, reshuffled each
+    // time round. Dead members stay in it, so they find out they were
+    // declared dead and can refute once a partition heals.
+    fn next_probe_target(&mut self) -> Option<String> {
+        if self.next_target >= self.order.len() {
+            self.order = self.members.keys().cloned().collect();
+            self.order.shuffle(&mut self.rng);
+            self.next_target = 0;
+        }
+        let target = self.order.get(self.next_target).cloned();
+        self.next_target += 1;
+        target
+    }
+    fn ping(&mut self, target: String, seq: u64) {
+        let updates = self.piggyback(&target);
+        self.outbox.push((target, Payload::Ping { seq, updates }));
+    }
+    // Updates to send to dest, least sent first. A node we think is
+    // suspect or dead is always told, so it can refute.
+    pub fn piggyback(&mut self, dest: &str) -> Vec<MemberUpdate> {
+        let mut updates = Vec::new();
+        if let Some(m) = self.members.get(dest) {
+            if m.state != MemberState::Alive {
+                updates.push(MemberUpdate {
+                    node: dest.to_string(),
+                    state: m.state,
+                    incarnation: m.incarnation,
+                });
+            }
+        }
+        self.updates
+            .sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+        for (update, remaining) in self.updates.iter_mut() {
+            if updates.len() >= MAX_PIGGYBACK {
+                break;
+            }
+            if update.node != dest || update.state == MemberState::Alive {
+                updates.push(update.clone());
+                *remaining -= 1;
+            }
+        }
+        self.updates.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+    // Each update goes out on about 3 log2(n) messages
+    fn spread(&mut self, update: MemberUpdate) {
+        let n = self.members.len() + 1;
+        let times = 3 * (usize::BITS - n.leading_zeros()) as usize;
+        self.updates.retain(|(u, _)| u.node != update.node);
+        self.updates.push((update, times));
+    }
+    fn apply(&mut self, update: MemberUpdate, now_ms: u64) {
+        if update.node == self.id {
+            // Refute anything but alive with a new incarnation
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                debug!("{} refutes {:?}", self.id, update);
+                self.spread(MemberUpdate {
+                    node: self.id.clone(),
+                    state: MemberState::Alive,
+                    incarnation: self.incarnation,
+                });
+            }
+            return;
+        }
+        let newer = match self.members.get(&update.node) {
+            None => true,
+            Some(m) => match update.state {
+                MemberState::Alive => update.incarnation > m.incarnation,
+                MemberState::Suspect => {
+                    update.incarnation > m.incarnation
+                        || (update.incarnation == m.incarnation && m.state == MemberState::Alive)
+                }
+                MemberState::Dead => {
+                    update.incarnation > m.incarnation
+                        || (update.incarnation == m.incarnation && m.state != MemberState::Dead)
+                }
+            },
+        };
+        if !newer {
+            return;
+        }
+        debug!("{} applies {:?}", self.id, update);
+        self.members.insert(
+            update.node.clone(),
+            Member {
+                state: update.state,
+                incarnation: update.incarnation,
+                changed_ms: now_ms,
+            },
+        );
+        self.spread(update);
+    }
+}
+
+impl crate::sim::Process for Swim {
+    fn handle(&mut self, from: &str, payload: Payload, now_ms: u64) {
+        Swim::handle(self, from, &payload, now_ms);
+    }
+    fn tick(&mut self, now_ms: u64) {
+        Swim::tick(self, now_ms)
+    }
+    fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        Swim::take_messages(self)
+    }
+}
+
+// Swim on the node's wall clock, for the nodes to hold
+pub struct Membership {
+    swim: Swim,
+    start: Instant,
+}
+
+impl Membership {
+    // Membership if FLY_SWIM is set
+    pub fn from_env(node_id: &str, node_ids: &[String]) -> anyhow::Result<Option<Self>> {
+        let Some(config) = SwimConfig::from_env()? else {
+            return Ok(None);
+        };
+        Ok(Some(Membership {
+            swim: Swim::new(node_id, node_ids, config, rand::random(), 0),
+            start: Instant::now(),
+        }))
+    }
+    pub fn swim(&self) -> &Swim {
+        &self.swim
+    }
+    pub fn is_live(&self, node: &str) -> bool {
+        self.swim.is_live(node)
+    }
+    pub fn handle(&mut self, from: &str, payload: &Payload) -> bool {
+        let now_ms = self.now_ms();
+        self.swim.handle(from, payload, now_ms)
+    }
+    pub fn tick(&mut self) {
+        let now_ms = self.now_ms();
+        self.swim.tick(now_ms)
+    }
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        self.swim.take_messages()
+    }
+    // Updates for the node's own messages to dest to carry
+    pub fn piggyback(&mut self, dest: &str) -> Vec<MemberUpdate> {
+        self.swim.piggyback(dest)
+    }
+    pub fn absorb(&mut self, updates: &[MemberUpdate]) {
+        let now_ms = self.now_ms();
+        self.swim.absorb(updates, now_ms)
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+#[test]
+fn swim_declares_a_cut_off_node_dead_and_it_refutes() {
+    use crate::sim::Sim;
+
+    let ids: Vec<String> = (0..4).map(|i| format!("n{}", i)).collect();
+    let nodes = ids.iter().enumerate().map(|(i, id)| {
+        let swim = Swim::new(id, &ids, SwimConfig::default(), i as u64, 0);
+        (id.clone(), swim)
+    });
+    let mut sim = Sim::new(nodes, 11);
+    sim.run_for(2_000);
+    assert!(sim.nodes().all(|(_, s)| s.live_peers().len() == 3));
+
+    sim.partition(&["n3"]);
+    let others = ["n0", "n1", "n2"];
+    let dead = |s: &Sim<Swim>| {
+        others
+            .iter()
+            .all(|n| s.node(n).state("n3") == Some(MemberState::Dead))
+    };
+    assert!(sim.run_until(10_000, dead));
+    assert!(others.iter().all(|n| !sim.node(n).is_live("n3")));
+    assert_eq!(sim.node("n0").live_peers(), vec!["n1", "n2"]);
+
+    // Back from the partition, n3 hears it was declared dead and refutes
+    sim.heal();
+    let alive = |s: &Sim<Swim>| {
+        s.nodes()
+            .all(|(_, swim)| ids.iter().all(|n| swim.is_live(n)))
+    };
+    assert!(sim.run_until(10_000, alive));
+    assert!(sim.node("n3").incarnation() > 0);
+}
+
+#[test]
+fn swim_updates_spread_on_gossip() {
+    let ids: Vec<String> = (0..3).map(|i| format!("n{}", i)).collect();
+    let mut swims: Vec<Swim> = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| Swim::new(id, &ids, SwimConfig::default(), i as u64, 0))
+        .collect();
+    let suspect = MemberUpdate {
+        node: "n2".to_string(),
+        state: MemberState::Suspect,
+        incarnation: 0,
+    };
+    swims[0].absorb(std::slice::from_ref(&suspect), 0);
+
+    // n1 hears of it from n0's gossip without any ping between them
+    let updates = swims[0].piggyback("n1");
+    assert!(updates.contains(&suspect));
+    swims[1].absorb(&updates, 0);
+    assert_eq!(swims[1].state("n2"), Some(MemberState::Suspect));
+}
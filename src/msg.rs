@@ -1,18 +1,21 @@
-use anyhow::{bail, Context};
-use log::{debug, error};
-use rand::seq::SliceRandom;
-
+use crate::broadcast::CausalMessage;
+use crate::clock::ClockStamp;
+use crate::groups::AssignmentStrategy;
+use crate::ids::GeneratedId;
+use crate::kafka_log::{BatchMessage, KeyData};
+use crate::membership::MemberUpdate;
+use crate::paxos::{AcceptedSlot, PaxosValue};
+use crate::raft::LogEntry;
+use crate::txn::{MicroOp, ReplicatedWrites};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::collections::HashSet;
-use std::io::{StdoutLock, Write};
-use std::thread;
-use std::time::Duration;
-use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Injected {
     GossipNow,
+    // From the failure detector, when a peer's phi crosses the threshold
+    PeerSuspected(String),
+    PeerRecovered(String),
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Event<Message, Injected = ()> {
@@ -32,6 +35,9 @@ pub struct Message {
 pub struct Body {
     pub msg_id: Option<usize>,
     pub in_reply_to: Option<usize>,
+    // Set by MessageClock on messages between nodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockStamp>,
     #[serde(flatten)]
     pub payload: Payload,
 }
@@ -44,15 +50,29 @@ pub enum Payload {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
-    Read,
-    #[serde(rename = "read_ok")]
-    ReadOkEcho {
-        messages: Vec<usize>,
+    Read {
+        // Only lin-kv reads have a key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<usize>,
+    },
+    // Broadcast reads fill in messages; counter and lin-kv reads, value
+    ReadOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        messages: Option<Vec<usize>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<usize>,
     },
-    #[serde(rename = "read_ok")]
-    ReadOkCount {
+    Write {
+        key: usize,
         value: usize,
     },
+    WriteOk,
+    Cas {
+        key: usize,
+        from: usize,
+        to: usize,
+    },
+    CasOk,
     Add {
         delta: usize,
     },
@@ -63,13 +83,21 @@ pub enum Payload {
     BroadcastOk,
     GossipEcho {
         ids: Vec<usize>,
+        // In causal mode, each of ids with what it depends on
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        causal: Vec<CausalMessage>,
+        // Membership updates riding along, when FLY_SWIM is set
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<MemberUpdate>,
     },
     GossipCount {
         adds: Vec<(String, usize, usize)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        updates: Vec<MemberUpdate>,
     },
     Generate,
     GenerateOk {
-        id: String,
+        id: GeneratedId,
     },
     Echo {
         echo: String,
@@ -85,10 +113,21 @@ pub enum Payload {
     Send {
         key: String,
         msg: usize,
+        // Extension: with compaction only the latest message per sub-key is kept
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
     },
     SendOk {
         offset: usize,
     },
+    // Extension: append to several keys at once, getting back every
+    // message's offset or an error with none of them appended
+    SendBatch {
+        msgs: Vec<BatchMessage>,
+    },
+    SendBatchOk {
+        offsets: Vec<usize>,
+    },
     Poll {
         offsets: HashMap<String, usize>,
     },
@@ -97,12 +136,201 @@ pub enum Payload {
     },
     CommitOffsets {
         offsets: HashMap<String, usize>,
+        // Extension: offsets are tracked per consumer group
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, usize>,
     },
+    // Extension: consumer group membership, handled by the group's coordinator
+    JoinGroup {
+        group: String,
+        member: String,
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        strategy: Option<AssignmentStrategy>,
+    },
+    JoinGroupOk {
+        generation: usize,
+        keys: Vec<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member: String,
+    },
+    LeaveGroupOk {
+        generation: usize,
+    },
+    SyncGroup {
+        group: String,
+        member: String,
+    },
+    SyncGroupOk {
+        generation: usize,
+        keys: Vec<String>,
+    },
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    // Between TxnNodes: a snapshot isolation transaction's writes, sent to
+    // the certifier to commit or reject with txn-conflict
+    CommitTxn {
+        start_ts: u64,
+        writes: Vec<(usize, usize)>,
+    },
+    // seq is the commit's place in the certifier's stream of writes
+    CommitTxnOk {
+        commit_ts: u64,
+        seq: usize,
+    },
+    // Between TxnNodes: writes made on src, acknowledged up to seq
+    ReplicateWrites {
+        writes: Vec<ReplicatedWrites>,
+    },
+    ReplicateWritesOk {
+        seq: usize,
+    },
+    // Between Raft peers
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        // On success the last index that matches the leader's log, otherwise
+        // a hint of where to retry from
+        match_index: u64,
+    },
+    Prepare {
+        ballot: u64,
+        // The first slot the proposer doesn't know the value of
+        from_slot: u64,
+    },
+    Promise {
+        ballot: u64,
+        ok: bool,
+        // The highest ballot the acceptor has promised, so a refused
+        // proposer knows what to beat
+        promised: u64,
+        accepted: Vec<AcceptedSlot>,
+        // Slots from from_slot on the acceptor already knows were decided
+        decided: Vec<(u64, PaxosValue)>,
+    },
+    Accept {
+        ballot: u64,
+        slot: u64,
+        value: PaxosValue,
+    },
+    Accepted {
+        ballot: u64,
+        slot: u64,
+        ok: bool,
+        promised: u64,
+    },
+    Decide {
+        decided: Vec<(u64, PaxosValue)>,
+    },
+    PaxosHeartbeat {
+        ballot: u64,
+        sent_ms: u64,
+        chosen_up_to: u64,
+    },
+    PaxosHeartbeatOk {
+        ballot: u64,
+        // Echoed from the heartbeat, for the leader's lease
+        sent_ms: u64,
+        chosen_up_to: u64,
+    },
+    Ping {
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    },
+    // Ask another node to ping target for us
+    PingReq {
+        seq: u64,
+        target: String,
+        updates: Vec<MemberUpdate>,
+    },
+    PingAck {
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    },
+    // Sent and consumed by the runtime for the failure detector
+    Heartbeat,
+    // Sent by a client to have the node it's sent to join or leave the
+    // cluster, answered once the node is in or out
+    JoinCluster,
+    JoinClusterOk {
+        epoch: u64,
+    },
+    LeaveCluster,
+    LeaveClusterOk {
+        epoch: u64,
+    },
+    // A node asking the cluster coordinator to add or remove it
+    Join {
+        node: String,
+    },
+    Leave {
+        node: String,
+    },
+    ViewChange {
+        epoch: u64,
+        members: Vec<String>,
+        previous: Vec<String>,
+    },
+    // The sender has every key it owns in epoch
+    ViewChangeOk {
+        epoch: u64,
+    },
+    // Keys the sender no longer owns as of epoch, possibly none
+    TransferKeys {
+        epoch: u64,
+        keys: Vec<KeyData>,
+    },
+    TransferKeysOk {
+        epoch: u64,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+// Maelstrom's standard error codes
+pub mod error_code {
+    pub const TIMEOUT: usize = 0;
+    pub const NODE_NOT_FOUND: usize = 1;
+    pub const NOT_SUPPORTED: usize = 10;
+    pub const TEMPORARILY_UNAVAILABLE: usize = 11;
+    pub const MALFORMED_REQUEST: usize = 12;
+    pub const CRASH: usize = 13;
+    pub const ABORT: usize = 14;
+    pub const KEY_DOES_NOT_EXIST: usize = 20;
+    pub const KEY_ALREADY_EXISTS: usize = 21;
+    pub const PRECONDITION_FAILED: usize = 22;
+    pub const TXN_CONFLICT: usize = 30;
 }
@@ -0,0 +1,632 @@
+use crate::msg::Payload;
+use log::{debug, error, info};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// Most decided slots sent to a lagging node in one decide message
+const MAX_DECIDE: usize = 64;
+
+// A value for a slot. proposed_in is the ballot it was first proposed in,
+// kept when a later leader proposes it again, so whoever proposed it can
+// tell it was theirs that was chosen.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PaxosValue {
+    pub proposed_in: u64,
+    // None for the no-ops a new leader fills gaps with
+    pub command: Option<serde_json::Value>,
+}
+
+// A value an acceptor has accepted, reported in its promise
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AcceptedSlot {
+    pub slot: u64,
+    pub ballot: u64,
+    pub value: PaxosValue,
+}
+
+#[derive(Clone, Debug)]
+pub struct PaxosConfig {
+    // Without a heartbeat for a random time in this range a node tries to
+    // become leader. The low end should be above lease_ms.
+    pub election_timeout_ms: (u64, u64),
+    pub heartbeat_ms: u64,
+    // How long a leader's heartbeat holds off other would-be leaders, and
+    // how long a leader keeps going without a quorum answering it
+    pub lease_ms: u64,
+}
+
+impl Default for PaxosConfig {
+    fn default() -> Self {
+        PaxosConfig {
+            election_timeout_ms: (400, 700),
+            heartbeat_ms: 50,
+            lease_ms: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    // Waiting for a quorum of promises for ballot
+    Preparing,
+    Leader,
+}
+
+// Multi-Paxos with a stable leader. Like Raft it does no I/O itself:
+// the owner passes in ticks and payloads and sends what take_messages
+// returns. Ballots are round * cluster size + this node's index, so every
+// node's are distinct.
+pub struct Paxos {
+    id: String,
+    index: u64,
+    peers: Vec<String>,
+    cluster: u64,
+    config: PaxosConfig,
+    rng: StdRng,
+    role: Role,
+    leader: Option<String>,
+    // Acceptor state
+    promised: u64,
+    accepted: BTreeMap<u64, (u64, PaxosValue)>,
+    // Learner state
+    chosen: BTreeMap<u64, PaxosValue>,
+    first_unchosen: u64,
+    last_applied: u64,
+    // Proposer state
+    ballot: u64,
+    // The highest ballot we've heard of, to pick the next one above
+    seen: u64,
+    promises: HashMap<String, Vec<AcceptedSlot>>,
+    next_slot: u64,
+    // Slot -> value and who has accepted it, for this ballot
+    proposals: BTreeMap<u64, (PaxosValue, Vec<String>)>,
+    // Peer -> send time of the newest heartbeat it answered
+    heartbeat_acks: HashMap<String, u64>,
+    lease_until: u64,
+    last_heard: u64,
+    election_deadline: u64,
+    heartbeat_due: u64,
+    outbox: Vec<(String, Payload)>,
+}
+
+impl Paxos {
+    pub fn new(id: &str, node_ids: &[String], config: PaxosConfig, seed: u64, now_ms: u64) -> Self {
+        let mut sorted: Vec<&String> = node_ids.iter().collect();
+        sorted.sort();
+        let index = sorted.iter().position(|n| *n == id).unwrap_or(0) as u64;
+        let mut paxos = Paxos {
+            id: id.to_string(),
+            index,
+            peers: node_ids.iter().filter(|n| *n != id).cloned().collect(),
+            cluster: node_ids.len().max(1) as u64,
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            role: Role::Follower,
+            leader: None,
+            promised: 0,
+            accepted: BTreeMap::new(),
+            chosen: BTreeMap::new(),
+            first_unchosen: 1,
+            last_applied: 0,
+            ballot: 0,
+            seen: 0,
+            promises: HashMap::new(),
+            next_slot: 1,
+            proposals: BTreeMap::new(),
+            heartbeat_acks: HashMap::new(),
+            lease_until: 0,
+            last_heard: now_ms,
+            election_deadline: 0,
+            heartbeat_due: 0,
+            outbox: Vec::new(),
+        };
+        paxos.reset_election_deadline(now_ms);
+        paxos
+    }
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+    pub fn leader(&self) -> Option<&String> {
+        self.leader.as_ref()
+    }
+    pub fn ballot(&self) -> u64 {
+        self.ballot
+    }
+    // Whether we lead and a quorum has heard from us within the lease, so
+    // no other leader can have been elected
+    pub fn has_lease(&self, now_ms: u64) -> bool {
+        self.is_leader() && now_ms < self.lease_until
+    }
+    // The highest slot such that it and every slot before it is chosen
+    pub fn chosen_up_to(&self) -> u64 {
+        self.first_unchosen - 1
+    }
+    // Propose command in the next slot if we lead, returning the slot and
+    // our ballot. Otherwise returns the leader to try instead.
+    pub fn propose(&mut self, command: serde_json::Value) -> Result<(u64, u64), Option<String>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        let value = PaxosValue {
+            proposed_in: self.ballot,
+            command: Some(command),
+        };
+        self.start_accept(slot, value);
+        Ok((slot, self.ballot))
+    }
+    pub fn tick(&mut self, now_ms: u64) {
+        match self.role {
+            Role::Leader => {
+                if now_ms >= self.lease_until {
+                    info!("{} lost its lease, stepping down", self.id);
+                    self.step_down(now_ms);
+                } else if now_ms >= self.heartbeat_due {
+                    self.heartbeat_due = now_ms + self.config.heartbeat_ms;
+                    self.broadcast_heartbeat(now_ms);
+                    // Accepts might have been lost, so send them again
+                    let in_flight: Vec<(u64, PaxosValue)> = self
+                        .proposals
+                        .iter()
+                        .map(|(slot, (value, _))| (*slot, value.clone()))
+                        .collect();
+                    for (slot, value) in in_flight {
+                        self.broadcast_accept(slot, &value);
+                    }
+                }
+            }
+            Role::Follower | Role::Preparing if now_ms >= self.election_deadline => {
+                self.start_prepare(now_ms);
+            }
+            _ => {}
+        }
+    }
+    // Handle a Paxos payload from another node, returning false for
+    // payloads that aren't Paxos's
+    pub fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool {
+        match payload {
+            Payload::Prepare { ballot, from_slot } => {
+                self.seen = self.seen.max(*ballot);
+                // A live leader's lease holds off everyone else
+                let leased = match self.role {
+                    Role::Leader => now_ms < self.lease_until,
+                    _ => {
+                        self.leader.as_deref().is_some_and(|l| l != from)
+                            && now_ms < self.last_heard + self.config.lease_ms
+                    }
+                };
+                let ok = *ballot > self.promised && !leased;
+                let (mut accepted, mut decided) = (Vec::new(), Vec::new());
+                if ok {
+                    self.promised = *ballot;
+                    if self.role != Role::Follower {
+                        self.step_down(now_ms);
+                    }
+                    accepted = self.accepted_from(*from_slot);
+                    // Accepted values are dropped once decided, so send
+                    // those instead
+                    decided = self
+                        .chosen
+                        .range(*from_slot..)
+                        .map(|(slot, value)| (*slot, value.clone()))
+                        .collect();
+                }
+                self.outbox.push((
+                    from.to_string(),
+                    Payload::Promise {
+                        ballot: *ballot,
+                        ok,
+                        promised: self.promised,
+                        accepted,
+                        decided,
+                    },
+                ));
+            }
+            Payload::Promise {
+                ballot,
+                ok,
+                promised,
+                accepted,
+                decided,
+            } => {
+                self.seen = self.seen.max(*promised);
+                if self.role != Role::Preparing || *ballot != self.ballot || !ok {
+                    return true;
+                }
+                for (slot, value) in decided {
+                    self.decide(*slot, value.clone());
+                }
+                self.promises.insert(from.to_string(), accepted.clone());
+                // Our own promise makes up the quorum
+                if self.promises.len() + 1 >= self.quorum() {
+                    self.become_leader(now_ms);
+                }
+            }
+            Payload::Accept {
+                ballot,
+                slot,
+                value,
+            } => {
+                let ok = *ballot >= self.promised;
+                if ok {
+                    self.promised = *ballot;
+                    if self.role != Role::Follower {
+                        self.step_down(now_ms);
+                    }
+                    self.accepted.insert(*slot, (*ballot, value.clone()));
+                    self.heard_from(from, now_ms);
+                }
+                self.outbox.push((
+                    from.to_string(),
+                    Payload::Accepted {
+                        ballot: *ballot,
+                        slot: *slot,
+                        ok,
+                        promised: self.promised,
+                    },
+                ));
+            }
+            Payload::Accepted {
+                ballot,
+                slot,
+                ok,
+                promised,
+            } => {
+                if self.role != Role::Leader || *ballot != self.ballot {
+                    return true;
+                }
+                if !ok {
+                    debug!("{} was preempted by ballot {}", self.id, promised);
+                    self.seen = self.seen.max(*promised);
+                    self.step_down(now_ms);
+                    return true;
+                }
+                let quorum = self.quorum();
+                let Some((value, acceptors)) = self.proposals.get_mut(slot) else {
+                    return true;
+                };
+                if !acceptors.iter().any(|a| a == from) {
+                    acceptors.push(from.to_string());
+                }
+                if acceptors.len() >= quorum {
+                    let value = value.clone();
+                    self.proposals.remove(slot);
+                    self.decide(*slot, value.clone());
+                    for peer in self.peers.clone() {
+                        self.outbox.push((
+                            peer,
+                            Payload::Decide {
+                                decided: vec![(*slot, value.clone())],
+                            },
+                        ));
+                    }
+                }
+            }
+            Payload::Decide { decided } => {
+                for (slot, value) in decided {
+                    self.decide(*slot, value.clone());
+                }
+            }
+            Payload::PaxosHeartbeat {
+                ballot,
+                sent_ms,
+                chosen_up_to,
+            } => {
+                if *ballot < self.promised {
+                    return true;
+                }
+                self.promised = *ballot;
+                if self.role != Role::Follower {
+                    self.step_down(now_ms);
+                }
+                self.heard_from(from, now_ms);
+                // If we're behind the leader sends what we've missed
+                if *chosen_up_to > self.chosen_up_to() {
+                    debug!("{} is behind {} at {}", self.id, from, self.chosen_up_to());
+                }
+                self.outbox.push((
+                    from.to_string(),
+                    Payload::PaxosHeartbeatOk {
+                        ballot: *ballot,
+                        sent_ms: *sent_ms,
+                        chosen_up_to: self.chosen_up_to(),
+                    },
+                ));
+            }
+            Payload::PaxosHeartbeatOk {
+                ballot,
+                sent_ms,
+                chosen_up_to,
+            } => {
+                if self.role != Role::Leader || *ballot != self.ballot {
+                    return true;
+                }
+                let acked = self.heartbeat_acks.entry(from.to_string()).or_default();
+                *acked = (*acked).max(*sent_ms);
+                self.renew_lease();
+                self.fill_gaps(from, *chosen_up_to);
+            }
+            _ => return false,
+        }
+        true
+    }
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        std::mem::take(&mut self.outbox)
+    }
+    // Newly chosen commands in slot order as (slot, ballot first proposed
+    // in, command), skipping no-ops
+    pub fn take_committed(&mut self) -> Vec<(u64, u64, serde_json::Value)> {
+        let mut committed = Vec::new();
+        while self.last_applied + 1 < self.first_unchosen {
+            self.last_applied += 1;
+            let value = &self.chosen[&self.last_applied];
+            if let Some(command) = &value.command {
+                committed.push((self.last_applied, value.proposed_in, command.clone()));
+            }
+        }
+        committed
+    }
+
+    fn quorum(&self) -> usize {
+        self.cluster as usize / 2 + 1
+    }
+    fn reset_election_deadline(&mut self, now_ms: u64) {
+        let (min, max) = self.config.election_timeout_ms;
+        self.election_deadline = now_ms + self.rng.gen_range(min..=max);
+    }
+    fn heard_from(&mut self, leader: &str, now_ms: u64) {
+        self.leader = Some(leader.to_string());
+        self.last_heard = now_ms;
+        self.reset_election_deadline(now_ms);
+    }
+    fn step_down(&mut self, now_ms: u64) {
+        self.role = Role::Follower;
+        if self.leader.as_deref() == Some(&self.id) {
+            self.leader = None;
+        }
+        self.proposals.clear();
+        self.promises.clear();
+        self.reset_election_deadline(now_ms);
+    }
+    fn accepted_from(&self, from_slot: u64) -> Vec<AcceptedSlot> {
+        self.accepted
+            .range(from_slot..)
+            .map(|(slot, (ballot, value))| AcceptedSlot {
+                slot: *slot,
+                ballot: *ballot,
+                value: value.clone(),
+            })
+            .collect()
+    }
+    // Our own promise isn't made until we have a quorum of the others, so
+    // a node whose prepare is refused doesn't go on turning away the
+    // leader's accepts
+    fn start_prepare(&mut self, now_ms: u64) {
+        let round = self.promised.max(self.ballot).max(self.seen) / self.cluster + 1;
+        self.ballot = round * self.cluster + self.index;
+        self.role = Role::Preparing;
+        self.leader = None;
+        self.promises.clear();
+        self.reset_election_deadline(now_ms);
+        debug!("{} preparing ballot {}", self.id, self.ballot);
+        if self.quorum() == 1 {
+            self.become_leader(now_ms);
+            return;
+        }
+        for peer in self.peers.clone() {
+            self.outbox.push((
+                peer,
+                Payload::Prepare {
+                    ballot: self.ballot,
+                    from_slot: self.first_unchosen,
+                },
+            ));
+        }
+    }
+    // With a quorum of promises, finish whatever slots earlier leaders may
+    // have got a value chosen in, and fill the gaps with no-ops
+    fn become_leader(&mut self, now_ms: u64) {
+        if self.promised > self.ballot {
+            self.step_down(now_ms);
+            return;
+        }
+        info!("{} became leader with ballot {}", self.id, self.ballot);
+        self.promised = self.ballot;
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.heartbeat_acks.clear();
+        self.lease_until = now_ms + self.config.lease_ms;
+        let own = self.accepted_from(self.first_unchosen);
+        self.promises.insert(self.id.clone(), own);
+        let mut highest: BTreeMap<u64, (u64, PaxosValue)> = BTreeMap::new();
+        for accepted in std::mem::take(&mut self.promises).into_values().flatten() {
+            match highest.get(&accepted.slot) {
+                Some((ballot, _)) if *ballot >= accepted.ballot => {}
+                _ => {
+                    highest.insert(accepted.slot, (accepted.ballot, accepted.value));
+                }
+            }
+        }
+        let last = highest
+            .keys()
+            .chain(self.chosen.keys())
+            .max()
+            .cloned()
+            .unwrap_or(0)
+            .max(self.first_unchosen - 1);
+        for slot in self.first_unchosen..=last {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let value = match highest.remove(&slot) {
+                Some((_, value)) => value,
+                None => PaxosValue {
+                    proposed_in: self.ballot,
+                    command: None,
+                },
+            };
+            self.start_accept(slot, value);
+        }
+        self.next_slot = last + 1;
+        self.heartbeat_due = now_ms + self.config.heartbeat_ms;
+        self.broadcast_heartbeat(now_ms);
+    }
+    fn start_accept(&mut self, slot: u64, value: PaxosValue) {
+        // Accept it ourselves first
+        self.accepted.insert(slot, (self.ballot, value.clone()));
+        self.proposals
+            .insert(slot, (value.clone(), vec![self.id.clone()]));
+        if self.quorum() == 1 {
+            self.proposals.remove(&slot);
+            self.decide(slot, value);
+            return;
+        }
+        self.broadcast_accept(slot, &value);
+    }
+    fn broadcast_accept(&mut self, slot: u64, value: &PaxosValue) {
+        for peer in self.peers.clone() {
+            self.outbox.push((
+                peer,
+                Payload::Accept {
+                    ballot: self.ballot,
+                    slot,
+                    value: value.clone(),
+                },
+            ));
+        }
+    }
+    fn broadcast_heartbeat(&mut self, now_ms: u64) {
+        if self.peers.is_empty() {
+            self.lease_until = now_ms + self.config.lease_ms;
+        }
+        for peer in self.peers.clone() {
+            self.outbox.push((
+                peer,
+                Payload::PaxosHeartbeat {
+                    ballot: self.ballot,
+                    sent_ms: now_ms,
+                    chosen_up_to: self.chosen_up_to(),
+                },
+            ));
+        }
+    }
+    // The lease runs from the newest heartbeat a quorum, counting us, has
+    // answered
+    fn renew_lease(&mut self) {
+        let mut acks: Vec<u64> = self.heartbeat_acks.values().cloned().collect();
+        acks.sort_unstable_by(|a, b| b.cmp(a));
+        let others = self.quorum().saturating_sub(1);
+        if let Some(sent_ms) = others.checked_sub(1).and_then(|i| acks.get(i)) {
+            self.lease_until = self.lease_until.max(sent_ms + self.config.lease_ms);
+        }
+    }
+    // Send a follower the decided slots it's missing
+    fn fill_gaps(&mut self, peer: &str, chosen_up_to: u64) {
+        let decided: Vec<(u64, PaxosValue)> = self
+            .chosen
+            .range(chosen_up_to + 1..)
+            .take(MAX_DECIDE)
+            .map(|(slot, value)| (*slot, value.clone()))
+            .collect();
+        if !decided.is_empty() {
+            debug!("filling {} slots for {}", decided.len(), peer);
+            self.outbox
+                .push((peer.to_string(), Payload::Decide { decided }));
+        }
+    }
+    fn decide(&mut self, slot: u64, value: PaxosValue) {
+        // A slot is only ever chosen once. A second, different value means
+        // the protocol is broken, so keep the first rather than apply both.
+        if let Some(existing) = self.chosen.get(&slot) {
+            if *existing != value {
+                error!(
+                    "two values chosen for slot {}: kept {:?}, refused {:?}",
+                    slot, existing, value
+                );
+            }
+            return;
+        }
+        self.chosen.insert(slot, value);
+        while self.chosen.contains_key(&self.first_unchosen) {
+            self.first_unchosen += 1;
+        }
+        // Chosen slots don't need their accepted value any more
+        while let Some((slot, _)) = self.accepted.first_key_value() {
+            if *slot >= self.first_unchosen {
+                break;
+            }
+            let slot = *slot;
+            self.accepted.remove(&slot);
+        }
+    }
+}
+
+#[test]
+fn paxos_fills_gaps_and_keeps_its_leader() {
+    use crate::sim::Sim;
+
+    let ids: Vec<String> = (0..3).map(|i| format!("n{}", i)).collect();
+    let nodes = ids.iter().enumerate().map(|(i, id)| {
+        let paxos = Paxos::new(id, &ids, PaxosConfig::default(), i as u64, 0);
+        (id.clone(), paxos)
+    });
+    let mut sim = Sim::new(nodes, 3);
+    let leader = |sim: &Sim<Paxos>| {
+        sim.nodes()
+            .find(|(_, p)| p.is_leader())
+            .map(|(id, _)| id.clone())
+    };
+    assert!(sim.run_until(2_000, |s| leader(s).is_some()));
+    let first = leader(&sim).unwrap();
+    let ballot = sim.node(&first).ballot();
+
+    // A follower misses a few decisions, and its prepares while cut off
+    // don't unseat the leader once it's back
+    let follower = ids.iter().find(|id| **id != first).unwrap().clone();
+    sim.partition(&[&follower]);
+    let mut last = 0;
+    for i in 0..3 {
+        (last, _) = sim.node_mut(&first).propose(serde_json::json!(i)).unwrap();
+    }
+    sim.run_for(1_000);
+    assert!(sim.node(&first).chosen_up_to() >= last);
+    assert!(sim.node(&follower).chosen_up_to() < last);
+    sim.heal();
+    assert!(sim.run_until(1_000, |s| s.node(&follower).chosen_up_to() >= last));
+    assert_eq!(leader(&sim), Some(first.clone()));
+    assert_eq!(sim.node(&first).ballot(), ballot);
+    assert!(sim.node(&first).has_lease(sim.now_ms()));
+    let commands: Vec<_> = sim
+        .node_mut(&follower)
+        .take_committed()
+        .into_iter()
+        .map(|(_, _, command)| command)
+        .collect();
+    assert_eq!(
+        commands,
+        vec![
+            serde_json::json!(0),
+            serde_json::json!(1),
+            serde_json::json!(2)
+        ]
+    );
+}
+
+#[test]
+fn paxos_keeps_the_first_value_decided_for_a_slot() {
+    let ids: Vec<String> = (0..3).map(|i| format!("n{}", i)).collect();
+    let mut paxos = Paxos::new("n1", &ids, PaxosConfig::default(), 1, 0);
+    let value = |command| PaxosValue {
+        proposed_in: 1,
+        command: Some(serde_json::json!(command)),
+    };
+    for command in [1, 2] {
+        let decided = vec![(1, value(command))];
+        assert!(paxos.handle("n0", &Payload::Decide { decided }, 0));
+    }
+    assert_eq!(paxos.take_committed(), vec![(1, 1, serde_json::json!(1))]);
+}
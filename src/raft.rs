@@ -0,0 +1,384 @@
+use crate::msg::Payload;
+use log::{debug, info};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// Most entries sent in one append_entries message
+const MAX_APPEND_ENTRIES: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub term: u64,
+    // None for the no-op a leader appends when it's elected, so entries
+    // from earlier terms can commit
+    pub command: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    // Followers start an election after a random timeout in this range
+    pub election_timeout_ms: (u64, u64),
+    pub heartbeat_ms: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_timeout_ms: (300, 600),
+            heartbeat_ms: 50,
+        }
+    }
+}
+
+// Raft consensus over a log of JSON commands. It does no I/O itself: the
+// owner feeds it ticks and the Raft payloads it receives, sends whatever
+// take_messages returns, and applies whatever take_committed returns in
+// order. Times are milliseconds from any fixed starting point.
+pub struct Raft {
+    id: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    rng: StdRng,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    // Entry i is at index i + 1
+    log: Vec<LogEntry>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: u64,
+    heartbeat_due: u64,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    outbox: Vec<(String, Payload)>,
+}
+
+impl Raft {
+    pub fn new(id: &str, node_ids: &[String], config: RaftConfig, seed: u64, now_ms: u64) -> Self {
+        let mut raft = Raft {
+            id: id.to_string(),
+            peers: node_ids.iter().filter(|n| *n != id).cloned().collect(),
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            election_deadline: 0,
+            heartbeat_due: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            outbox: Vec::new(),
+        };
+        raft.reset_election_deadline(now_ms);
+        raft
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+    // The leader of the current term, if we've heard from one
+    pub fn leader(&self) -> Option<&String> {
+        self.leader.as_ref()
+    }
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+    // Append command to the log if we're the leader, returning the index and
+    // term it'll commit at. Otherwise returns the leader to try instead.
+    pub fn propose(&mut self, command: serde_json::Value) -> Result<(u64, u64), Option<String>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        self.log.push(LogEntry {
+            term: self.term,
+            command: Some(command),
+        });
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok((self.last_index(), self.term))
+    }
+    pub fn tick(&mut self, now_ms: u64) {
+        match self.role {
+            Role::Leader if now_ms >= self.heartbeat_due => {
+                self.heartbeat_due = now_ms + self.config.heartbeat_ms;
+                self.broadcast_append();
+            }
+            Role::Follower | Role::Candidate if now_ms >= self.election_deadline => {
+                self.start_election(now_ms);
+            }
+            _ => {}
+        }
+    }
+    // Handle a Raft payload from another node, returning false for payloads
+    // that aren't Raft's
+    pub fn handle(&mut self, from: &str, payload: &Payload, now_ms: u64) -> bool {
+        let term = match payload {
+            Payload::RequestVote { term, .. }
+            | Payload::RequestVoteOk { term, .. }
+            | Payload::AppendEntries { term, .. }
+            | Payload::AppendEntriesOk { term, .. } => *term,
+            _ => return false,
+        };
+        if term > self.term {
+            self.become_follower(term, None);
+        }
+        match payload {
+            Payload::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (*last_log_term, *last_log_index) >= (self.last_term(), self.last_index());
+                let granted = *term == self.term
+                    && up_to_date
+                    && self.voted_for.as_deref().is_none_or(|v| v == from);
+                if granted {
+                    self.voted_for = Some(from.to_string());
+                    self.reset_election_deadline(now_ms);
+                }
+                self.outbox.push((
+                    from.to_string(),
+                    Payload::RequestVoteOk {
+                        term: self.term,
+                        vote_granted: granted,
+                    },
+                ));
+            }
+            Payload::RequestVoteOk { term, vote_granted } => {
+                if self.role == Role::Candidate && *term == self.term && *vote_granted {
+                    self.votes.insert(from.to_string());
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(now_ms);
+                    }
+                }
+            }
+            Payload::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let reply = if *term < self.term {
+                    (false, 0)
+                } else {
+                    if self.role != Role::Follower {
+                        self.become_follower(*term, self.voted_for.clone());
+                    }
+                    self.leader = Some(from.to_string());
+                    self.reset_election_deadline(now_ms);
+                    self.append_entries(*prev_log_index, *prev_log_term, entries, *leader_commit)
+                };
+                self.outbox.push((
+                    from.to_string(),
+                    Payload::AppendEntriesOk {
+                        term: self.term,
+                        success: reply.0,
+                        match_index: reply.1,
+                    },
+                ));
+            }
+            Payload::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || *term != self.term {
+                    return true;
+                }
+                if *success {
+                    let matched = self.match_index.entry(from.to_string()).or_default();
+                    *matched = (*matched).max(*match_index);
+                    self.next_index.insert(from.to_string(), *matched + 1);
+                    self.maybe_commit();
+                } else {
+                    // match_index is the follower's hint of where its log
+                    // might still agree with ours
+                    let next = self.next_index.entry(from.to_string()).or_insert(1);
+                    *next = (*next - 1).min(*match_index + 1).max(1);
+                    self.send_append(from.to_string());
+                }
+            }
+            _ => unreachable!("not a raft payload"),
+        }
+        true
+    }
+    // Messages to send, as (destination, payload)
+    pub fn take_messages(&mut self) -> Vec<(String, Payload)> {
+        std::mem::take(&mut self.outbox)
+    }
+    // Newly committed commands as (index, term, command), skipping no-ops
+    pub fn take_committed(&mut self) -> Vec<(u64, u64, serde_json::Value)> {
+        let mut committed = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied as usize - 1];
+            if let Some(command) = &entry.command {
+                committed.push((self.last_applied, entry.term, command.clone()));
+            }
+        }
+        committed
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|e| e.term).unwrap_or(0)
+    }
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            i => self.log.get(i as usize - 1).map(|e| e.term),
+        }
+    }
+    fn quorum(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+    fn reset_election_deadline(&mut self, now_ms: u64) {
+        let (min, max) = self.config.election_timeout_ms;
+        self.election_deadline = now_ms + self.rng.gen_range(min..=max);
+    }
+    fn become_follower(&mut self, term: u64, voted_for: Option<String>) {
+        if term > self.term {
+            self.leader = None;
+        }
+        self.role = Role::Follower;
+        self.term = term;
+        self.voted_for = voted_for;
+    }
+    fn start_election(&mut self, now_ms: u64) {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.votes = [self.id.clone()].into_iter().collect();
+        self.reset_election_deadline(now_ms);
+        debug!("{} starting election for term {}", self.id, self.term);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader(now_ms);
+            return;
+        }
+        for peer in self.peers.clone() {
+            self.outbox.push((
+                peer,
+                Payload::RequestVote {
+                    term: self.term,
+                    last_log_index: self.last_index(),
+                    last_log_term: self.last_term(),
+                },
+            ));
+        }
+    }
+    fn become_leader(&mut self, now_ms: u64) {
+        info!("{} became leader for term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.next_index = self
+            .peers
+            .iter()
+            .map(|p| (p.clone(), self.last_index() + 1))
+            .collect();
+        self.match_index = self.peers.iter().map(|p| (p.clone(), 0)).collect();
+        self.log.push(LogEntry {
+            term: self.term,
+            command: None,
+        });
+        self.maybe_commit();
+        self.heartbeat_due = now_ms + self.config.heartbeat_ms;
+        self.broadcast_append();
+    }
+    // Follower side of append_entries, returning whether it matched and the
+    // last index known to agree with the leader
+    fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: &[LogEntry],
+        leader_commit: u64,
+    ) -> (bool, u64) {
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            return (
+                false,
+                self.last_index().min(prev_log_index.saturating_sub(1)),
+            );
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            let index = prev_log_index + 1 + i as u64;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    debug!("{} truncating log from {}", self.id, index);
+                    self.log.truncate(index as usize - 1);
+                }
+                None => {}
+            }
+            self.log.push(entry.clone());
+        }
+        let matched = prev_log_index + entries.len() as u64;
+        self.commit_index = self.commit_index.max(leader_commit.min(matched));
+        (true, matched)
+    }
+    // Commit the newest entry from this term that a quorum has
+    fn maybe_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+    fn send_append(&mut self, peer: String) {
+        let next = self.next_index.get(&peer).cloned().unwrap_or(1);
+        let prev_log_index = next - 1;
+        let entries: Vec<LogEntry> = self
+            .log
+            .iter()
+            .skip(prev_log_index as usize)
+            .take(MAX_APPEND_ENTRIES)
+            .cloned()
+            .collect();
+        self.outbox.push((
+            peer,
+            Payload::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+                entries,
+                leader_commit: self.commit_index,
+            },
+        ));
+    }
+}
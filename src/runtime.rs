@@ -0,0 +1,287 @@
+use crate::clock::MessageClock;
+use crate::dedup::DedupCache;
+use crate::failure::{PhiConfig, PhiDetector};
+use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::sink::{MessageSink, WriteSink};
+use crate::transport::{Outbound, Transport};
+use crate::writer::{BatchWriter, WriteMetrics, WriterConfig};
+use anyhow::Context;
+use log::{debug, error, info};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(30);
+
+// Where a transport takes messages as they're written, one JSON object per
+// line. The transport decides where they go from there.
+pub type Output<'a> = Box<dyn Write + 'a>;
+
+pub trait Node: Sized {
+    fn from_init(
+        init_msg: Event<Message, Injected>,
+        output: Box<dyn MessageSink>,
+    ) -> anyhow::Result<Self>;
+    fn step(&mut self, input: Event<Message, Injected>) -> anyhow::Result<()>;
+    // Step as of now. Nodes with timeouts read the time from here instead
+    // of the system clock, so tests can drive them with simulated time.
+    fn step_at(&mut self, input: Event<Message, Injected>, _now: Instant) -> anyhow::Result<()> {
+        self.step(input)
+    }
+    fn send(&mut self, msg: Message) -> anyhow::Result<()>;
+    // Replies already sent, used to answer client retries without calling step
+    fn dedup(&mut self) -> Option<&mut DedupCache> {
+        None
+    }
+    // The clock stamped on messages to other nodes, which the runtime
+    // advances for every message received
+    fn clock(&mut self) -> Option<&mut MessageClock> {
+        None
+    }
+    // Write out anything still buffered for the output
+    fn flush(&mut self) -> anyhow::Result<()>;
+    // Called once the event loop has stopped and output has been flushed
+    fn on_shutdown(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// Periodically injects an event into the node's event stream until stopped
+// or until the receiving side of the channel has gone away.
+pub struct Timer {
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Timer {
+    pub fn every(
+        interval: Duration,
+        tx: Sender<Event<Message, Injected>>,
+        injected: Injected,
+    ) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = stopped.clone();
+        let handle = thread::spawn(move || loop {
+            thread::sleep(interval);
+            if flag.load(Ordering::Relaxed) {
+                break;
+            }
+            if tx.send(Event::Injected(injected.clone())).is_err() {
+                debug!("event channel closed, stopping timer");
+                break;
+            }
+        });
+        Timer {
+            stopped,
+            handle: Some(handle),
+        }
+    }
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages: usize,
+    pub injected: usize,
+    pub errors: usize,
+    pub replayed: usize,
+    pub heartbeats: usize,
+    pub uptime: Duration,
+    // Zero for transports that carry messages rather than bytes
+    pub writes: WriteMetrics,
+}
+
+// Run a node on the transport from FLY_TRANSPORT
+pub fn run<N: Node>() -> anyhow::Result<()> {
+    run_with::<N>(crate::transport::from_env().context("set up transport")?)
+}
+
+// Run a node until its input ends or the process is signalled
+pub fn run_with<N: Node>(mut transport: Box<dyn Transport>) -> anyhow::Result<()> {
+    let (output, write_metrics): (Box<dyn MessageSink>, _) =
+        match transport.output().context("open output")? {
+            Outbound::Bytes(output) => {
+                let output = BatchWriter::new(output, WriterConfig::from_env()?);
+                let write_metrics = output.metrics();
+                (Box::new(WriteSink::new(output)), Some(write_metrics))
+            }
+            Outbound::Messages(sink) => (sink, None),
+        };
+
+    let (tx, rx) = channel();
+
+    debug!("reading init message");
+
+    let init_msg = transport.init()?;
+
+    // Peers for the failure detector to watch
+    let (node_id, peers) = match init_msg.body.payload {
+        Payload::Init {
+            ref node_id,
+            ref node_ids,
+        } => {
+            let peers: Vec<String> = node_ids.iter().filter(|n| *n != node_id).cloned().collect();
+            (node_id.clone(), peers)
+        }
+        _ => (String::new(), Vec::new()),
+    };
+
+    info!("Creating node");
+    let mut state =
+        N::from_init(Event::Message(init_msg), output).context("failed to create node")?;
+
+    let mut timer = Timer::every(GOSSIP_INTERVAL, tx.clone(), Injected::GossipNow);
+    let detector_tx = tx.clone();
+
+    // SIGTERM/SIGINT go through the same path as EOF on STDIN
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("register signal handlers")?;
+    let signals_handle = signals.handle();
+    let signal_tx = tx.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            info!("received signal {}, shutting down", signal);
+            let _ = signal_tx.send(Event::EOF);
+        }
+    });
+
+    let jh = thread::spawn(move || {
+        let res = transport.receive(tx.clone());
+        let _ = tx.send(Event::EOF);
+        res
+    });
+
+    info!("Deserialising messages");
+    let started = Instant::now();
+    let mut metrics = Metrics::default();
+    let mut detector = PhiConfig::from_env()?.map(|config| PhiDetector::new(&peers, config, 0));
+    let mut last_heartbeat = 0;
+    loop {
+        let input = match rx.try_recv() {
+            Ok(input) => input,
+            Err(TryRecvError::Empty) => {
+                // The batch of events is done, so send what it produced
+                if let Err(e) = state.flush() {
+                    metrics.errors += 1;
+                    error!("flush failed: {:?}", e);
+                }
+                match rx.recv() {
+                    Ok(input) => input,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match input {
+            Event::EOF => break,
+            Event::Message(..) => metrics.messages += 1,
+            Event::Injected(..) => metrics.injected += 1,
+        }
+        // Any message from a peer counts as a heartbeat. The detector's
+        // checks ride on the timer, and the events they produce go into the
+        // channel.
+        if let Some(detector) = detector.as_mut() {
+            let now_ms = started.elapsed().as_millis() as u64;
+            match input {
+                Event::Message(ref msg) => detector.heartbeat(&msg.src, now_ms),
+                Event::Injected(Injected::GossipNow) => {
+                    if now_ms >= last_heartbeat + detector.config().heartbeat_ms {
+                        last_heartbeat = now_ms;
+                        for peer in peers.iter() {
+                            let heartbeat = Message {
+                                src: node_id.clone(),
+                                dest: peer.clone(),
+                                body: Body {
+                                    msg_id: None,
+                                    in_reply_to: None,
+                                    clock: None,
+                                    payload: Payload::Heartbeat,
+                                },
+                            };
+                            if let Err(e) = state.send(heartbeat) {
+                                metrics.errors += 1;
+                                error!("heartbeat failed: {:?}", e);
+                            }
+                        }
+                    }
+                    for event in detector.check(now_ms) {
+                        let _ = detector_tx.send(Event::Injected(event));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Event::Message(ref msg) = input {
+            // Heartbeats stop here, whether or not this node runs the
+            // detector, so nodes never see a peer's
+            if matches!(msg.body.payload, Payload::Heartbeat) {
+                metrics.heartbeats += 1;
+                continue;
+            }
+            if let Some(clock) = state.clock() {
+                clock.receive(msg);
+            }
+            if let Some(reply) = state.dedup().and_then(|d| d.lookup(msg)) {
+                debug!("replaying reply to retried request: {:?}", msg);
+                metrics.replayed += 1;
+                if let Err(e) = state.send(reply) {
+                    metrics.errors += 1;
+                    error!("replay failed: {:?}", e);
+                }
+                continue;
+            }
+        }
+        // A single bad message shouldn't take the whole node down
+        if let Err(e) = state.step(input) {
+            metrics.errors += 1;
+            error!("step failed: {:?}", e);
+        }
+    }
+
+    info!("Shutting down");
+    timer.stop();
+    signals_handle.close();
+    state.flush().context("flush output on shutdown")?;
+    metrics.uptime = started.elapsed();
+    if let Some(write_metrics) = write_metrics {
+        metrics.writes = write_metrics.lock().expect("write metrics lock").clone();
+    }
+    info!("final metrics: {:?}", metrics);
+    state.on_shutdown().context("on_shutdown hook")?;
+
+    // On a signal the reader may still be blocked on its input, so only
+    // join it if it has already finished.
+    if jh.is_finished() {
+        jh.join().expect("jh expect")?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn timer_stops_on_closed_channel() {
+    let (tx, rx) = channel();
+    let mut timer = Timer::every(Duration::from_millis(1), tx, Injected::GossipNow);
+    assert!(matches!(
+        rx.recv().unwrap(),
+        Event::Injected(Injected::GossipNow)
+    ));
+    drop(rx);
+    timer.stop();
+    assert!(timer.handle.is_none());
+}
@@ -0,0 +1,159 @@
+use crate::msg::Payload;
+use log::trace;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashSet};
+
+// Something the simulator can run: it's handed messages and ticks, and
+// collects whatever the process wants to send after each
+pub trait Process {
+    fn handle(&mut self, from: &str, payload: Payload, now_ms: u64);
+    fn tick(&mut self, now_ms: u64);
+    // Messages to send, as (destination, payload)
+    fn take_messages(&mut self) -> Vec<(String, Payload)>;
+}
+
+struct InFlight {
+    deliver_at: u64,
+    from: String,
+    to: String,
+    payload: Payload,
+}
+
+// A deterministic, in-process network of processes with simulated time,
+// random latency and partitions. The same seed always gives the same run.
+pub struct Sim<P: Process> {
+    nodes: BTreeMap<String, P>,
+    in_flight: Vec<InFlight>,
+    now_ms: u64,
+    rng: StdRng,
+    latency_ms: (u64, u64),
+    // (from, to) pairs whose messages are dropped
+    cut: HashSet<(String, String)>,
+    // Messages to destinations that aren't nodes, like clients
+    external: Vec<(String, String, Payload)>,
+    delivered: usize,
+    dropped: usize,
+}
+
+impl<P: Process> Sim<P> {
+    pub fn new(nodes: impl IntoIterator<Item = (String, P)>, seed: u64) -> Self {
+        Sim {
+            nodes: nodes.into_iter().collect(),
+            in_flight: Vec::new(),
+            now_ms: 0,
+            rng: StdRng::seed_from_u64(seed),
+            latency_ms: (1, 5),
+            cut: HashSet::new(),
+            external: Vec::new(),
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+    pub fn node(&self, id: &str) -> &P {
+        &self.nodes[id]
+    }
+    pub fn node_mut(&mut self, id: &str) -> &mut P {
+        self.nodes.get_mut(id).expect("node in simulation")
+    }
+    // Start a new node mid-run. Messages sent to it before now were treated
+    // as external and aren't delivered.
+    pub fn add_node(&mut self, id: &str, node: P) {
+        self.nodes.insert(id.to_string(), node);
+    }
+    pub fn nodes(&self) -> impl Iterator<Item = (&String, &P)> {
+        self.nodes.iter()
+    }
+    // (delivered, dropped) message counts so far
+    pub fn stats(&self) -> (usize, usize) {
+        (self.delivered, self.dropped)
+    }
+    // Cut every link between a node in side and one outside it, both ways
+    pub fn partition(&mut self, side: &[&str]) {
+        let side: HashSet<&str> = side.iter().cloned().collect();
+        for a in self.nodes.keys() {
+            for b in self.nodes.keys() {
+                if side.contains(a.as_str()) != side.contains(b.as_str()) {
+                    self.cut.insert((a.clone(), b.clone()));
+                }
+            }
+        }
+    }
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+    // Send a message into the network from outside, e.g. from a client
+    pub fn inject(&mut self, from: &str, to: &str, payload: Payload) {
+        self.in_flight.push(InFlight {
+            deliver_at: self.now_ms,
+            from: from.to_string(),
+            to: to.to_string(),
+            payload,
+        });
+    }
+    // Messages sent to destinations outside the simulation, as
+    // (from, to, payload)
+    pub fn take_external(&mut self) -> Vec<(String, String, Payload)> {
+        std::mem::take(&mut self.external)
+    }
+    // Advance time a millisecond at a time, delivering messages when they
+    // arrive and ticking every node each millisecond
+    pub fn run_for(&mut self, ms: u64) {
+        for _ in 0..ms {
+            self.now_ms += 1;
+            self.step();
+        }
+    }
+    // Run until check passes or max_ms has gone by, returning whether it did
+    pub fn run_until(&mut self, max_ms: u64, mut check: impl FnMut(&Self) -> bool) -> bool {
+        for _ in 0..max_ms {
+            if check(self) {
+                return true;
+            }
+            self.now_ms += 1;
+            self.step();
+        }
+        check(self)
+    }
+
+    fn step(&mut self) {
+        let now = self.now_ms;
+        let (due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|m| m.deliver_at <= now);
+        self.in_flight = later;
+        for m in due {
+            let Some(node) = self.nodes.get_mut(&m.to) else {
+                self.external.push((m.from, m.to, m.payload));
+                continue;
+            };
+            if self.cut.contains(&(m.from.clone(), m.to.clone())) {
+                trace!("dropping {:?} from {} to {}", m.payload, m.from, m.to);
+                self.dropped += 1;
+                continue;
+            }
+            node.handle(&m.from, m.payload, now);
+            self.delivered += 1;
+        }
+        for node in self.nodes.values_mut() {
+            node.tick(now);
+        }
+        let ids: Vec<String> = self.nodes.keys().cloned().collect();
+        for id in ids {
+            let outgoing = self.nodes.get_mut(&id).expect("node").take_messages();
+            for (to, payload) in outgoing {
+                let (min, max) = self.latency_ms;
+                let deliver_at = now + self.rng.gen_range(min..=max);
+                self.in_flight.push(InFlight {
+                    deliver_at,
+                    from: id.clone(),
+                    to,
+                    payload,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::msg::Message;
+use anyhow::{bail, Context};
+use std::fs::File;
+use std::io::{BufWriter, Stdout, Write};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+// Where a node sends its messages
+pub trait MessageSink {
+    fn send(&mut self, msg: &Message) -> anyhow::Result<()>;
+    // Write out anything still buffered
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes each message as one line of JSON, as Maelstrom expects on stdout
+pub struct WriteSink<W: Write> {
+    writer: W,
+    line: Vec<u8>,
+}
+
+pub type StdoutSink = WriteSink<Stdout>;
+pub type FileSink = WriteSink<BufWriter<File>>;
+
+impl<W: Write> WriteSink<W> {
+    pub fn new(writer: W) -> Self {
+        WriteSink {
+            writer,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl StdoutSink {
+    pub fn stdout() -> Self {
+        WriteSink::new(std::io::stdout())
+    }
+}
+
+impl FileSink {
+    // Messages go to a new file at path, replacing any that's there
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("create {:?}", path))?;
+        Ok(WriteSink::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> MessageSink for WriteSink<W> {
+    fn send(&mut self, msg: &Message) -> anyhow::Result<()> {
+        // One write per message, newline included
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, msg).context("serialize message")?;
+        self.line.push(b'\n');
+        self.writer.write_all(&self.line).context("write message")
+    }
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("flush output")
+    }
+}
+
+// Keeps every message sent, for tests to look at. Clones share the
+// messages, so keep one before handing the sink to a node.
+#[derive(Clone, Debug, Default)]
+pub struct VecSink {
+    messages: Arc<Mutex<Vec<Message>>>,
+}
+
+impl VecSink {
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().expect("sink lock").clone()
+    }
+    // The messages sent since the last take
+    pub fn take(&self) -> Vec<Message> {
+        std::mem::take(&mut *self.messages.lock().expect("sink lock"))
+    }
+}
+
+impl MessageSink for VecSink {
+    fn send(&mut self, msg: &Message) -> anyhow::Result<()> {
+        self.messages.lock().expect("sink lock").push(msg.clone());
+        Ok(())
+    }
+}
+
+// Hands each message to a channel, e.g. to another thread
+pub struct ChannelSink {
+    tx: Sender<Message>,
+}
+
+impl ChannelSink {
+    pub fn new(tx: Sender<Message>) -> Self {
+        ChannelSink { tx }
+    }
+}
+
+impl MessageSink for ChannelSink {
+    fn send(&mut self, msg: &Message) -> anyhow::Result<()> {
+        if self.tx.send(msg.clone()).is_err() {
+            bail!("receiver for {} has gone away", msg.dest);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn sinks_deliver_what_a_node_sends() -> anyhow::Result<()> {
+    use crate::msg::{Event, Payload};
+    use crate::EchoNode::EchoNode;
+    use std::io::BufRead;
+
+    let init: Message = serde_json::from_str(
+        r#"{"src":"c1","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}"#,
+    )?;
+    let echo: Message = serde_json::from_str(
+        r#"{"src":"c1","dest":"n0","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#,
+    )?;
+    let is_echo_ok =
+        |msg: &Message| matches!(msg.body.payload, Payload::EchoOk { ref echo } if echo == "hi");
+
+    let sink = VecSink::default();
+    let mut node = EchoNode::new(Event::Message(init.clone()), Box::new(sink.clone()))?;
+    let sent = sink.take();
+    assert!(sent.len() == 1 && matches!(sent[0].body.payload, Payload::InitOk));
+    node.step(Event::Message(echo.clone()))?;
+    let sent = sink.take();
+    assert!(sent.len() == 1 && is_echo_ok(&sent[0]));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut node = EchoNode::new(Event::Message(init.clone()), Box::new(ChannelSink::new(tx)))?;
+    node.step(Event::Message(echo.clone()))?;
+    assert!(is_echo_ok(&rx.try_iter().nth(1).expect("echo_ok")));
+
+    let path = std::env::temp_dir().join(format!("fly-sink-{}.jsonl", std::process::id()));
+    let mut node = EchoNode::new(Event::Message(init), Box::new(FileSink::create(&path)?))?;
+    node.step(Event::Message(echo))?;
+    crate::runtime::Node::flush(&mut node)?;
+    let lines: Vec<String> = std::io::BufReader::new(File::open(&path)?)
+        .lines()
+        .collect::<Result<_, _>>()?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(lines.len(), 2);
+    assert!(is_echo_ok(&serde_json::from_str(&lines[1])?));
+    Ok(())
+}
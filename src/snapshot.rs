@@ -0,0 +1,91 @@
+use anyhow::Context;
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Setting FLY_SNAPSHOT_DIR turns on periodic snapshots of node state
+pub const SNAPSHOT_DIR_ENV: &str = "FLY_SNAPSHOT_DIR";
+pub const SNAPSHOT_INTERVAL_ENV: &str = "FLY_SNAPSHOT_INTERVAL_MS";
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Writes a node's state to <dir>/<node_id>.json every interval, and reads
+// it back when the node starts up again
+pub struct Snapshotter {
+    path: PathBuf,
+    interval: Duration,
+    last: Instant,
+}
+
+impl Snapshotter {
+    pub fn new(dir: impl Into<PathBuf>, node_id: &str, interval: Duration) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context("create snapshot dir")?;
+        Ok(Snapshotter {
+            path: dir.join(format!("{}.json", node_id)),
+            interval,
+            last: Instant::now(),
+        })
+    }
+    // None unless FLY_SNAPSHOT_DIR is set
+    pub fn from_env(node_id: &str) -> anyhow::Result<Option<Self>> {
+        let Ok(dir) = std::env::var(SNAPSHOT_DIR_ENV) else {
+            return Ok(None);
+        };
+        let interval = match std::env::var(SNAPSHOT_INTERVAL_ENV) {
+            Ok(ms) => Duration::from_millis(ms.parse().context("parse snapshot interval")?),
+            Err(_) => DEFAULT_SNAPSHOT_INTERVAL,
+        };
+        Ok(Some(Snapshotter::new(dir, node_id, interval)?))
+    }
+    // The last snapshot written, if there is one
+    pub fn load<T: DeserializeOwned>(&self) -> anyhow::Result<Option<T>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&self.path).context("open snapshot")?;
+        let state = serde_json::from_reader(file)
+            .with_context(|| format!("deserialize snapshot {:?}", self.path))?;
+        info!("restored snapshot from {:?}", self.path);
+        Ok(Some(state))
+    }
+    // Whether at least interval has passed since the last save
+    pub fn is_due(&self) -> bool {
+        self.last.elapsed() >= self.interval
+    }
+    // Write to a temporary file and rename it over the old snapshot, so a
+    // crash mid-write leaves the previous snapshot in place
+    pub fn save<T: Serialize>(&mut self, state: &T) -> anyhow::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let mut tmp = File::create(&tmp_path).context("create snapshot")?;
+        serde_json::to_writer(&mut tmp, state).context("serialize snapshot")?;
+        tmp.flush()?;
+        tmp.sync_all().context("fsync snapshot")?;
+        fs::rename(&tmp_path, &self.path).context("replace snapshot")?;
+        self.last = Instant::now();
+        debug!("wrote snapshot to {:?}", self.path);
+        Ok(())
+    }
+}
+
+#[test]
+fn snapshot_round_trip() -> anyhow::Result<()> {
+    use std::collections::HashSet;
+
+    let dir = std::env::temp_dir().join(format!("fly-snapshot-{}", uuid::Uuid::new_v4()));
+    let mut snapshotter = Snapshotter::new(&dir, "n1", Duration::from_secs(60))?;
+    assert_eq!(snapshotter.load::<HashSet<usize>>()?, None);
+
+    let state: HashSet<usize> = [1, 2, 3].into_iter().collect();
+    assert!(!snapshotter.is_due());
+    snapshotter.save(&state)?;
+
+    let snapshotter = Snapshotter::new(&dir, "n1", Duration::ZERO)?;
+    assert_eq!(snapshotter.load::<HashSet<usize>>()?, Some(state));
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,757 @@
+use crate::codec::Codec;
+use crate::msg::{Body, Event, Injected, Message, Payload};
+use crate::runtime::Output;
+use crate::sink::MessageSink;
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// stdio (the default, for Maelstrom), tcp or unix
+pub const TRANSPORT_ENV: &str = "FLY_TRANSPORT";
+// This node's id, when there's no Maelstrom to send init
+pub const NODE_ID_ENV: &str = "FLY_NODE_ID";
+// Every node's address, this one's included, as n0=127.0.0.1:7000,n1=...
+pub const TCP_PEERS_ENV: &str = "FLY_TCP_PEERS";
+// Every node's socket path, as n0=/tmp/fly/n0.sock,n1=...
+pub const UNIX_PEERS_ENV: &str = "FLY_UNIX_PEERS";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where a node's messages come from and go to
+pub trait Transport: Send {
+    // The init message, before anything else is read
+    fn init(&mut self) -> anyhow::Result<Message>;
+    // Where the node sends its messages
+    fn output(&self) -> anyhow::Result<Outbound>;
+    // Read messages into tx until the input ends, on a thread of its own
+    fn receive(self: Box<Self>, tx: Sender<Event<Message, Injected>>) -> anyhow::Result<()>;
+}
+
+// How a node's messages leave it
+pub enum Outbound {
+    // Written as JSON objects, one per line, which the runtime batches
+    Bytes(Output<'static>),
+    // Handed over as they are, for transports that carry messages
+    Messages(Box<dyn MessageSink>),
+}
+
+// The transport from FLY_TRANSPORT, stdio by default
+pub fn from_env() -> anyhow::Result<Box<dyn Transport>> {
+    match std::env::var(TRANSPORT_ENV).as_deref() {
+        Err(_) | Ok("stdio") => Ok(Box::new(StdioTransport)),
+        Ok("tcp") => Ok(Box::new(TcpTransport::bind(TcpConfig::from_env(
+            TCP_PEERS_ENV,
+        )?)?)),
+        Ok("unix") => Ok(Box::new(UnixTransport::bind(UnixConfig::from_env(
+            UNIX_PEERS_ENV,
+        )?)?)),
+        Ok(other) => bail!("unknown transport {:?}", other),
+    }
+}
+
+// Maelstrom's: messages in on STDIN and out on STDOUT
+pub struct StdioTransport;
+
+impl Transport for StdioTransport {
+    fn init(&mut self) -> anyhow::Result<Message> {
+        let line = std::io::stdin()
+            .lock()
+            .lines()
+            .next()
+            .context("no init message on STDIN")?
+            .context("failed to read init message")?;
+        serde_json::from_str(&line).context("failed to deserialize init")
+    }
+    fn output(&self) -> anyhow::Result<Outbound> {
+        Ok(Outbound::Bytes(Box::new(std::io::stdout().lock())))
+    }
+    fn receive(self: Box<Self>, tx: Sender<Event<Message, Injected>>) -> anyhow::Result<()> {
+        let stdin = std::io::stdin().lock();
+        for line in stdin.lines() {
+            let line = line.context("Malestrom line from STDIN not read")?;
+            debug!("{:?}", &line);
+            let input: Message = match serde_json::from_str(&line) {
+                Ok(input) => input,
+                Err(e) => {
+                    error!("could not deserialize {:?}: {}", line, e);
+                    continue;
+                }
+            };
+            if tx.send(Event::Message(input)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The init message for a node with no Maelstrom to send one, from itself
+fn self_init(node_id: &str, node_ids: Vec<String>) -> Message {
+    Message {
+        src: node_id.to_string(),
+        dest: node_id.to_string(),
+        body: Body {
+            msg_id: None,
+            in_reply_to: None,
+            clock: None,
+            payload: Payload::Init {
+                node_id: node_id.to_string(),
+                node_ids,
+            },
+        },
+    }
+}
+
+// Where each line a node writes goes
+trait Route {
+    fn route(&mut self, line: &[u8]);
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Splits what the node writes into lines, routing each once it's complete
+struct LineOutput<R> {
+    route: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Route> Write for LineOutput<R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            self.route.route(&line);
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.route.flush()
+    }
+}
+
+// What the socket transports need from their kind of socket
+pub trait Socket: Send + 'static {
+    type Addr: Clone + Debug + FromStr + Send + 'static;
+    type Stream: Read + Write + Send + 'static;
+    type Listener: Send + 'static;
+
+    fn bind(addr: &Self::Addr) -> std::io::Result<Self::Listener>;
+    // Where listener ended up, given the address it was bound to
+    fn local_addr(listener: &Self::Listener, bound: &Self::Addr) -> std::io::Result<Self::Addr>;
+    fn accept(listener: &Self::Listener) -> std::io::Result<Self::Stream>;
+    fn connect(addr: &Self::Addr) -> std::io::Result<Self::Stream>;
+    fn try_clone(stream: &Self::Stream) -> std::io::Result<Self::Stream>;
+}
+
+pub struct Tcp;
+
+impl Socket for Tcp {
+    type Addr = SocketAddr;
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn bind(addr: &SocketAddr) -> std::io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+    fn local_addr(listener: &TcpListener, _bound: &SocketAddr) -> std::io::Result<SocketAddr> {
+        listener.local_addr()
+    }
+    fn accept(listener: &TcpListener) -> std::io::Result<TcpStream> {
+        listener.accept().map(|(stream, _)| stream)
+    }
+    fn connect(addr: &SocketAddr) -> std::io::Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+    fn try_clone(stream: &TcpStream) -> std::io::Result<TcpStream> {
+        stream.try_clone()
+    }
+}
+
+// Unix domain sockets, for clusters on one machine without picking ports
+pub struct Unix;
+
+impl Socket for Unix {
+    type Addr = PathBuf;
+    type Stream = UnixStream;
+    type Listener = UnixListener;
+
+    // A socket file left by an earlier run would make bind fail
+    fn bind(path: &PathBuf) -> std::io::Result<UnixListener> {
+        if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        UnixListener::bind(path)
+    }
+    fn local_addr(_listener: &UnixListener, bound: &PathBuf) -> std::io::Result<PathBuf> {
+        Ok(bound.clone())
+    }
+    fn accept(listener: &UnixListener) -> std::io::Result<UnixStream> {
+        listener.accept().map(|(stream, _)| stream)
+    }
+    fn connect(path: &PathBuf) -> std::io::Result<UnixStream> {
+        UnixStream::connect(path)
+    }
+    fn try_clone(stream: &UnixStream) -> std::io::Result<UnixStream> {
+        stream.try_clone()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SocketConfig<A> {
+    pub node_id: String,
+    // node id -> address it listens on
+    pub addrs: BTreeMap<String, A>,
+    // How messages are framed on every connection
+    pub codec: Codec,
+}
+
+pub type TcpConfig = SocketConfig<SocketAddr>;
+pub type UnixConfig = SocketConfig<PathBuf>;
+
+impl<A: FromStr> SocketConfig<A>
+where
+    A::Err: std::error::Error + Send + Sync + 'static,
+{
+    // This node from FLY_NODE_ID, every node's address from peers_env and
+    // the codec from FLY_CODEC
+    pub fn from_env(peers_env: &str) -> anyhow::Result<Self> {
+        let node_id = std::env::var(NODE_ID_ENV).context("FLY_NODE_ID is needed for sockets")?;
+        let peers = std::env::var(peers_env).with_context(|| format!("{} is needed", peers_env))?;
+        let addrs = parse_peers(&peers).with_context(|| format!("parse {}", peers_env))?;
+        if !addrs.contains_key(&node_id) {
+            bail!("{} has no address in {}", node_id, peers_env);
+        }
+        Ok(SocketConfig {
+            node_id,
+            addrs,
+            codec: Codec::from_env()?,
+        })
+    }
+}
+
+// Parse n0=127.0.0.1:7000,n1=127.0.0.1:7001 into node id -> address
+pub fn parse_peers<A: FromStr>(s: &str) -> anyhow::Result<BTreeMap<String, A>>
+where
+    A::Err: std::error::Error + Send + Sync + 'static,
+{
+    s.split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            let (node, addr) = p
+                .split_once('=')
+                .with_context(|| format!("expected node=address, got {:?}", p))?;
+            let addr = addr
+                .trim()
+                .parse()
+                .with_context(|| format!("bad address for {}", node))?;
+            Ok((node.trim().to_string(), addr))
+        })
+        .collect()
+}
+
+// Open connections by the node or client at the other end
+type Connections<S> = Arc<Mutex<HashMap<String, S>>>;
+
+// Each node listens on its address from a static map. Messages to another
+// node go over a connection this node opens to it on first use; messages
+// to a client go back over the connection the client opened. A node never
+// answers a peer on the peer's own connection, so every connection only
+// carries messages one way between nodes.
+pub struct SocketTransport<S: Socket> {
+    config: SocketConfig<S::Addr>,
+    listener: S::Listener,
+    connections: Connections<S::Stream>,
+}
+
+pub type TcpTransport = SocketTransport<Tcp>;
+pub type UnixTransport = SocketTransport<Unix>;
+
+impl<S: Socket> SocketTransport<S> {
+    pub fn bind(config: SocketConfig<S::Addr>) -> anyhow::Result<Self> {
+        let addr = &config.addrs[&config.node_id];
+        let listener = S::bind(addr).with_context(|| format!("listen on {:?}", addr))?;
+        info!(
+            "{} listening on {:?}",
+            config.node_id,
+            S::local_addr(&listener, addr)?
+        );
+        Ok(SocketTransport {
+            config,
+            listener,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+    pub fn local_addr(&self) -> anyhow::Result<S::Addr> {
+        let bound = &self.config.addrs[&self.config.node_id];
+        S::local_addr(&self.listener, bound).context("listener address")
+    }
+}
+
+impl<S: Socket> Transport for SocketTransport<S> {
+    // With no Maelstrom, the node inits itself from the peer map
+    fn init(&mut self) -> anyhow::Result<Message> {
+        let node_ids = self.config.addrs.keys().cloned().collect();
+        Ok(self_init(&self.config.node_id, node_ids))
+    }
+    fn output(&self) -> anyhow::Result<Outbound> {
+        Ok(Outbound::Bytes(Box::new(LineOutput {
+            route: SocketRoute::<S> {
+                config: self.config.clone(),
+                connections: self.connections.clone(),
+            },
+            buf: Vec::new(),
+        })))
+    }
+    fn receive(self: Box<Self>, tx: Sender<Event<Message, Injected>>) -> anyhow::Result<()> {
+        loop {
+            let stream = match S::accept(&self.listener) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            let config = self.config.clone();
+            let connections = self.connections.clone();
+            thread::spawn(move || {
+                if let Err(e) = read_connection::<S>(stream, &config, &connections, &tx) {
+                    warn!("connection failed: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+// Pass the messages on one connection to the node, remembering it as the
+// way back to each client that sends on it
+fn read_connection<S: Socket>(
+    stream: S::Stream,
+    config: &SocketConfig<S::Addr>,
+    connections: &Connections<S::Stream>,
+    tx: &Sender<Event<Message, Injected>>,
+) -> anyhow::Result<()> {
+    let mut registered = HashSet::new();
+    let mut reader = BufReader::new(S::try_clone(&stream).context("clone connection")?);
+    while let Some(frame) = config.codec.read_frame(&mut reader)? {
+        let input: Message = match config.codec.decode(&frame) {
+            Ok(input) => input,
+            Err(e) => {
+                error!("could not deserialize {:?}: {:#}", frame, e);
+                continue;
+            }
+        };
+        if !config.addrs.contains_key(&input.src) && registered.insert(input.src.clone()) {
+            let writer = S::try_clone(&stream).context("clone connection")?;
+            connections
+                .lock()
+                .expect("connections lock")
+                .insert(input.src.clone(), writer);
+        }
+        if tx.send(Event::Message(input)).is_err() {
+            break;
+        }
+    }
+    debug!("connection closed");
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Dest {
+    dest: String,
+}
+
+// Sends each line to its dest's connection, re-encoded if the codec isn't
+// JSON. Like any network, a message that can't be delivered is lost.
+struct SocketRoute<S: Socket> {
+    config: SocketConfig<S::Addr>,
+    connections: Connections<S::Stream>,
+}
+
+impl<S: Socket> Route for SocketRoute<S> {
+    fn route(&mut self, line: &[u8]) {
+        let dest = match serde_json::from_slice::<Dest>(line) {
+            Ok(d) => d.dest,
+            Err(e) => {
+                error!("not sending unparseable message: {}", e);
+                return;
+            }
+        };
+        if dest == self.config.node_id {
+            debug!("dropping message to ourselves");
+            return;
+        }
+        let mut frame = Vec::new();
+        let frame = match self.config.codec {
+            Codec::Json => line,
+            codec => {
+                let encoded = serde_json::from_slice::<Message>(line)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|msg| codec.encode(&msg, &mut frame));
+                if let Err(e) = encoded {
+                    error!("not sending unencodable message: {:#}", e);
+                    return;
+                }
+                &frame[..]
+            }
+        };
+        let connected = self
+            .connections
+            .lock()
+            .expect("connections lock")
+            .contains_key(&dest);
+        // Connecting can take up to a second, so it's done without holding
+        // the lock that readers take to register clients
+        let stream = if connected {
+            None
+        } else {
+            let Some(addr) = self.config.addrs.get(&dest) else {
+                warn!("no connection to {}, dropping message", dest);
+                return;
+            };
+            match S::connect(addr) {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    warn!("can't reach {} at {:?}: {}", dest, addr, e);
+                    return;
+                }
+            }
+        };
+        let mut connections = self.connections.lock().expect("connections lock");
+        if let Some(stream) = stream {
+            // Another writer may have connected to dest in the meantime, in
+            // which case its connection is kept
+            connections.entry(dest.clone()).or_insert(stream);
+        }
+        let stream = connections.get_mut(&dest).expect("connection");
+        if let Err(e) = stream.write_all(frame) {
+            warn!("lost connection to {}: {}", dest, e);
+            connections.remove(&dest);
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        for stream in self
+            .connections
+            .lock()
+            .expect("connections lock")
+            .values_mut()
+        {
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Several nodes in one process, for tests and benchmarks. Each message a
+// node sends is handed as it is to the inbox of its dest, node or client.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    inboxes: Arc<Mutex<HashMap<String, Sender<Message>>>>,
+}
+
+impl ChannelNetwork {
+    // A transport for node_id, one of node_ids
+    pub fn transport(&self, node_id: &str, node_ids: &[String]) -> ChannelTransport {
+        ChannelTransport {
+            network: self.clone(),
+            node_id: node_id.to_string(),
+            node_ids: node_ids.to_vec(),
+            inbox: self.inbox(node_id),
+        }
+    }
+    // The messages nodes send to a client called id
+    pub fn client(&self, id: &str) -> Receiver<Message> {
+        self.inbox(id)
+    }
+    // Deliver msg, returning false if there's nothing at its dest
+    pub fn send(&self, msg: Message) -> bool {
+        let inboxes = self.inboxes.lock().expect("inboxes lock");
+        match inboxes.get(&msg.dest) {
+            Some(inbox) => inbox.send(msg).is_ok(),
+            None => false,
+        }
+    }
+    // Take id off the network. For a node that ends its input, so it shuts
+    // down as it would on EOF.
+    pub fn remove(&self, id: &str) {
+        self.inboxes.lock().expect("inboxes lock").remove(id);
+    }
+
+    fn inbox(&self, id: &str) -> Receiver<Message> {
+        let (tx, rx) = channel();
+        self.inboxes
+            .lock()
+            .expect("inboxes lock")
+            .insert(id.to_string(), tx);
+        rx
+    }
+}
+
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+    node_id: String,
+    node_ids: Vec<String>,
+    inbox: Receiver<Message>,
+}
+
+impl Transport for ChannelTransport {
+    fn init(&mut self) -> anyhow::Result<Message> {
+        Ok(self_init(&self.node_id, self.node_ids.clone()))
+    }
+    fn output(&self) -> anyhow::Result<Outbound> {
+        Ok(Outbound::Messages(Box::new(NetworkSink {
+            network: self.network.clone(),
+            node_id: self.node_id.clone(),
+        })))
+    }
+    fn receive(self: Box<Self>, tx: Sender<Event<Message, Injected>>) -> anyhow::Result<()> {
+        for msg in self.inbox.iter() {
+            if tx.send(Event::Message(msg)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Like any network, a message with nothing at its dest is lost
+struct NetworkSink {
+    network: ChannelNetwork,
+    node_id: String,
+}
+
+impl MessageSink for NetworkSink {
+    fn send(&mut self, msg: &Message) -> anyhow::Result<()> {
+        if msg.dest == self.node_id {
+            debug!("dropping message to ourselves");
+        } else if !self.network.send(msg.clone()) {
+            debug!("nothing at {}, dropping message", msg.dest);
+        }
+        Ok(())
+    }
+}
+
+// A message from n0 to n1 and an echo from a client answered on the
+// client's own connection, over whichever kind of socket
+#[cfg(test)]
+fn carries_messages_between_nodes_and_clients<S: Socket>(
+    n0_addr: S::Addr,
+    n1_addr: S::Addr,
+    codec: Codec,
+) -> anyhow::Result<()>
+where
+    S::Addr: Ord,
+{
+    let n1 = SocketTransport::<S>::bind(SocketConfig {
+        node_id: "n1".to_string(),
+        addrs: BTreeMap::from([("n1".to_string(), n1_addr)]),
+        codec,
+    })?;
+    let n1_addr = n1.local_addr()?;
+    let mut n0 = SocketTransport::<S>::bind(SocketConfig {
+        node_id: "n0".to_string(),
+        addrs: BTreeMap::from([
+            ("n0".to_string(), n0_addr),
+            ("n1".to_string(), n1_addr.clone()),
+        ]),
+        codec,
+    })?;
+    assert!(matches!(
+        n0.init()?.body.payload,
+        Payload::Init { ref node_ids, .. } if node_ids == &["n0", "n1"]
+    ));
+
+    let Outbound::Bytes(mut n1_output) = n1.output()? else {
+        panic!("socket transports write bytes");
+    };
+    let (tx, rx) = channel();
+    thread::spawn(move || Box::new(n1).receive(tx));
+    let next = || match rx.recv_timeout(Duration::from_secs(5)) {
+        Ok(Event::Message(msg)) => msg,
+        other => panic!("expected a message, got {:?}", other.is_ok()),
+    };
+
+    let Outbound::Bytes(mut n0_output) = n0.output()? else {
+        panic!("socket transports write bytes");
+    };
+    writeln!(
+        n0_output,
+        r#"{{"src":"n0","dest":"n1","body":{{"type":"read","msg_id":1}}}}"#
+    )?;
+    n0_output.flush()?;
+    let msg = next();
+    assert_eq!((msg.src.as_str(), msg.dest.as_str()), ("n0", "n1"));
+
+    let mut client = S::connect(&n1_addr)?;
+    let echo: Message = serde_json::from_str(
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi","msg_id":1}}"#,
+    )?;
+    let mut frame = Vec::new();
+    codec.encode(&echo, &mut frame)?;
+    client.write_all(&frame)?;
+    assert!(matches!(next().body.payload, Payload::Echo { .. }));
+    // Written in two pieces, as a line is only sent once it's complete
+    write!(
+        n1_output,
+        r#"{{"src":"n1","dest":"c1","body":{{"type":"echo_ok","#
+    )?;
+    writeln!(n1_output, r#""echo":"hi","in_reply_to":1}}}}"#)?;
+    let frame = codec.read_frame(&mut BufReader::new(client))?;
+    let reply: Message = codec.decode(&frame.context("reply")?)?;
+    assert!(matches!(reply.body.payload, Payload::EchoOk { ref echo } if echo == "hi"));
+    Ok(())
+}
+
+#[test]
+fn tcp_carries_messages_between_nodes_and_clients() -> anyhow::Result<()> {
+    let peers: BTreeMap<String, SocketAddr> = parse_peers("n0=127.0.0.1:0, n1=127.0.0.1:0")?;
+    carries_messages_between_nodes_and_clients::<Tcp>(peers["n0"], peers["n1"], Codec::Json)
+}
+
+#[test]
+fn unix_carries_messages_between_nodes_and_clients() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("fly-unix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let res = carries_messages_between_nodes_and_clients::<Unix>(
+        dir.join("n0.sock"),
+        dir.join("n1.sock"),
+        Codec::MessagePack,
+    );
+    std::fs::remove_dir_all(&dir)?;
+    res
+}
+
+#[test]
+fn channel_network_runs_nodes_in_one_process() -> anyhow::Result<()> {
+    use crate::EchoNode::EchoNode;
+
+    let network = ChannelNetwork::default();
+    let ids = vec!["n0".to_string(), "n1".to_string()];
+    let nodes: Vec<_> = ids
+        .iter()
+        .map(|id| {
+            let transport = network.transport(id, &ids);
+            thread::spawn(move || crate::runtime::run_with::<EchoNode>(Box::new(transport)))
+        })
+        .collect();
+    let client = network.client("c1");
+    let request = |dest: &str, msg_id: usize, payload: Payload| {
+        let msg = Message {
+            src: "c1".to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                clock: None,
+                payload,
+            },
+        };
+        assert!(network.send(msg));
+        let reply = client.recv_timeout(Duration::from_secs(5)).expect("reply");
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        reply.body.payload
+    };
+
+    let topology: HashMap<String, Vec<String>> = HashMap::from([
+        ("n0".into(), vec!["n1".into()]),
+        ("n1".into(), vec!["n0".into()]),
+    ]);
+    for id in ids.iter() {
+        let payload = Payload::Topology {
+            topology: topology.clone(),
+        };
+        assert!(matches!(request(id, 1, payload), Payload::TopologyOk));
+    }
+    let broadcast = Payload::Broadcast { message: 7 };
+    assert!(matches!(request("n0", 2, broadcast), Payload::BroadcastOk));
+    // Gossip gets it to n1 in the background
+    let mut seen = false;
+    for msg_id in 3..100 {
+        if let Payload::ReadOk {
+            messages: Some(messages),
+            ..
+        } = request("n1", msg_id, Payload::Read { key: None })
+        {
+            if messages == [7] {
+                seen = true;
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(seen);
+
+    for id in ids.iter() {
+        network.remove(id);
+    }
+    for node in nodes {
+        node.join().expect("node thread")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn channel_network_carries_every_reply_but_not_to_the_sender() -> anyhow::Result<()> {
+    use crate::CountNode::CountNode;
+
+    let network = ChannelNetwork::default();
+    let ids = vec!["n0".to_string()];
+    // Anything the node sends itself, like the init_ok for its self_init,
+    // would land here
+    let own_inbox = network.client("n0");
+    let mut sink = NetworkSink {
+        network: network.clone(),
+        node_id: "n0".to_string(),
+    };
+    sink.send(&self_init("n0", ids.clone()))?;
+    assert!(own_inbox.try_recv().is_err());
+
+    let transport = network.transport("n0", &ids);
+    let node = thread::spawn(move || crate::runtime::run_with::<CountNode>(Box::new(transport)));
+    let client = network.client("c1");
+    let request = |msg_id: usize, payload: Payload| {
+        assert!(network.send(Message {
+            src: "c1".to_string(),
+            dest: "n0".to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                clock: None,
+                payload,
+            },
+        }));
+        client
+            .recv_timeout(Duration::from_secs(5))
+            .expect("reply")
+            .body
+            .payload
+    };
+    assert!(matches!(
+        request(1, Payload::Add { delta: 3 }),
+        Payload::AddOk
+    ));
+    // A read_ok with a value, which used to be lost on the way back
+    assert!(matches!(
+        request(2, Payload::Read { key: None }),
+        Payload::ReadOk { value: Some(3), .. }
+    ));
+
+    network.remove("n0");
+    node.join().expect("node thread")?;
+    Ok(())
+}
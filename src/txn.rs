@@ -0,0 +1,298 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Environment variable used to pick TxnNode's consistency level
+pub const TXN_CONSISTENCY_ENV: &str = "FLY_TXN_CONSISTENCY";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+// `["r", key, null]` or `["w", key, value]`. Replies fill in the value read.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MicroOp(pub OpKind, pub usize, pub Option<usize>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    // Every write is replicated on its own as soon as it's made, so other
+    // nodes can see part of a transaction, including values it overwrote
+    ReadUncommitted,
+    // Only the final value of each key a transaction wrote is replicated,
+    // and all of them are applied together
+    ReadCommitted,
+    // Transactions read from a snapshot of multi-version storage and their
+    // writes are certified by one node, first committer wins
+    SnapshotIsolation,
+}
+
+impl std::str::FromStr for Consistency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "read-uncommitted" => Ok(Consistency::ReadUncommitted),
+            "read-committed" => Ok(Consistency::ReadCommitted),
+            "snapshot-isolation" => Ok(Consistency::SnapshotIsolation),
+            _ => bail!("unknown consistency level {:?}", s),
+        }
+    }
+}
+
+impl Consistency {
+    // Level from FLY_TXN_CONSISTENCY, defaulting to read committed
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(TXN_CONSISTENCY_ENV) {
+            Ok(s) => s.parse().context("parse FLY_TXN_CONSISTENCY"),
+            Err(_) => Ok(Consistency::ReadCommitted),
+        }
+    }
+}
+
+// Lamport timestamp of a write, with the node that made it breaking ties so
+// every node orders writes to a key the same way
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub counter: u64,
+    pub node: String,
+}
+
+// Writes made by one node, sent to the others until they acknowledge seq
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicatedWrites {
+    pub seq: usize,
+    pub version: Version,
+    // (key, value)
+    pub writes: Vec<(usize, usize)>,
+}
+
+// Writes that replicate together, all made at one version
+pub type WriteUnit = (Version, Vec<(usize, usize)>);
+
+// The latest value of each key, by version
+#[derive(Default)]
+pub struct Store {
+    values: HashMap<usize, (usize, Version)>,
+    clock: u64,
+}
+
+impl Store {
+    // A version newer than anything this store has seen
+    pub fn next_version(&mut self, node: &str) -> Version {
+        self.clock += 1;
+        Version {
+            counter: self.clock,
+            node: node.to_string(),
+        }
+    }
+    pub fn get(&self, key: usize) -> Option<usize> {
+        self.values.get(&key).map(|(value, _)| *value)
+    }
+    // Last writer wins, so applying writes in any order, or more than once,
+    // ends up in the same state. An equal version is a later write from the
+    // same transaction.
+    pub fn apply(&mut self, key: usize, value: usize, version: &Version) {
+        self.clock = self.clock.max(version.counter);
+        match self.values.get(&key) {
+            Some((_, current)) if current > version => {}
+            _ => {
+                self.values.insert(key, (value, version.clone()));
+            }
+        }
+    }
+    // Run ops as a transaction on node, returning them with reads filled in
+    // and the writes to replicate. Under read uncommitted each write is its
+    // own unit with its own version, otherwise the transaction's final
+    // writes share one.
+    pub fn execute(
+        &mut self,
+        ops: Vec<MicroOp>,
+        node: &str,
+        consistency: Consistency,
+    ) -> (Vec<MicroOp>, Vec<WriteUnit>) {
+        let txn_version = self.next_version(node);
+        let mut written: Vec<(usize, usize)> = Vec::new();
+        let mut units = Vec::new();
+        let mut result = Vec::with_capacity(ops.len());
+        for MicroOp(kind, key, value) in ops {
+            match (kind, value) {
+                (OpKind::Read, _) => result.push(MicroOp(kind, key, self.get(key))),
+                (OpKind::Write, Some(value)) => {
+                    if consistency == Consistency::ReadUncommitted {
+                        let version = self.next_version(node);
+                        self.apply(key, value, &version);
+                        units.push((version, vec![(key, value)]));
+                    } else {
+                        self.apply(key, value, &txn_version);
+                        match written.iter_mut().find(|(k, _)| *k == key) {
+                            Some(w) => w.1 = value,
+                            None => written.push((key, value)),
+                        }
+                    }
+                    result.push(MicroOp(kind, key, Some(value)));
+                }
+                // Writing null isn't part of the workload, treat it as a no-op
+                (OpKind::Write, None) => result.push(MicroOp(kind, key, None)),
+            }
+        }
+        if !written.is_empty() {
+            units.push((txn_version, written));
+        }
+        (result, units)
+    }
+}
+
+// Every committed version of each key, by hybrid logical clock commit
+// timestamp, oldest first
+#[derive(Default)]
+pub struct MvccStore {
+    versions: HashMap<usize, Vec<(u64, usize)>>,
+}
+
+impl MvccStore {
+    // The value of key as of the snapshot at ts
+    pub fn read(&self, key: usize, ts: u64) -> Option<usize> {
+        let versions = self.versions.get(&key)?;
+        let i = versions.partition_point(|(commit_ts, _)| *commit_ts <= ts);
+        i.checked_sub(1).map(|i| versions[i].1)
+    }
+    pub fn latest_commit(&self, key: usize) -> Option<u64> {
+        self.versions
+            .get(&key)?
+            .last()
+            .map(|(commit_ts, _)| *commit_ts)
+    }
+    pub fn apply(&mut self, key: usize, value: usize, commit_ts: u64) {
+        let versions = self.versions.entry(key).or_default();
+        let i = versions.partition_point(|(ts, _)| *ts <= commit_ts);
+        if i > 0 && versions[i - 1].0 == commit_ts {
+            versions[i - 1].1 = value;
+        } else {
+            versions.insert(i, (commit_ts, value));
+        }
+    }
+    // Run ops against the snapshot at start_ts, returning them with reads
+    // filled in and the final value of each key written. Reads see the
+    // transaction's own earlier writes.
+    pub fn execute(&self, ops: Vec<MicroOp>, start_ts: u64) -> (Vec<MicroOp>, Vec<(usize, usize)>) {
+        let mut written: Vec<(usize, usize)> = Vec::new();
+        let mut result = Vec::with_capacity(ops.len());
+        for MicroOp(kind, key, value) in ops {
+            match (kind, value) {
+                (OpKind::Read, _) => {
+                    let value = match written.iter().find(|(k, _)| *k == key) {
+                        Some((_, v)) => Some(*v),
+                        None => self.read(key, start_ts),
+                    };
+                    result.push(MicroOp(kind, key, value));
+                }
+                (OpKind::Write, Some(value)) => {
+                    match written.iter_mut().find(|(k, _)| *k == key) {
+                        Some(w) => w.1 = value,
+                        None => written.push((key, value)),
+                    }
+                    result.push(MicroOp(kind, key, Some(value)));
+                }
+                (OpKind::Write, None) => result.push(MicroOp(kind, key, None)),
+            }
+        }
+        (result, written)
+    }
+    // The first key in writes someone else committed after start_ts
+    pub fn conflict(&self, writes: &[(usize, usize)], start_ts: u64) -> Option<usize> {
+        writes
+            .iter()
+            .map(|(key, _)| *key)
+            .find(|key| self.latest_commit(*key).is_some_and(|ts| ts > start_ts))
+    }
+    // Drop versions no snapshot at or after oldest can read: everything
+    // older than the newest version at oldest. Returns how many went.
+    pub fn gc(&mut self, oldest: u64) -> usize {
+        let mut removed = 0;
+        for versions in self.versions.values_mut() {
+            let visible = versions.partition_point(|(ts, _)| *ts <= oldest);
+            let drop = visible.saturating_sub(1);
+            versions.drain(..drop);
+            removed += drop;
+        }
+        removed
+    }
+}
+
+#[test]
+fn mvcc_snapshots_conflicts_and_gc() {
+    let mut store = MvccStore::default();
+    store.apply(1, 10, 100);
+    store.apply(1, 11, 200);
+    store.apply(2, 20, 150);
+    assert_eq!(store.read(1, 99), None);
+    assert_eq!(store.read(1, 199), Some(10));
+    assert_eq!(store.read(1, 200), Some(11));
+
+    let ops = vec![
+        MicroOp(OpKind::Read, 1, None),
+        MicroOp(OpKind::Write, 2, Some(21)),
+        MicroOp(OpKind::Read, 2, None),
+    ];
+    let (result, writes) = store.execute(ops, 160);
+    assert_eq!(result[0], MicroOp(OpKind::Read, 1, Some(10)));
+    assert_eq!(result[2], MicroOp(OpKind::Read, 2, Some(21)));
+    assert_eq!(writes, vec![(2, 21)]);
+    // Key 2 hasn't changed since 150, but key 1 has since 160
+    assert_eq!(store.conflict(&writes, 160), None);
+    assert_eq!(store.conflict(&[(1, 12)], 160), Some(1));
+
+    // A snapshot at 160 still needs 10 for key 1, but nothing older
+    store.apply(1, 9, 50);
+    assert_eq!(store.gc(160), 1);
+    assert_eq!(store.read(1, 160), Some(10));
+    assert_eq!(store.gc(250), 1);
+    assert_eq!(store.read(1, 250), Some(11));
+}
+
+#[test]
+fn txn_reads_own_writes_and_last_writer_wins() {
+    let ops = vec![
+        MicroOp(OpKind::Write, 1, Some(5)),
+        MicroOp(OpKind::Read, 1, None),
+        MicroOp(OpKind::Write, 1, Some(6)),
+        MicroOp(OpKind::Read, 2, None),
+    ];
+    assert_eq!(
+        serde_json::to_string(&ops[..2]).unwrap(),
+        r#"[["w",1,5],["r",1,null]]"#
+    );
+
+    let mut store = Store::default();
+    let (result, units) = store.execute(ops.clone(), "n0", Consistency::ReadCommitted);
+    assert_eq!(result[1], MicroOp(OpKind::Read, 1, Some(5)));
+    assert_eq!(result[3], MicroOp(OpKind::Read, 2, None));
+    assert_eq!(store.get(1), Some(6));
+    // Only the final value goes to the other nodes
+    let writes: Vec<_> = units.into_iter().map(|(_, w)| w).collect();
+    assert_eq!(writes, vec![vec![(1, 6)]]);
+    let (_, units) = store.execute(ops, "n0", Consistency::ReadUncommitted);
+    assert_eq!(units.len(), 2);
+    assert!(units[0].0 < units[1].0);
+
+    // A replicated write from a node that's behind loses to ours, but moves
+    // our clock past a newer one
+    let mut remote = Store::default();
+    let older = remote.next_version("n1");
+    store.apply(1, 7, &older);
+    assert_eq!(store.get(1), Some(6));
+    store.apply(
+        2,
+        8,
+        &Version {
+            counter: 10,
+            node: "n1".to_string(),
+        },
+    );
+    assert_eq!(store.next_version("n0").counter, 11);
+    assert_eq!(store.get(2), Some(8));
+}
@@ -0,0 +1,845 @@
+use crate::kafka_log::{
+    BatchMessage, CommittedOffsets, Entry, KeyData, MessageLog, RetentionPolicy,
+};
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// Setting FLY_KAFKA_WAL_DIR turns on the on-disk log for KafkaNode
+pub const WAL_DIR_ENV: &str = "FLY_KAFKA_WAL_DIR";
+// always | never | every:<n records>, defaults to always
+pub const WAL_FSYNC_ENV: &str = "FLY_KAFKA_WAL_FSYNC";
+pub const WAL_SEGMENT_BYTES_ENV: &str = "FLY_KAFKA_WAL_SEGMENT_BYTES";
+
+pub const DEFAULT_SEGMENT_BYTES: u64 = 1024 * 1024;
+
+const COMMITTED_FILE: &str = "committed.log";
+// Holds the batch being appended until every message of it is in its
+// segment, so recovery can finish a batch a crash interrupted
+const BATCH_FILE: &str = "batch.log";
+const SEGMENT_SUFFIX: &str = ".log";
+// Segments being rewritten by retention, removed on recovery
+const REWRITE_SUFFIX: &str = ".tmp";
+// u32 payload length followed by the u32 CRC32 of the payload
+const HEADER_LEN: usize = 8;
+// offset, msg and timestamp, followed by the sub-key if there is one
+const MESSAGE_HEADER_LEN: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always,
+    Every(usize),
+    Never,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.strip_prefix("every:") {
+                Some(n) => Ok(FsyncPolicy::Every(
+                    n.parse().context("parse fsync record count")?,
+                )),
+                None => bail!("unknown fsync policy {:?}", s),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    pub segment_bytes: u64,
+}
+
+impl WalConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WalConfig {
+            dir: dir.into(),
+            fsync: FsyncPolicy::Always,
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+        }
+    }
+    // None unless FLY_KAFKA_WAL_DIR is set
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(dir) = std::env::var(WAL_DIR_ENV) else {
+            return Ok(None);
+        };
+        let mut config = WalConfig::new(dir);
+        if let Ok(fsync) = std::env::var(WAL_FSYNC_ENV) {
+            config.fsync = fsync.parse()?;
+        }
+        if let Ok(bytes) = std::env::var(WAL_SEGMENT_BYTES_ENV) {
+            config.segment_bytes = bytes.parse().context("parse segment size")?;
+        }
+        Ok(Some(config))
+    }
+}
+
+struct Segment {
+    file: File,
+    len: u64,
+}
+
+// The segments for a single key, named by the first offset they hold
+struct KeyLog {
+    dir: PathBuf,
+    segments: BTreeMap<usize, Segment>,
+    // offset -> (segment base offset, position of the record in the segment)
+    index: BTreeMap<usize, (usize, u64)>,
+    next_offset: usize,
+}
+
+// Append-only, CRC-checked segment files per key, plus a log of committed
+// offsets. Everything other than the offset index and the committed
+// offsets stays on disk.
+pub struct SegmentLog {
+    config: WalConfig,
+    keys: HashMap<String, KeyLog>,
+    committed: CommittedOffsets,
+    committed_file: File,
+    batch_file: File,
+    unsynced: usize,
+}
+
+impl SegmentLog {
+    // Open the log at config.dir, recovering whatever a previous run left
+    pub fn open(config: WalConfig) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.dir).context("create wal dir")?;
+        let mut keys = HashMap::new();
+        for entry in fs::read_dir(&config.dir).context("list wal dir")? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            let key = decode_key(&name.to_string_lossy())
+                .with_context(|| format!("unexpected wal directory {:?}", name))?;
+            let log = KeyLog::recover(entry.path())
+                .with_context(|| format!("recover log for {:?}", key))?;
+            debug!("recovered {:?} up to offset {}", key, log.next_offset - 1);
+            keys.insert(key, log);
+        }
+
+        let committed_path = config.dir.join(COMMITTED_FILE);
+        let mut committed = CommittedOffsets::default();
+        if committed_path.exists() {
+            let file = File::open(&committed_path).context("open committed offsets")?;
+            let (records, _) = scan(&file)?;
+            for (_, payload) in records {
+                let (offset, group, key) = decode_commit(&payload)?;
+                committed.commit(&group, &key, offset);
+            }
+        }
+        // Rewrite the committed offsets so the file doesn't grow across restarts
+        let tmp_path = config.dir.join(format!("{}.tmp", COMMITTED_FILE));
+        let mut tmp = File::create(&tmp_path).context("create committed offsets")?;
+        for (group, key, offset) in committed.iter() {
+            tmp.write_all(&encode(&encode_commit(group, key, offset)))?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &committed_path).context("replace committed offsets")?;
+        let committed_file = OpenOptions::new()
+            .append(true)
+            .open(&committed_path)
+            .context("open committed offsets")?;
+
+        let batch_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(config.dir.join(BATCH_FILE))
+            .context("open batch log")?;
+
+        info!(
+            "opened wal at {:?} with {} keys and {} committed offsets",
+            config.dir,
+            keys.len(),
+            committed.len()
+        );
+        let mut log = SegmentLog {
+            config,
+            keys,
+            committed,
+            committed_file,
+            batch_file,
+            unsynced: 0,
+        };
+        log.redo_batch().context("finish interrupted batch")?;
+        Ok(log)
+    }
+    // Append whatever messages of the batch in the batch log didn't make it
+    // into their segments before the last run stopped
+    fn redo_batch(&mut self) -> anyhow::Result<()> {
+        let (records, _) = scan(&self.batch_file)?;
+        // Only a complete record counts, a torn one means the batch never
+        // started appending
+        if let Some((_, payload)) = records.last() {
+            let batch = decode_batch(payload)?;
+            let mut redone = 0;
+            for (key, entry) in batch.iter() {
+                if self
+                    .keys
+                    .get(key)
+                    .is_none_or(|l| l.next_offset <= entry.offset)
+                {
+                    self.append_entry(key, entry)?;
+                    redone += 1;
+                }
+            }
+            if redone > 0 {
+                warn!("appended {} messages of an interrupted batch", redone);
+                self.sync()?;
+            }
+        }
+        self.batch_file.set_len(0).context("clear batch log")?;
+        Ok(())
+    }
+    fn key_log(&mut self, key: &str) -> anyhow::Result<&mut KeyLog> {
+        if !self.keys.contains_key(key) {
+            let dir = self.config.dir.join(encode_key(key));
+            fs::create_dir_all(&dir).context("create key dir")?;
+            self.keys.insert(key.to_string(), KeyLog::recover(dir)?);
+        }
+        Ok(self.keys.get_mut(key).expect("key log just created"))
+    }
+    // Write entry to the end of key's active segment, rolling a new one if
+    // it's full. entry.offset must be the key's next offset.
+    fn append_entry(&mut self, key: &str, entry: &Entry) -> anyhow::Result<()> {
+        let segment_bytes = self.config.segment_bytes;
+        let fsync = self.config.fsync;
+        let log = self.key_log(key)?;
+        let offset = entry.offset;
+        if offset != log.next_offset {
+            bail!(
+                "appending offset {} to {:?} at {}",
+                offset,
+                key,
+                log.next_offset
+            );
+        }
+        let record = encode(&encode_message(entry));
+
+        let roll = match log.segments.last_key_value() {
+            Some((_, active)) => active.len > 0 && active.len + record.len() as u64 > segment_bytes,
+            None => true,
+        };
+        if roll {
+            debug!("rolling segment for {:?} at offset {}", key, offset);
+            let file = open_segment(&log.dir, offset)?;
+            log.segments.insert(offset, Segment { file, len: 0 });
+        }
+        let (base, active) = log.segments.iter_mut().next_back().expect("active segment");
+        active
+            .file
+            .write_all(&record)
+            .context("append to segment")?;
+        if fsync == FsyncPolicy::Always {
+            active.file.sync_data().context("fsync segment")?;
+        }
+        log.index.insert(offset, (*base, active.len));
+        active.len += record.len() as u64;
+        log.next_offset += 1;
+        self.written()
+    }
+    // Count a written record for the Every fsync policy; with Always each
+    // write has already been synced on its own
+    fn written(&mut self) -> anyhow::Result<()> {
+        if let FsyncPolicy::Every(n) = self.config.fsync {
+            self.unsynced += 1;
+            if self.unsynced >= n {
+                self.sync()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageLog for SegmentLog {
+    fn append(&mut self, key: &str, msg: usize, sub_key: Option<&str>) -> anyhow::Result<usize> {
+        let offset = self.key_log(key)?.next_offset;
+        self.append_entry(
+            key,
+            &Entry {
+                offset,
+                msg,
+                timestamp_ms: crate::ids::now_ms(),
+                sub_key: sub_key.map(|s| s.to_string()),
+            },
+        )?;
+        Ok(offset)
+    }
+    fn read(&self, key: &str, offset: usize) -> anyhow::Result<Vec<(usize, usize)>> {
+        let Some(log) = self.keys.get(key) else {
+            return Ok(Vec::new());
+        };
+        let mut msgs = Vec::new();
+        for (_, (base, pos)) in log.index.range(offset..) {
+            let segment = &log.segments[base];
+            let payload = read_record(&segment.file, *pos)?
+                .with_context(|| format!("missing record at {} in segment {}", pos, base))?;
+            let entry = decode_message(&payload)?;
+            msgs.push((entry.offset, entry.msg));
+        }
+        Ok(msgs)
+    }
+    // The whole batch goes to the batch log before any of it is appended. If
+    // an append fails the ones before it are truncated away again, and if
+    // the process dies part way through, the next open appends the rest.
+    fn append_batch(&mut self, msgs: &[BatchMessage]) -> anyhow::Result<Vec<usize>> {
+        let timestamp_ms = crate::ids::now_ms();
+        // key -> its next offset before the batch, to roll back to
+        let mut start: HashMap<&str, usize> = HashMap::new();
+        let mut next: HashMap<&str, usize> = HashMap::new();
+        let mut batch = Vec::with_capacity(msgs.len());
+        for m in msgs {
+            if !next.contains_key(m.key.as_str()) {
+                let offset = self.key_log(&m.key)?.next_offset;
+                start.insert(&m.key, offset);
+                next.insert(&m.key, offset);
+            }
+            let offset = next.get_mut(m.key.as_str()).expect("next offset for key");
+            *offset += 1;
+            batch.push((
+                m.key.as_str(),
+                Entry {
+                    offset: *offset - 1,
+                    msg: m.msg,
+                    timestamp_ms,
+                    sub_key: m.sub_key.clone(),
+                },
+            ));
+        }
+
+        self.batch_file
+            .write_all(&encode(&encode_batch(&batch)))
+            .context("write batch log")?;
+        if self.config.fsync != FsyncPolicy::Never {
+            self.batch_file.sync_data().context("fsync batch log")?;
+        }
+        for (key, entry) in batch.iter() {
+            if let Err(e) = self.append_entry(key, entry) {
+                for (key, offset) in start.iter() {
+                    self.keys
+                        .get_mut(*key)
+                        .expect("key log for batch")
+                        .truncate(*offset)
+                        .with_context(|| format!("roll back batch on {:?}", key))?;
+                }
+                // Make sure recovery doesn't redo what was just undone
+                self.batch_file.set_len(0).context("clear batch log")?;
+                self.batch_file.sync_data().context("fsync batch log")?;
+                return Err(e);
+            }
+        }
+        // The batch log can only go once the messages are durable themselves
+        if let FsyncPolicy::Every(_) = self.config.fsync {
+            self.sync()?;
+        }
+        self.batch_file.set_len(0).context("clear batch log")?;
+        Ok(batch.into_iter().map(|(_, e)| e.offset).collect())
+    }
+    fn commit(&mut self, group: &str, key: &str, offset: usize) -> anyhow::Result<()> {
+        if self.committed.get(group, key).is_some_and(|c| c >= offset) {
+            return Ok(());
+        }
+        let record = encode(&encode_commit(group, key, offset));
+        self.committed_file
+            .write_all(&record)
+            .context("append committed offset")?;
+        if self.config.fsync == FsyncPolicy::Always {
+            self.committed_file
+                .sync_data()
+                .context("fsync committed offsets")?;
+        }
+        self.committed.commit(group, key, offset);
+        self.written()
+    }
+    fn committed(&self, group: &str, key: &str) -> Option<usize> {
+        self.committed.get(group, key)
+    }
+    fn retain(&mut self, policy: &RetentionPolicy, now_ms: u64) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for (key, log) in self.keys.iter_mut() {
+            let entries = log.entries()?;
+            let expired = policy.expired(&entries, self.committed.lowest(key), now_ms);
+            if expired.is_empty() {
+                continue;
+            }
+            debug!("removing {} messages from {:?}", expired.len(), key);
+            log.remove(&expired)
+                .with_context(|| format!("apply retention to {:?}", key))?;
+            removed += expired.len();
+        }
+        Ok(removed)
+    }
+    fn keys(&self) -> Vec<String> {
+        let keys: BTreeSet<&str> = self
+            .keys
+            .keys()
+            .map(|k| k.as_str())
+            .chain(self.committed.iter().map(|(_, key, _)| key))
+            .collect();
+        keys.into_iter().map(|k| k.to_string()).collect()
+    }
+    fn export(&self, key: &str) -> anyhow::Result<KeyData> {
+        let entries = match self.keys.get(key) {
+            Some(log) => log.entries()?,
+            None => Vec::new(),
+        };
+        Ok(KeyData {
+            key: key.to_string(),
+            entries,
+            committed: self.committed.for_key(key),
+        })
+    }
+    fn import(&mut self, data: KeyData) -> anyhow::Result<()> {
+        for entry in data.entries {
+            let log = self.key_log(&data.key)?;
+            if entry.offset < log.next_offset {
+                continue;
+            }
+            // Retention can have left gaps, which the index doesn't mind
+            log.next_offset = entry.offset;
+            self.append_entry(&data.key, &entry)?;
+        }
+        for (group, offset) in data.committed {
+            self.commit(&group, &data.key, offset)?;
+        }
+        Ok(())
+    }
+    // The committed offsets log is append-only, so after a restart the
+    // key's offsets come back. They only ever merge forward into what the
+    // owner has, so that does no harm.
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        if let Some(log) = self.keys.remove(key) {
+            fs::remove_dir_all(&log.dir).context("delete key dir")?;
+        }
+        self.committed.remove_key(key);
+        Ok(())
+    }
+    fn sync(&mut self) -> anyhow::Result<()> {
+        for log in self.keys.values() {
+            if let Some((_, active)) = log.segments.last_key_value() {
+                active.file.sync_data().context("fsync segment")?;
+            }
+        }
+        self.committed_file
+            .sync_data()
+            .context("fsync committed offsets")?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+impl KeyLog {
+    // Rebuild the index from the segments in dir. A torn or corrupt record
+    // at the end of the newest segment is truncated away; anywhere else it's
+    // an error.
+    fn recover(dir: PathBuf) -> anyhow::Result<Self> {
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&dir).context("list segments")? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(REWRITE_SUFFIX) {
+                warn!("removing interrupted segment rewrite {:?}", name);
+                fs::remove_file(entry.path()).context("remove segment rewrite")?;
+            } else if let Some(base) = name.strip_suffix(SEGMENT_SUFFIX) {
+                bases.push(base.parse::<usize>().context("parse segment name")?);
+            }
+        }
+        bases.sort();
+
+        let mut log = KeyLog {
+            dir,
+            segments: BTreeMap::new(),
+            index: BTreeMap::new(),
+            next_offset: 1,
+        };
+        for (i, base) in bases.iter().enumerate() {
+            let file = open_segment(&log.dir, *base)?;
+            let (records, valid_len) = scan(&file)?;
+            let len = file.metadata()?.len();
+            if valid_len < len {
+                if i + 1 < bases.len() {
+                    bail!("corrupt record at {} in segment {}", valid_len, base);
+                }
+                warn!(
+                    "truncating segment {} from {} to {} bytes",
+                    base, len, valid_len
+                );
+                file.set_len(valid_len).context("truncate segment")?;
+                file.sync_all()?;
+            }
+            for (pos, payload) in records {
+                let offset = decode_message(&payload)?.offset;
+                log.index.insert(offset, (*base, pos));
+                log.next_offset = offset + 1;
+            }
+            log.segments.insert(
+                *base,
+                Segment {
+                    file,
+                    len: valid_len,
+                },
+            );
+        }
+        Ok(log)
+    }
+}
+
+impl KeyLog {
+    fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let mut entries = Vec::with_capacity(self.index.len());
+        for (offset, (base, pos)) in self.index.iter() {
+            let payload = read_record(&self.segments[base].file, *pos)?
+                .with_context(|| format!("missing record for offset {}", offset))?;
+            entries.push(decode_message(&payload)?);
+        }
+        Ok(entries)
+    }
+    // Drop offset and everything after it, undoing the appends of a batch
+    // that failed part way through
+    fn truncate(&mut self, offset: usize) -> anyhow::Result<()> {
+        let removed = self.index.split_off(&offset);
+        let rolled: Vec<usize> = self.segments.range(offset..).map(|(b, _)| *b).collect();
+        for base in rolled {
+            self.segments.remove(&base);
+            fs::remove_file(segment_path(&self.dir, base)).context("delete segment")?;
+        }
+        if let Some((base, pos)) = removed.values().find(|(base, _)| *base < offset) {
+            let segment = self
+                .segments
+                .get_mut(base)
+                .expect("segment of removed offset");
+            segment.file.set_len(*pos).context("truncate segment")?;
+            segment.len = *pos;
+        }
+        self.next_offset = offset;
+        Ok(())
+    }
+    // Drop the expired offsets by rewriting the segments that hold them,
+    // deleting segments that end up empty
+    fn remove(&mut self, expired: &HashSet<usize>) -> anyhow::Result<()> {
+        let bases: Vec<usize> = self.segments.keys().cloned().collect();
+        for (i, base) in bases.iter().enumerate() {
+            let next = bases.get(i + 1).cloned().unwrap_or(usize::MAX);
+            let offsets: Vec<(usize, u64)> = self
+                .index
+                .range(*base..next)
+                .map(|(offset, (_, pos))| (*offset, *pos))
+                .collect();
+            if !offsets.iter().any(|(offset, _)| expired.contains(offset)) {
+                continue;
+            }
+            for (offset, _) in offsets.iter() {
+                self.index.remove(offset);
+            }
+            let path = segment_path(&self.dir, *base);
+            let kept: Vec<_> = offsets
+                .into_iter()
+                .filter(|(offset, _)| !expired.contains(offset))
+                .collect();
+            if kept.is_empty() {
+                debug!("deleting segment {:?}", path);
+                self.segments.remove(base);
+                fs::remove_file(&path).context("delete segment")?;
+                continue;
+            }
+
+            let tmp_path = self.dir.join(format!("{:020}{}", base, REWRITE_SUFFIX));
+            let mut tmp = File::create(&tmp_path).context("create segment rewrite")?;
+            let mut len = 0;
+            for (offset, pos) in kept {
+                let payload = read_record(&self.segments[base].file, pos)?
+                    .with_context(|| format!("missing record for offset {}", offset))?;
+                let record = encode(&payload);
+                tmp.write_all(&record)?;
+                self.index.insert(offset, (*base, len));
+                len += record.len() as u64;
+            }
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &path).context("replace segment")?;
+            let file = open_segment(&self.dir, *base)?;
+            self.segments.insert(*base, Segment { file, len });
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, base: usize) -> PathBuf {
+    dir.join(format!("{:020}{}", base, SEGMENT_SUFFIX))
+}
+
+fn open_segment(dir: &Path, base: usize) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(segment_path(dir, base))
+        .context("open segment")
+}
+
+fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(crc32fast::hash(payload).to_le_bytes());
+    record.extend(payload);
+    record
+}
+
+// The record at pos, or None if it's incomplete or fails its CRC check
+fn read_record(file: &File, pos: u64) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    if !read_exact_at(file, &mut header, pos)? {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[..4].try_into()?) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into()?);
+    let mut payload = vec![0u8; len];
+    if !read_exact_at(file, &mut payload, pos + HEADER_LEN as u64)? {
+        return Ok(None);
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+// (position, payload) of a record within a file
+type Record = (u64, Vec<u8>);
+
+// All valid records from the start of the file, and where they end
+fn scan(file: &File) -> anyhow::Result<(Vec<Record>, u64)> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while let Some(payload) = read_record(file, pos)? {
+        let next = pos + (HEADER_LEN + payload.len()) as u64;
+        records.push((pos, payload));
+        pos = next;
+    }
+    Ok((records, pos))
+}
+
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> anyhow::Result<bool> {
+    match file.read_exact_at(buf, pos) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("read wal record"),
+    }
+}
+
+fn encode_message(entry: &Entry) -> Vec<u8> {
+    let sub_key = entry.sub_key.as_deref().unwrap_or_default();
+    let mut payload = Vec::with_capacity(MESSAGE_HEADER_LEN + sub_key.len());
+    payload.extend((entry.offset as u64).to_le_bytes());
+    payload.extend((entry.msg as u64).to_le_bytes());
+    payload.extend(entry.timestamp_ms.to_le_bytes());
+    payload.extend(sub_key.as_bytes());
+    payload
+}
+
+fn decode_message(payload: &[u8]) -> anyhow::Result<Entry> {
+    if payload.len() < MESSAGE_HEADER_LEN {
+        bail!("message record has {} bytes", payload.len());
+    }
+    let sub_key = &payload[MESSAGE_HEADER_LEN..];
+    Ok(Entry {
+        offset: u64::from_le_bytes(payload[..8].try_into()?) as usize,
+        msg: u64::from_le_bytes(payload[8..16].try_into()?) as usize,
+        timestamp_ms: u64::from_le_bytes(payload[16..24].try_into()?),
+        sub_key: match sub_key.is_empty() {
+            true => None,
+            false => Some(String::from_utf8(sub_key.to_vec()).context("message sub-key")?),
+        },
+    })
+}
+
+fn encode_commit(group: &str, key: &str, offset: usize) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12 + group.len() + key.len());
+    payload.extend((offset as u64).to_le_bytes());
+    payload.extend((group.len() as u32).to_le_bytes());
+    payload.extend(group.as_bytes());
+    payload.extend(key.as_bytes());
+    payload
+}
+
+fn decode_commit(payload: &[u8]) -> anyhow::Result<(usize, String, String)> {
+    if payload.len() < 12 {
+        bail!("commit record has {} bytes", payload.len());
+    }
+    let offset = u64::from_le_bytes(payload[..8].try_into()?) as usize;
+    let group_len = u32::from_le_bytes(payload[8..12].try_into()?) as usize;
+    let Some(group) = payload[12..].get(..group_len) else {
+        bail!("commit record group runs past the record");
+    };
+    let group = String::from_utf8(group.to_vec()).context("commit record group")?;
+    let key = String::from_utf8(payload[12 + group_len..].to_vec()).context("commit record key")?;
+    Ok((offset, group, key))
+}
+
+// Each message as the u32 key length, the key, then the message record
+fn encode_batch(batch: &[(&str, Entry)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (key, entry) in batch {
+        let message = encode_message(entry);
+        payload.extend((key.len() as u32).to_le_bytes());
+        payload.extend(key.as_bytes());
+        payload.extend((message.len() as u32).to_le_bytes());
+        payload.extend(message);
+    }
+    payload
+}
+
+fn decode_batch(mut payload: &[u8]) -> anyhow::Result<Vec<(String, Entry)>> {
+    let take = |payload: &mut &[u8]| -> anyhow::Result<Vec<u8>> {
+        let Some(len) = payload.get(..4) else {
+            bail!("batch record length runs past the record");
+        };
+        let len = u32::from_le_bytes(len.try_into()?) as usize;
+        let Some(bytes) = payload.get(4..4 + len) else {
+            bail!("batch record field runs past the record");
+        };
+        let bytes = bytes.to_vec();
+        *payload = &payload[4 + len..];
+        Ok(bytes)
+    };
+    let mut batch = Vec::new();
+    while !payload.is_empty() {
+        let key = String::from_utf8(take(&mut payload)?).context("batch record key")?;
+        let entry = decode_message(&take(&mut payload)?)?;
+        batch.push((key, entry));
+    }
+    Ok(batch)
+}
+
+// Keys are arbitrary strings, so directories are named by their hex bytes
+fn encode_key(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_key(name: &str) -> anyhow::Result<String> {
+    if !name.len().is_multiple_of(2) {
+        bail!("odd length key directory");
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[test]
+fn wal_recovers_and_truncates_torn_tail() -> anyhow::Result<()> {
+    use crate::kafka_log::DEFAULT_GROUP;
+
+    let dir = std::env::temp_dir().join(format!("fly-wal-{}", uuid::Uuid::new_v4()));
+    let mut config = WalConfig::new(&dir);
+    // Small enough that every few records roll a new segment
+    config.segment_bytes = 64;
+
+    let mut wal = SegmentLog::open(config.clone())?;
+    for msg in 0..10 {
+        assert_eq!(wal.append("k1", 100 + msg, None)?, msg + 1);
+    }
+    wal.append("other/key", 7, None)?;
+    wal.commit(DEFAULT_GROUP, "k1", 4)?;
+    wal.commit(DEFAULT_GROUP, "k1", 2)?;
+    wal.commit("g1", "k1", 6)?;
+    drop(wal);
+
+    let wal = SegmentLog::open(config.clone())?;
+    assert!(wal.keys["k1"].segments.len() > 1);
+    assert_eq!(wal.read("k1", 9)?, vec![(9, 108), (10, 109)]);
+    assert_eq!(wal.read("other/key", 0)?, vec![(1, 7)]);
+    assert_eq!(wal.committed(DEFAULT_GROUP, "k1"), Some(4));
+    assert_eq!(wal.committed("g1", "k1"), Some(6));
+    drop(wal);
+
+    // Tear the last record of the newest segment
+    let key_dir = dir.join(encode_key("k1"));
+    let mut segments: Vec<_> = fs::read_dir(&key_dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    segments.sort();
+    let newest = OpenOptions::new()
+        .write(true)
+        .open(segments.last().unwrap())?;
+    newest.set_len(newest.metadata()?.len() - 3)?;
+
+    let mut wal = SegmentLog::open(config.clone())?;
+    assert_eq!(wal.read("k1", 9)?, vec![(9, 108)]);
+    assert_eq!(wal.append("k1", 200, None)?, 10);
+    assert_eq!(wal.read("k1", 10)?, vec![(10, 200)]);
+
+    // Everything below the committed offset goes, emptying the older segments
+    let policy = RetentionPolicy {
+        delete_committed: true,
+        ..Default::default()
+    };
+    assert_eq!(wal.retain(&policy, 0)?, 3);
+    assert_eq!(wal.read("k1", 0)?[0], (4, 103));
+    drop(wal);
+    let wal = SegmentLog::open(config)?;
+    assert_eq!(wal.read("k1", 0)?.len(), 7);
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn wal_batches_finish_after_crash() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("fly-wal-{}", uuid::Uuid::new_v4()));
+    let mut config = WalConfig::new(&dir);
+    config.segment_bytes = 64;
+    let batch_msg = |key: &str, msg| BatchMessage {
+        key: key.to_string(),
+        msg,
+        sub_key: None,
+    };
+
+    let mut wal = SegmentLog::open(config.clone())?;
+    wal.append("events", 1, None)?;
+    let offsets = wal.append_batch(&[
+        batch_msg("events", 2),
+        batch_msg("audit", 3),
+        batch_msg("events", 4),
+    ])?;
+    assert_eq!(offsets, vec![2, 1, 3]);
+    assert_eq!(wal.batch_file.metadata()?.len(), 0);
+
+    // Undoing a batch drops the segments it rolled as well as its records
+    let segments = wal.keys["events"].segments.len();
+    wal.append("events", 5, None)?;
+    wal.append("events", 6, None)?;
+    assert!(wal.keys["events"].segments.len() > segments);
+    wal.keys.get_mut("events").unwrap().truncate(4)?;
+    assert_eq!(wal.keys["events"].segments.len(), segments);
+    assert_eq!(wal.append("events", 7, None)?, 4);
+
+    // A crash after the batch log was written but before the second key
+    let entry = |offset, msg| Entry {
+        offset,
+        msg,
+        timestamp_ms: 0,
+        sub_key: None,
+    };
+    let batch = [("events", entry(5, 8)), ("audit", entry(2, 9))];
+    wal.batch_file.write_all(&encode(&encode_batch(&batch)))?;
+    wal.append_entry("events", &batch[0].1)?;
+    drop(wal);
+
+    let wal = SegmentLog::open(config)?;
+    assert_eq!(wal.read("events", 5)?, vec![(5, 8)]);
+    assert_eq!(wal.read("audit", 0)?, vec![(1, 3), (2, 9)]);
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use crate::runtime::Output;
+use anyhow::Context;
+use log::debug;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Bytes buffered before they're written out mid-batch
+pub const WRITE_BUFFER_BYTES_ENV: &str = "FLY_WRITE_BUFFER_BYTES";
+// Longest a buffered message waits before it's written out mid-batch, in
+// milliseconds
+pub const WRITE_DEADLINE_MS_ENV: &str = "FLY_WRITE_DEADLINE_MS";
+
+#[derive(Clone, Debug)]
+pub struct WriterConfig {
+    pub max_bytes: usize,
+    pub deadline: Duration,
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        WriterConfig {
+            max_bytes: 64 << 10,
+            deadline: Duration::from_millis(5),
+        }
+    }
+}
+
+impl WriterConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = WriterConfig::default();
+        if let Ok(bytes) = std::env::var(WRITE_BUFFER_BYTES_ENV) {
+            config.max_bytes = bytes.parse().context("parse FLY_WRITE_BUFFER_BYTES")?;
+        }
+        if let Ok(ms) = std::env::var(WRITE_DEADLINE_MS_ENV) {
+            config.deadline =
+                Duration::from_millis(ms.parse().context("parse FLY_WRITE_DEADLINE_MS")?);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WriteMetrics {
+    pub flushes: usize,
+    pub messages: usize,
+    pub bytes: usize,
+    // Time spent writing to the output, and the longest single flush
+    pub write_time: Duration,
+    pub max_write: Duration,
+    // Longest a message sat in the buffer before it was written
+    pub max_delay: Duration,
+}
+
+// Coalesces a node's messages into one buffer that goes out in a single
+// write when flushed. The runtime flushes at the end of each batch of
+// events; a full buffer or one older than the deadline is flushed on the
+// next write.
+pub struct BatchWriter<'a> {
+    inner: Output<'a>,
+    config: WriterConfig,
+    buf: Vec<u8>,
+    // When the oldest byte in buf was written
+    since: Option<Instant>,
+    metrics: Arc<Mutex<WriteMetrics>>,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(inner: Output<'a>, config: WriterConfig) -> Self {
+        BatchWriter {
+            inner,
+            config,
+            buf: Vec::new(),
+            since: None,
+            metrics: Arc::new(Mutex::new(WriteMetrics::default())),
+        }
+    }
+    // Shared with the writer, so it can be read after the writer is handed
+    // to a node
+    pub fn metrics(&self) -> Arc<Mutex<WriteMetrics>> {
+        self.metrics.clone()
+    }
+}
+
+impl Write for BatchWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if self.buf.len() >= self.config.max_bytes || since.elapsed() >= self.config.deadline {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        let Some(since) = self.since.take() else {
+            return Ok(());
+        };
+        let started = Instant::now();
+        let res = self
+            .inner
+            .write_all(&self.buf)
+            .and_then(|_| self.inner.flush());
+        let took = started.elapsed();
+        let mut metrics = self.metrics.lock().expect("write metrics lock");
+        metrics.flushes += 1;
+        metrics.messages += self.buf.iter().filter(|b| **b == b'\n').count();
+        metrics.bytes += self.buf.len();
+        metrics.write_time += took;
+        metrics.max_write = metrics.max_write.max(took);
+        metrics.max_delay = metrics.max_delay.max(started - since);
+        debug!("wrote {} bytes in {:?}", self.buf.len(), took);
+        // Whatever didn't make it out is lost, as it would be on a network
+        self.buf.clear();
+        res
+    }
+}
+
+#[test]
+fn batch_writer_coalesces_until_flushed() -> anyhow::Result<()> {
+    // Records each write it's given
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let recorder = Recorder::default();
+    let config = WriterConfig {
+        max_bytes: 32,
+        deadline: Duration::from_secs(60),
+    };
+    let mut writer = BatchWriter::new(Box::new(recorder.clone()), config);
+    let metrics = writer.metrics();
+    writer.write_all(b"{\"a\":1}")?;
+    writer.write_all(b"\n")?;
+    writer.write_all(b"{\"b\":2}\n")?;
+    assert!(recorder.0.lock().unwrap().is_empty());
+    writer.flush()?;
+    assert_eq!(*recorder.0.lock().unwrap(), [b"{\"a\":1}\n{\"b\":2}\n"]);
+    // Nothing buffered, so nothing to write
+    writer.flush()?;
+    assert_eq!(recorder.0.lock().unwrap().len(), 1);
+
+    // A full buffer goes out without waiting for the flush
+    writer.write_all(b"{\"c\":\"0123456789012345678901234\"}\n")?;
+    assert_eq!(recorder.0.lock().unwrap().len(), 2);
+    let metrics = metrics.lock().unwrap().clone();
+    assert_eq!((metrics.flushes, metrics.messages), (2, 3));
+    assert_eq!(metrics.bytes, 16 + 34);
+
+    // As does one past its deadline
+    let recorder = Recorder::default();
+    let config = WriterConfig {
+        max_bytes: 1 << 20,
+        deadline: Duration::ZERO,
+    };
+    let mut writer = BatchWriter::new(Box::new(recorder.clone()), config);
+    writer.write_all(b"{}\n")?;
+    assert_eq!(recorder.0.lock().unwrap().len(), 1);
+    Ok(())
+}